@@ -1,6 +1,10 @@
 use std::io::{self, Error, ErrorKind};
 
-use common::{messages::Message, MessageStream};
+use common::{
+    messages::Message,
+    scram::{self, ScramClient},
+    MessageStream,
+};
 use http_auth_basic::Credentials;
 
 pub async fn authenticate(
@@ -9,11 +13,37 @@ pub async fn authenticate(
     username: &Option<String>,
     password: &Option<String>,
 ) -> io::Result<String> {
+    if mode == scram::METHOD {
+        return authenticate_scram(stream, username, password).await;
+    }
+
     let request = match mode.as_str() {
         "none" => Ok(Message::AuthenticationRequest {
             method: "none".into(),
             credentials: Vec::new(),
         }),
+        // The user is taken from the client certificate.
+        "certificate" => Ok(Message::AuthenticationRequest {
+            method: "certificate".into(),
+            credentials: Vec::new(),
+        }),
+        // The user is the owner of the process, as seen by the server through
+        // a Unix domain socket.
+        "peer" => Ok(Message::AuthenticationRequest {
+            method: "peer".into(),
+            credentials: Vec::new(),
+        }),
+        // The password holds the token.
+        "bearer" => {
+            let Some(token) = password else {
+                return Err(Error::new(ErrorKind::Other, "missing token"));
+            };
+
+            Ok(Message::AuthenticationRequest {
+                method: "bearer".into(),
+                credentials: token.as_bytes().to_vec(),
+            })
+        }
         "basic" | "ldap" => {
             let Some(username) = username else {
                 return Err(Error::new(ErrorKind::Other, "missing username"));
@@ -36,6 +66,50 @@ pub async fn authenticate(
     let response = stream.read().await?;
 
     match response {
+        Message::AuthenticationResponse { client_id } => Ok(client_id.clone()),
+        Message::LimitExceeded { limit, detail } => Err(Error::new(
+            ErrorKind::Other,
+            format!("refused for {limit}: {detail}"),
+        )),
+        _ => Err(Error::new(ErrorKind::Other, "invalid message")),
+    }
+}
+
+async fn authenticate_scram(
+    stream: &mut impl MessageStream,
+    username: &Option<String>,
+    password: &Option<String>,
+) -> io::Result<String> {
+    let Some(username) = username else {
+        return Err(Error::new(ErrorKind::Other, "missing username"));
+    };
+    let Some(password) = password else {
+        return Err(Error::new(ErrorKind::Other, "missing password"));
+    };
+
+    let mut scram_client = ScramClient::new(username, password)?;
+
+    let request = Message::AuthenticationRequest {
+        method: scram::METHOD.into(),
+        credentials: scram_client.client_first(),
+    };
+    stream.write(&request).await?;
+
+    let Message::AuthenticationChallenge { challenge } = stream.read().await? else {
+        return Err(Error::new(ErrorKind::Other, "invalid message"));
+    };
+    let response = Message::AuthenticationChallengeResponse {
+        response: scram_client.client_final(&challenge)?,
+    };
+    stream.write(&response).await?;
+
+    // The server proves it knows the credentials before accepting.
+    let Message::AuthenticationChallenge { challenge } = stream.read().await? else {
+        return Err(Error::new(ErrorKind::Other, "invalid message"));
+    };
+    scram_client.verify_server_final(&challenge)?;
+
+    match stream.read().await? {
         Message::AuthenticationResponse { client_id } => Ok(client_id.clone()),
         _ => Err(Error::new(ErrorKind::Other, "invalid message")),
     }
@@ -1,7 +1,7 @@
 #![allow(dead_code)]
 use std::io;
 use std::net::ToSocketAddrs;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 
 use common::MessageSocket;
 use common::MessageStream;
@@ -10,7 +10,7 @@ use futures::future::BoxFuture;
 use common::messages::DataPacket;
 use common::messages::Message;
 use tokio::io::{AsyncRead, AsyncWrite};
-use tokio::net::TcpStream;
+use tokio::net::{TcpStream, UnixStream};
 use tokio::sync::mpsc::{self, Receiver, Sender};
 
 use crate::authentication::authenticate;
@@ -24,6 +24,17 @@ pub trait ClientCallbacks {
         topic: String,
         count: u32,
     ) -> BoxFuture<'_, ()>;
+    /// The server refused a subscription request.
+    fn on_subscription_rejected(&mut self, topic: String, reason: String) -> BoxFuture<'_, ()>;
+    /// The server removed a subscription the client is no longer authorized for.
+    fn on_subscription_revoked(&mut self, topic: String) -> BoxFuture<'_, ()>;
+    /// The server removed a notification the client is no longer authorized for.
+    fn on_notification_revoked(&mut self, pattern: String) -> BoxFuture<'_, ()>;
+    /// The server is shutting down, and will close the connection once the
+    /// queued messages have been sent. The client may connect to another.
+    fn on_shutdown(&mut self, reason: String) -> BoxFuture<'_, ()>;
+    /// A request was refused as it exceeded a limit of the server.
+    fn on_limit_exceeded(&mut self, limit: String, detail: String) -> BoxFuture<'_, ()>;
 }
 
 pub trait ClientProtocol {
@@ -39,6 +50,12 @@ pub trait ClientProtocol {
         data_packets: Vec<DataPacket>,
     ) -> BoxFuture<'_, io::Result<()>>;
     fn add_subscription(&mut self, topic: String) -> BoxFuture<'_, io::Result<()>>;
+    fn add_subscription_with_options(
+        &mut self,
+        topic: String,
+        filter: Option<String>,
+        max_rate: Option<u32>,
+    ) -> BoxFuture<'_, io::Result<()>>;
     fn remove_subscription(&mut self, topic: String) -> BoxFuture<'_, io::Result<()>>;
     fn remove_notification(&mut self, topic: String) -> BoxFuture<'_, io::Result<()>>;
     fn add_notification(&mut self, topic: String) -> BoxFuture<'_, io::Result<()>>;
@@ -118,8 +135,19 @@ where
         self.send_message(message).await
     }
 
-    async fn send_subscription_request(&mut self, topic: String, is_add: bool) -> io::Result<()> {
-        let message = Message::SubscriptionRequest { topic, is_add };
+    async fn send_subscription_request(
+        &mut self,
+        topic: String,
+        is_add: bool,
+        filter: Option<String>,
+        max_rate: Option<u32>,
+    ) -> io::Result<()> {
+        let message = Message::SubscriptionRequest {
+            topic,
+            is_add,
+            filter,
+            max_rate,
+        };
         self.send_message(message).await
     }
 
@@ -150,6 +178,19 @@ where
                     .on_forwarded_subscription(client_id, topic, count)
                     .await
             }
+            Message::SubscriptionRejected { topic, reason } => {
+                self.callbacks.on_subscription_rejected(topic, reason).await
+            }
+            Message::SubscriptionRevoked { topic } => {
+                self.callbacks.on_subscription_revoked(topic).await
+            }
+            Message::NotificationRevoked { pattern } => {
+                self.callbacks.on_notification_revoked(pattern).await
+            }
+            Message::Shutdown { reason } => self.callbacks.on_shutdown(reason).await,
+            Message::LimitExceeded { limit, detail } => {
+                self.callbacks.on_limit_exceeded(limit, detail).await
+            }
             _ => todo!(),
         };
     }
@@ -196,11 +237,29 @@ where
     }
 
     fn add_subscription(&mut self, topic: String) -> BoxFuture<'_, io::Result<()>> {
-        Box::pin(async move { self.send_subscription_request(topic, true).await })
+        Box::pin(async move {
+            self.send_subscription_request(topic, true, None, None)
+                .await
+        })
+    }
+
+    fn add_subscription_with_options(
+        &mut self,
+        topic: String,
+        filter: Option<String>,
+        max_rate: Option<u32>,
+    ) -> BoxFuture<'_, io::Result<()>> {
+        Box::pin(async move {
+            self.send_subscription_request(topic, true, filter, max_rate)
+                .await
+        })
     }
 
     fn remove_subscription(&mut self, topic: String) -> BoxFuture<'_, io::Result<()>> {
-        Box::pin(async move { self.send_subscription_request(topic, false).await })
+        Box::pin(async move {
+            self.send_subscription_request(topic, false, None, None)
+                .await
+        })
     }
 
     fn add_notification(&mut self, pattern: String) -> BoxFuture<'_, io::Result<()>> {
@@ -217,6 +276,8 @@ pub async fn connect<S>(
     port: u16,
     tls: bool,
     cafile: &Option<PathBuf>,
+    certfile: &Option<PathBuf>,
+    keyfile: &Option<PathBuf>,
     authentication_mode: &String,
     username: &Option<String>,
     password: &Option<String>,
@@ -237,7 +298,7 @@ where
 
     let client = match tls {
         true => {
-            let stream = create_tls_stream(host, cafile, stream).await?;
+            let stream = create_tls_stream(host, cafile, certfile, keyfile, stream).await?;
             let client: Box<dyn ClientProtocol> = Box::from(
                 Client::start(stream, callbacks, authentication_mode, username, password).await?,
             );
@@ -253,3 +314,16 @@ where
 
     Ok(client)
 }
+
+/// Connect to a server listening on a Unix domain socket.
+pub async fn connect_unix(
+    path: &Path,
+    authentication_mode: &String,
+    username: &Option<String>,
+    password: &Option<String>,
+    callbacks: Box<dyn ClientCallbacks + Send>,
+) -> io::Result<Box<dyn ClientProtocol>> {
+    let stream = UnixStream::connect(path).await?;
+    let client = Client::start(stream, callbacks, authentication_mode, username, password).await?;
+    Ok(Box::new(client))
+}
@@ -5,7 +5,7 @@ use protocol::communicate;
 use tls::create_tls_stream;
 
 use options::Options;
-use tokio::net::TcpStream;
+use tokio::net::{TcpStream, UnixStream};
 
 mod authentication;
 mod client;
@@ -21,6 +21,18 @@ async fn main() -> Result<(), Box<dyn Error + Send + Sync>> {
 
     let options = Options::load();
 
+    if let Some(path) = &options.unix_socket {
+        let socket = UnixStream::connect(path).await?;
+        communicate(
+            socket,
+            &options.authentication_mode,
+            &options.username,
+            &options.password,
+        )
+        .await;
+        return Ok(());
+    }
+
     let endpoint = format!("{}:{}", options.host.as_str(), options.port);
 
     let addr = endpoint
@@ -31,7 +43,14 @@ async fn main() -> Result<(), Box<dyn Error + Send + Sync>> {
     let socket = TcpStream::connect(&addr).await?;
     match options.tls {
         true => {
-            let stream = create_tls_stream(options.host.as_str(), &options.cafile, socket).await?;
+            let stream = create_tls_stream(
+                options.host.as_str(),
+                &options.cafile,
+                &options.certfile,
+                &options.keyfile,
+                socket,
+            )
+            .await?;
             communicate(
                 stream,
                 &options.authentication_mode,
@@ -25,6 +25,10 @@ pub struct Options {
     #[argh(option, short = 'p', default = "default_port()")]
     pub port: u16,
 
+    /// the path of a unix domain socket, rather than the host and port
+    #[argh(option, short = 'u')]
+    pub unix_socket: Option<PathBuf>,
+
     /// use tls
     #[argh(switch, short = 't')]
     pub tls: bool,
@@ -33,6 +37,14 @@ pub struct Options {
     #[argh(option, short = 'c')]
     pub cafile: Option<PathBuf>,
 
+    /// client certificate file, for certificate authentication
+    #[argh(option)]
+    pub certfile: Option<PathBuf>,
+
+    /// client key file, for certificate authentication
+    #[argh(option)]
+    pub keyfile: Option<PathBuf>,
+
     /// authentication mode
     #[argh(option, short = 'm', default = "default_authentication_mode()")]
     pub authentication_mode: String,
@@ -41,7 +53,7 @@ pub struct Options {
     #[argh(option, short = 'U')]
     pub username: Option<String>,
 
-    /// password
+    /// password, or the token for bearer authentication
     #[argh(option, short = 'P')]
     pub password: Option<String>,
 }
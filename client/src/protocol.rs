@@ -34,7 +34,7 @@ pub async fn communicate<S>(
 
         println!("Enter request:");
         println!("\tpublish <topic> <entitlements> <message>");
-        println!("\tsubscribe <topic>");
+        println!("\tsubscribe <topic> [@<max-rate>] [<filter>]");
         println!("\tnotify <pattern>");
 
         tokio::select! {
@@ -54,6 +54,11 @@ pub async fn communicate<S>(
             result = stream.read() => {
                 let message = result.unwrap();
                 println!("Received message {message:?}");
+                if let Message::Shutdown { reason } = message {
+                    // The server closes the connection after this.
+                    println!("Disconnected: {reason}");
+                    return;
+                }
             }
         }
     }
@@ -88,11 +93,10 @@ fn handle_publish(args: Vec<&str>) -> Result<Message, &'static str> {
         i += 1;
 
         let message = args[i];
-        data_packets.push(DataPacket::new(
-            entitlements,
-            HashMap::from([(b"content-type".into(), b"text/plain".into())]),
-            Vec::from(message.as_bytes()),
-        ));
+        let mut data_packet =
+            DataPacket::new(entitlements, HashMap::new(), Vec::from(message.as_bytes()));
+        data_packet.set_content_type("text/plain");
+        data_packets.push(data_packet);
         i += 1;
     }
     let message = Message::MulticastData {
@@ -103,13 +107,32 @@ fn handle_publish(args: Vec<&str>) -> Result<Message, &'static str> {
 }
 
 fn handle_subscribe(args: Vec<&str>) -> Result<Message, &'static str> {
-    if args.len() != 2 {
-        return Err("usage: subscribe <topic>");
+    if args.len() < 2 {
+        return Err("usage: subscribe <topic> [@<max-rate>] [<filter>]");
     }
     let topic = args[1].to_string();
+    let mut i = 2;
+    let max_rate = match args.get(i).and_then(|arg| arg.strip_prefix('@')) {
+        Some(max_rate) => {
+            i += 1;
+            Some(
+                max_rate
+                    .parse()
+                    .map_err(|_| "max rate should be an integer")?,
+            )
+        }
+        None => None,
+    };
+    // The filter is the remainder of the line.
+    let filter = match args.len() > i {
+        true => Some(args[i..].join(" ")),
+        false => None,
+    };
     let message = Message::SubscriptionRequest {
         topic,
         is_add: true,
+        filter,
+        max_rate,
     };
     Ok(message)
 }
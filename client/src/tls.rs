@@ -10,16 +10,20 @@ use tokio_rustls::{client::TlsStream, rustls, TlsConnector};
 pub async fn create_tls_stream(
     host: &str,
     cafile: &Option<PathBuf>,
+    certfile: &Option<PathBuf>,
+    keyfile: &Option<PathBuf>,
     stream: TcpStream,
 ) -> io::Result<TlsStream<TcpStream>> {
-    let (tls_connector, domain) = create_tls_connector(host, cafile);
+    let (tls_connector, domain) = create_tls_connector(host, cafile, certfile, keyfile)?;
     tls_connector.connect(domain, stream).await
 }
 
 pub fn create_tls_connector<'a>(
     host: &str,
     cafile: &Option<PathBuf>,
-) -> (TlsConnector, ServerName<'a>) {
+    certfile: &Option<PathBuf>,
+    keyfile: &Option<PathBuf>,
+) -> io::Result<(TlsConnector, ServerName<'a>)> {
     let mut root_cert_store = rustls::RootCertStore::empty();
     if let Some(cafile) = cafile {
         let mut pem = io::BufReader::new(File::open(cafile).expect("Should open cert file"));
@@ -30,9 +34,28 @@ pub fn create_tls_connector<'a>(
         root_cert_store.extend(webpki_roots::TLS_SERVER_ROOTS.iter().cloned());
     }
 
-    let config = rustls::ClientConfig::builder()
-        .with_root_certificates(root_cert_store)
-        .with_no_client_auth(); // i guess this was previously the default?
+    let builder = rustls::ClientConfig::builder().with_root_certificates(root_cert_store);
+    let config = match (certfile, keyfile) {
+        (Some(certfile), Some(keyfile)) => {
+            // Present a certificate for the server to authenticate.
+            let mut pem = io::BufReader::new(File::open(certfile)?);
+            let certs = rustls_pemfile::certs(&mut pem).collect::<io::Result<Vec<_>>>()?;
+            let mut pem = io::BufReader::new(File::open(keyfile)?);
+            let key = rustls_pemfile::private_key(&mut pem)?.ok_or_else(|| {
+                io::Error::new(io::ErrorKind::InvalidInput, "no private key found")
+            })?;
+            builder
+                .with_client_auth_cert(certs, key)
+                .map_err(|err| io::Error::new(io::ErrorKind::InvalidInput, err))?
+        }
+        (None, None) => builder.with_no_client_auth(), // i guess this was previously the default?
+        _ => {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "both a certificate and key file are required",
+            ))
+        }
+    };
     let connector = TlsConnector::from(Arc::new(config));
 
     let domain = pki_types::ServerName::try_from(host)
@@ -40,5 +63,5 @@ pub fn create_tls_connector<'a>(
         .unwrap()
         .to_owned();
 
-    (connector, domain)
+    Ok((connector, domain))
 }
@@ -6,6 +6,17 @@ use tokio::io::{
 
 use crate::{message_stream::MessageStream, messages::Message, Serializable};
 
+/// Encode a message as a frame, prefixed by its length.
+pub fn frame(message: &Message) -> io::Result<Vec<u8>> {
+    let len = message.size();
+    let mut cursor: Cursor<Vec<u8>> = Cursor::new(Vec::with_capacity(4 + len));
+
+    (len as u32).serialize(&mut cursor)?;
+    message.serialize(&mut cursor)?;
+
+    Ok(cursor.into_inner())
+}
+
 pub struct MessageSocket<T> {
     reader: BufReader<ReadHalf<T>>,
     writer: WriteHalf<T>,
@@ -40,14 +51,17 @@ where
     }
 
     async fn write(&mut self, message: &Message) -> io::Result<()> {
-        let len = message.size();
-        let mut cursor: Cursor<Vec<u8>> = Cursor::new(Vec::with_capacity(4 + len));
+        let frame = frame(message)?;
 
-        (len as u32).serialize(&mut cursor)?;
-        message.serialize(&mut cursor)?;
+        log::debug!(
+            "MessageSocket::write: writing frame of {} bytes",
+            frame.len() - 4
+        );
 
-        log::debug!("MessageSocket::write: writing frame of {} bytes", len);
+        self.writer.write_all(&frame).await
+    }
 
-        self.writer.write_all(cursor.get_ref().as_slice()).await
+    async fn close(&mut self) -> io::Result<()> {
+        self.writer.shutdown().await
     }
 }
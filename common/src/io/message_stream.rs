@@ -7,4 +7,6 @@ use crate::messages::Message;
 pub trait MessageStream {
     fn read(&mut self) -> impl Future<Output = io::Result<Message>> + Send;
     fn write(&mut self, message: &Message) -> impl Future<Output = io::Result<()>> + Send;
+    /// Flush any buffered messages and close the connection.
+    fn close(&mut self) -> impl Future<Output = io::Result<()>> + Send;
 }
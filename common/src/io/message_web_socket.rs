@@ -6,8 +6,35 @@ use tokio_tungstenite::{tungstenite, WebSocketStream};
 
 use crate::{message_stream::MessageStream, messages::Message, Serializable};
 
+/// The web socket sub-protocol a client requests to use the JSON encoding.
+pub const JSON_SUB_PROTOCOL: &str = "squawkbus.json";
+
+/// How messages are encoded in web socket frames.
+#[derive(Debug, PartialEq, Copy, Clone)]
+pub enum WebSocketEncoding {
+    /// The binary protocol, sent as binary frames.
+    Binary,
+    /// JSON, sent as text frames.
+    Json,
+}
+
+impl WebSocketEncoding {
+    /// Choose the encoding from the comma separated sub-protocols requested
+    /// by the client.
+    pub fn from_sub_protocols(sub_protocols: &str) -> WebSocketEncoding {
+        match sub_protocols
+            .split(',')
+            .any(|sub_protocol| sub_protocol.trim() == JSON_SUB_PROTOCOL)
+        {
+            true => WebSocketEncoding::Json,
+            false => WebSocketEncoding::Binary,
+        }
+    }
+}
+
 pub struct MessageWebSocket<T> {
     stream: WebSocketStream<T>,
+    encoding: WebSocketEncoding,
 }
 
 impl<T> MessageWebSocket<T>
@@ -15,7 +42,14 @@ where
     T: AsyncRead + AsyncWrite + Unpin,
 {
     pub fn new(stream: WebSocketStream<T>) -> MessageWebSocket<T> {
-        MessageWebSocket { stream }
+        Self::with_encoding(stream, WebSocketEncoding::Binary)
+    }
+
+    pub fn with_encoding(
+        stream: WebSocketStream<T>,
+        encoding: WebSocketEncoding,
+    ) -> MessageWebSocket<T> {
+        MessageWebSocket { stream, encoding }
     }
 }
 
@@ -37,11 +71,19 @@ where
             )
         })?;
 
-        match message {
-            tungstenite::Message::Binary(buf) => {
+        match (message, self.encoding) {
+            (tungstenite::Message::Binary(buf), _) => {
                 let mut cursor: Cursor<Vec<u8>> = Cursor::new(buf.into());
                 Message::deserialize(&mut cursor)
             }
+            (tungstenite::Message::Text(text), WebSocketEncoding::Json) => {
+                serde_json::from_str(text.as_str()).map_err(|e| {
+                    io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        format!("Failed to decode json message: {}", e),
+                    )
+                })
+            }
             _ => Err(io::Error::new(
                 io::ErrorKind::Other,
                 "Failed to receive message",
@@ -50,17 +92,60 @@ where
     }
 
     async fn write(&mut self, message: &Message) -> io::Result<()> {
-        let bytes_to_write = message.size();
-        let mut cursor: Cursor<Vec<u8>> = Cursor::new(Vec::with_capacity(bytes_to_write));
-        message.serialize(&mut cursor)?;
+        let frame = match self.encoding {
+            WebSocketEncoding::Binary => {
+                let bytes_to_write = message.size();
+                let mut cursor: Cursor<Vec<u8>> = Cursor::new(Vec::with_capacity(bytes_to_write));
+                message.serialize(&mut cursor)?;
+                tungstenite::Message::Binary(cursor.into_inner().into())
+            }
+            WebSocketEncoding::Json => {
+                let text = serde_json::to_string(message).map_err(|e| {
+                    io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        format!("Failed to encode json message: {}", e),
+                    )
+                })?;
+                tungstenite::Message::Text(text.into())
+            }
+        };
+        self.stream.send(frame).await.map_err(|e| {
+            io::Error::new(
+                io::ErrorKind::Other,
+                format!("Failed to send message: {}", e),
+            )
+        })
+    }
+
+    async fn close(&mut self) -> io::Result<()> {
         self.stream
-            .send(tungstenite::Message::Binary(cursor.into_inner().into()))
+            .close(None)
             .await
-            .map_err(|e| {
-                io::Error::new(
-                    io::ErrorKind::Other,
-                    format!("Failed to send message: {}", e),
-                )
-            })
+            .map_err(|e| io::Error::other(format!("Failed to close web socket: {}", e)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn should_choose_encoding_from_sub_protocols() {
+        assert_eq!(
+            WebSocketEncoding::from_sub_protocols("squawkbus.json"),
+            WebSocketEncoding::Json
+        );
+        assert_eq!(
+            WebSocketEncoding::from_sub_protocols("chat, squawkbus.json"),
+            WebSocketEncoding::Json
+        );
+        assert_eq!(
+            WebSocketEncoding::from_sub_protocols("chat"),
+            WebSocketEncoding::Binary
+        );
+        assert_eq!(
+            WebSocketEncoding::from_sub_protocols(""),
+            WebSocketEncoding::Binary
+        );
     }
 }
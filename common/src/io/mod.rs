@@ -5,7 +5,7 @@ pub mod message_socket;
 pub use message_socket::MessageSocket;
 
 pub mod message_web_socket;
-pub use message_web_socket::MessageWebSocket;
+pub use message_web_socket::{MessageWebSocket, WebSocketEncoding, JSON_SUB_PROTOCOL};
 
 pub mod serialization;
 pub use serialization::Serializable;
@@ -158,7 +158,7 @@ impl Serializable for HashSet<i32> {
     }
 }
 
-impl Serializable for HashMap<String, String> {
+impl Serializable for HashMap<Vec<u8>, Vec<u8>> {
     fn serialize(&self, writer: &mut Cursor<Vec<u8>>) -> io::Result<()> {
         (self.len() as u32).serialize(writer)?;
         for (key, value) in self {
@@ -173,10 +173,10 @@ impl Serializable for HashMap<String, String> {
         let capacity: usize = len
             .try_into()
             .map_err(|e| io::Error::new(io::ErrorKind::Other, e))?;
-        let mut hash_map: HashMap<String, String> = HashMap::with_capacity(capacity);
+        let mut hash_map: HashMap<Vec<u8>, Vec<u8>> = HashMap::with_capacity(capacity);
         for _ in 0..len {
-            let key = String::deserialize(reader)?;
-            let value = String::deserialize(reader)?;
+            let key = Vec::<u8>::deserialize(reader)?;
+            let value = Vec::<u8>::deserialize(reader)?;
             hash_map.insert(key, value);
         }
         Ok(hash_map)
@@ -192,40 +192,48 @@ impl Serializable for HashMap<String, String> {
     }
 }
 
-impl Serializable for HashMap<Vec<u8>, Vec<u8>> {
+impl<T> Serializable for Option<T>
+where
+    T: Serializable,
+{
     fn serialize(&self, writer: &mut Cursor<Vec<u8>>) -> io::Result<()> {
-        (self.len() as u32).serialize(writer)?;
-        for (key, value) in self {
-            key.serialize(writer)?;
-            value.serialize(writer)?;
+        match self {
+            Some(value) => {
+                true.serialize(writer)?;
+                value.serialize(writer)
+            }
+            None => false.serialize(writer),
         }
-        Ok(())
     }
 
     fn deserialize(reader: &mut Cursor<Vec<u8>>) -> io::Result<Self> {
-        let len = u32::deserialize(reader)?;
-        let capacity: usize = len
-            .try_into()
-            .map_err(|e| io::Error::new(io::ErrorKind::Other, e))?;
-        let mut hash_map: HashMap<Vec<u8>, Vec<u8>> = HashMap::with_capacity(capacity);
-        for _ in 0..len {
-            let key = Vec::<u8>::deserialize(reader)?;
-            let value = Vec::<u8>::deserialize(reader)?;
-            hash_map.insert(key, value);
+        match bool::deserialize(reader)? {
+            true => Ok(Some(T::deserialize(reader)?)),
+            false => Ok(None),
         }
-        Ok(hash_map)
     }
 
     fn size(&self) -> usize {
-        let mut len = size_of::<u32>();
-        for (key, value) in self {
-            len += key.size();
+        let mut len = size_of::<u8>();
+        if let Some(value) = self {
             len += value.size();
         }
         len
     }
 }
 
+/// Deserialize a field which was added to the end of a message. Messages from
+/// older peers end before the field, which then takes its default.
+pub fn deserialize_added<T>(reader: &mut Cursor<Vec<u8>>) -> io::Result<T>
+where
+    T: Serializable + Default,
+{
+    if reader.position() >= reader.get_ref().len() as u64 {
+        return Ok(T::default());
+    }
+    T::deserialize(reader)
+}
+
 #[cfg(test)]
 mod tests {
     use std::io::Seek;
@@ -304,19 +312,35 @@ mod tests {
     }
 
     #[test]
-    fn should_roundtrip_i32_hash_map() {
+    fn should_roundtrip_bytes_hash_map() {
         let mut cursor: Cursor<Vec<u8>> = Cursor::new(Vec::new());
 
-        let actual: HashMap<String, String> = HashMap::from([
-            ("a".to_string(), "one".to_string()),
-            ("b".to_string(), "two".to_string()),
+        let actual: HashMap<Vec<u8>, Vec<u8>> = HashMap::from([
+            (b"a".to_vec(), b"one".to_vec()),
+            (b"b".to_vec(), b"two".to_vec()),
         ]);
         actual.serialize(&mut cursor).expect("should serialize");
+        assert_eq!(actual.size(), cursor.position() as usize);
 
         cursor.rewind().expect("should rewind");
-        match HashMap::<String, String>::deserialize(&mut cursor) {
+        match HashMap::<Vec<u8>, Vec<u8>>::deserialize(&mut cursor) {
             Ok(expected) => assert_eq!(actual, expected),
             Err(error) => panic!("Failed to serialize: {:?}", error),
         }
     }
+
+    #[test]
+    fn should_roundtrip_option_string() {
+        for actual in [Some(String::from("Hello, World!")), None] {
+            let mut cursor: Cursor<Vec<u8>> = Cursor::new(Vec::new());
+            actual.serialize(&mut cursor).expect("should serialize");
+            assert_eq!(actual.size(), cursor.position() as usize);
+
+            cursor.rewind().expect("should rewind");
+            match Option::<String>::deserialize(&mut cursor) {
+                Ok(expected) => assert_eq!(actual, expected),
+                Err(error) => panic!("Failed to serialize: {:?}", error),
+            }
+        }
+    }
 }
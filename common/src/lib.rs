@@ -1,4 +1,5 @@
 pub mod messages;
+pub mod scram;
 
 pub mod io;
 pub use io::*;
\ No newline at end of file
@@ -0,0 +1,372 @@
+//! Conformance tests for the wire protocol.
+//!
+//! The machine readable specification is in `protocol/spec.json`, and the
+//! golden test vectors are in `protocol/vectors`. Each vector is the hex
+//! encoding of one of the messages below. After an intentional change to the
+//! protocol, bump the version and regenerate the vectors with:
+//!
+//! ```bash
+//! SQUAWKBUS_UPDATE_VECTORS=1 cargo test -p common conformance
+//! ```
+
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::io::Cursor;
+use std::path::PathBuf;
+
+use serde_json::Value;
+
+use crate::io::Serializable;
+
+use super::{DataPacket, Message, MessageType, PROTOCOL_VERSION};
+
+const UPDATE_VECTORS: &str = "SQUAWKBUS_UPDATE_VECTORS";
+
+fn protocol_dir() -> PathBuf {
+    PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("protocol")
+}
+
+fn load_spec() -> Value {
+    let text = fs::read_to_string(protocol_dir().join("spec.json")).expect("should read spec");
+    serde_json::from_str(&text).expect("should parse spec")
+}
+
+// Sets and maps are encoded in no particular order, so the data packets in
+// the vectors have at most one entitlement and one header.
+fn data_packets() -> Vec<DataPacket> {
+    vec![DataPacket::new(
+        HashSet::from([1]),
+        HashMap::from([(b"content-type".into(), b"text/plain".into())]),
+        "Hello, World!".into(),
+    )]
+}
+
+fn vectors() -> Vec<(&'static str, Message)> {
+    vec![
+        (
+            "authentication_request",
+            Message::AuthenticationRequest {
+                method: "basic".into(),
+                credentials: "bWFyeTpzZWNyZXQ=".into(),
+            },
+        ),
+        (
+            "authentication_response",
+            Message::AuthenticationResponse {
+                client_id: "67e55044-10b1-426f-9247-bb680e5fe0c8".into(),
+            },
+        ),
+        (
+            "authentication_challenge",
+            Message::AuthenticationChallenge {
+                challenge: "r=rOprNGfwEbeRWgbNEkqO%hvYDpWUa2RaTCAfuxFIlj)hNlF$k0,s=W22ZaJ0SNY7soEsUEjb6gQ==,i=4096".into(),
+            },
+        ),
+        (
+            "authentication_challenge_response",
+            Message::AuthenticationChallengeResponse {
+                response: "c=biws,r=rOprNGfwEbeRWgbNEkqO%hvYDpWUa2RaTCAfuxFIlj)hNlF$k0,p=dHzbZapWIk4jUhN+Ute9ytag9zjfMHgsqmmiz7AndVQ=".into(),
+            },
+        ),
+        (
+            "multicast_data",
+            Message::MulticastData {
+                topic: "VOD.LSE".into(),
+                data_packets: data_packets(),
+            },
+        ),
+        (
+            "multicast_data_empty",
+            Message::MulticastData {
+                topic: "VOD.LSE".into(),
+                data_packets: Vec::new(),
+            },
+        ),
+        (
+            "unicast_data",
+            Message::UnicastData {
+                client_id: "67e55044-10b1-426f-9247-bb680e5fe0c8".into(),
+                topic: "VOD.LSE".into(),
+                data_packets: data_packets(),
+            },
+        ),
+        (
+            "forwarded_subscription_request",
+            Message::ForwardedSubscriptionRequest {
+                host: "127.0.0.1".into(),
+                user: "mary".into(),
+                client_id: "67e55044-10b1-426f-9247-bb680e5fe0c8".into(),
+                topic: "VOD.LSE".into(),
+                count: 1,
+            },
+        ),
+        (
+            "notification_request",
+            Message::NotificationRequest {
+                pattern: "*.LSE".into(),
+                is_add: true,
+            },
+        ),
+        (
+            "notification_revoked",
+            Message::NotificationRevoked {
+                pattern: "*.LSE".into(),
+            },
+        ),
+        (
+            "limit_exceeded",
+            Message::LimitExceeded {
+                limit: "max_subscriptions_per_client".into(),
+                detail: "VOD.LSE".into(),
+            },
+        ),
+        (
+            "subscription_rejected",
+            Message::SubscriptionRejected {
+                topic: "VOD.LSE".into(),
+                reason: "invalid filter: unexpected end of filter".into(),
+            },
+        ),
+        (
+            "shutdown",
+            Message::Shutdown {
+                reason: "server shutting down".into(),
+            },
+        ),
+        (
+            "subscription_request_add",
+            Message::SubscriptionRequest {
+                topic: "VOD.LSE".into(),
+                is_add: true,
+                filter: None,
+                max_rate: None,
+            },
+        ),
+        (
+            "subscription_request_filtered",
+            Message::SubscriptionRequest {
+                topic: "VOD.LSE".into(),
+                is_add: true,
+                filter: Some("exchange = 'LSE' AND size > 1000".into()),
+                max_rate: None,
+            },
+        ),
+        (
+            "subscription_request_throttled",
+            Message::SubscriptionRequest {
+                topic: "VOD.LSE".into(),
+                is_add: true,
+                filter: None,
+                max_rate: Some(4),
+            },
+        ),
+        (
+            "subscription_request_remove",
+            Message::SubscriptionRequest {
+                topic: "VOD.LSE".into(),
+                is_add: false,
+                filter: None,
+                max_rate: None,
+            },
+        ),
+        (
+            "subscription_revoked",
+            Message::SubscriptionRevoked {
+                topic: "VOD.LSE".into(),
+            },
+        ),
+        (
+            "forwarded_multicast_data",
+            Message::ForwardedMulticastData {
+                host: "127.0.0.1".into(),
+                user: "mary".into(),
+                topic: "VOD.LSE".into(),
+                data_packets: data_packets(),
+            },
+        ),
+        (
+            "forwarded_unicast_data",
+            Message::ForwardedUnicastData {
+                host: "127.0.0.1".into(),
+                user: "mary".into(),
+                client_id: "67e55044-10b1-426f-9247-bb680e5fe0c8".into(),
+                topic: "VOD.LSE".into(),
+                data_packets: data_packets(),
+            },
+        ),
+    ]
+}
+
+fn message_types() -> Vec<MessageType> {
+    (0..=u8::MAX)
+        .filter_map(|byte| MessageType::try_from(byte).ok())
+        .collect()
+}
+
+fn encode(message: &Message) -> Vec<u8> {
+    let mut cursor: Cursor<Vec<u8>> = Cursor::new(Vec::new());
+    message.serialize(&mut cursor).expect("should serialize");
+    cursor.into_inner()
+}
+
+fn to_hex(buf: &[u8]) -> String {
+    buf.iter().map(|byte| format!("{byte:02x}")).collect()
+}
+
+fn from_hex(text: &str) -> Vec<u8> {
+    let text = text.trim();
+    (0..text.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&text[i..i + 2], 16).expect("should be hex"))
+        .collect()
+}
+
+/// Skips over a value of the given specification type, returning the number
+/// of bytes it occupied.
+fn skip_spec_type(type_name: &str, buf: &[u8]) -> usize {
+    let read_u32 = |offset: usize| {
+        u32::from_be_bytes(
+            buf[offset..offset + 4]
+                .try_into()
+                .expect("should be 4 bytes"),
+        ) as usize
+    };
+    match type_name {
+        "u8" | "bool" => 1,
+        "u32" | "i32" => 4,
+        "string" | "bytes" => 4 + read_u32(0),
+        "optional_string" => match buf[0] {
+            1 => 1 + skip_spec_type("string", &buf[1..]),
+            _ => 1,
+        },
+        "optional_u32" => match buf[0] {
+            1 => 1 + skip_spec_type("u32", &buf[1..]),
+            _ => 1,
+        },
+        "entitlements" => 4 + 4 * read_u32(0),
+        "headers" => {
+            let mut offset = 4;
+            for _ in 0..read_u32(0) {
+                offset += skip_spec_type("bytes", &buf[offset..]);
+                offset += skip_spec_type("bytes", &buf[offset..]);
+            }
+            offset
+        }
+        "data_packet" => {
+            let mut offset = 0;
+            for field_type in ["entitlements", "headers", "bytes"] {
+                offset += skip_spec_type(field_type, &buf[offset..]);
+            }
+            offset
+        }
+        "data_packets" => {
+            let mut offset = 4;
+            for _ in 0..read_u32(0) {
+                offset += skip_spec_type("data_packet", &buf[offset..]);
+            }
+            offset
+        }
+        _ => panic!("unknown type {type_name} in spec"),
+    }
+}
+
+#[test]
+fn spec_version_should_match_protocol_version() {
+    let spec = load_spec();
+    assert_eq!(spec["version"].as_u64(), Some(PROTOCOL_VERSION as u64));
+}
+
+#[test]
+fn spec_should_describe_every_message_type() {
+    let spec = load_spec();
+    let messages = spec["messages"].as_array().expect("should have messages");
+
+    let expected: Vec<(String, u64)> = message_types()
+        .into_iter()
+        .map(|message_type| {
+            let code: u8 = message_type.into();
+            (format!("{message_type:?}"), code as u64)
+        })
+        .collect();
+    let actual: Vec<(String, u64)> = messages
+        .iter()
+        .map(|message| {
+            (
+                message["name"]
+                    .as_str()
+                    .expect("should have name")
+                    .to_string(),
+                message["type"].as_u64().expect("should have type"),
+            )
+        })
+        .collect();
+
+    assert_eq!(actual, expected);
+}
+
+#[test]
+fn vectors_should_cover_every_message_type() {
+    let covered: Vec<MessageType> = vectors()
+        .iter()
+        .map(|(_, message)| message.message_type())
+        .collect();
+
+    for message_type in message_types() {
+        assert!(
+            covered.contains(&message_type),
+            "no vector for {message_type:?}"
+        );
+    }
+}
+
+#[test]
+fn should_match_vectors() {
+    let vectors_dir = protocol_dir().join("vectors");
+
+    if std::env::var_os(UPDATE_VECTORS).is_some() {
+        for (name, message) in vectors() {
+            let path = vectors_dir.join(format!("{name}.hex"));
+            fs::write(path, to_hex(&encode(&message)) + "\n").expect("should write vector");
+        }
+    }
+
+    for (name, message) in vectors() {
+        let path = vectors_dir.join(format!("{name}.hex"));
+        let text = fs::read_to_string(&path)
+            .unwrap_or_else(|e| panic!("should read {}: {e}", path.display()));
+        let expected = from_hex(&text);
+
+        assert_eq!(encode(&message), expected, "encoding differs for {name}");
+
+        let mut cursor = Cursor::new(expected.clone());
+        let decoded = Message::deserialize(&mut cursor).expect("should deserialize");
+        assert_eq!(decoded, message, "decoding differs for {name}");
+        assert_eq!(cursor.position() as usize, expected.len());
+        assert_eq!(message.size(), expected.len());
+    }
+}
+
+#[test]
+fn spec_should_decode_vectors() {
+    let spec = load_spec();
+    let messages = spec["messages"].as_array().expect("should have messages");
+
+    for (name, message) in vectors() {
+        let buf = encode(&message);
+        let spec_message = messages
+            .iter()
+            .find(|spec_message| spec_message["type"].as_u64() == Some(buf[0] as u64))
+            .unwrap_or_else(|| panic!("no spec for {name}"));
+
+        let mut offset = 1;
+        for field in spec_message["fields"]
+            .as_array()
+            .expect("should have fields")
+        {
+            let field_type = field["type"].as_str().expect("should have field type");
+            offset += skip_spec_type(field_type, &buf[offset..]);
+        }
+
+        assert_eq!(offset, buf.len(), "spec does not describe {name}");
+    }
+}
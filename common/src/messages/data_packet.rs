@@ -1,12 +1,17 @@
 use std::collections::{HashMap, HashSet};
 use std::io::{self, Cursor};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
 use crate::io::Serializable;
 
-#[derive(Debug, PartialEq, Clone)]
+use super::headers::{CONTENT_ENCODING, CONTENT_TYPE, MESSAGE_ID, TIMESTAMP};
+
+#[derive(Debug, PartialEq, Clone, serde::Serialize, serde::Deserialize)]
 pub struct DataPacket {
     pub entitlements: HashSet<i32>,
+    #[serde(with = "super::json::string_headers")]
     pub headers: HashMap<Vec<u8>, Vec<u8>>,
+    #[serde(with = "super::json::base64_bytes")]
     pub data: Vec<u8>,
 }
 
@@ -26,6 +31,68 @@ impl DataPacket {
     pub fn is_authorized(&self, all_entitlements: &HashSet<i32>) -> bool {
         all_entitlements.is_superset(&self.entitlements)
     }
+
+    /// Get a header, matching the name without regard to ASCII case.
+    pub fn header(&self, name: &[u8]) -> Option<&[u8]> {
+        if let Some(value) = self.headers.get(name) {
+            return Some(value.as_slice());
+        }
+        self.headers
+            .iter()
+            .find(|(key, _)| key.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_slice())
+    }
+
+    /// Get a header as a string, if it is present and valid UTF-8.
+    pub fn header_str(&self, name: &[u8]) -> Option<&str> {
+        self.header(name)
+            .and_then(|value| std::str::from_utf8(value).ok())
+    }
+
+    /// Set a header, replacing any with the same name regardless of case.
+    pub fn set_header(&mut self, name: &[u8], value: impl Into<Vec<u8>>) {
+        self.headers
+            .retain(|key, _| !key.eq_ignore_ascii_case(name));
+        self.headers.insert(name.to_ascii_lowercase(), value.into());
+    }
+
+    pub fn content_type(&self) -> Option<&str> {
+        self.header_str(CONTENT_TYPE)
+    }
+
+    pub fn set_content_type(&mut self, content_type: &str) {
+        self.set_header(CONTENT_TYPE, content_type)
+    }
+
+    pub fn content_encoding(&self) -> Option<&str> {
+        self.header_str(CONTENT_ENCODING)
+    }
+
+    pub fn set_content_encoding(&mut self, content_encoding: &str) {
+        self.set_header(CONTENT_ENCODING, content_encoding)
+    }
+
+    /// The timestamp header, which is sent as milliseconds since the epoch.
+    pub fn timestamp(&self) -> Option<SystemTime> {
+        let millis: u64 = self.header_str(TIMESTAMP)?.trim().parse().ok()?;
+        UNIX_EPOCH.checked_add(Duration::from_millis(millis))
+    }
+
+    pub fn set_timestamp(&mut self, timestamp: SystemTime) {
+        let millis = timestamp
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_millis();
+        self.set_header(TIMESTAMP, millis.to_string())
+    }
+
+    pub fn message_id(&self) -> Option<&str> {
+        self.header_str(MESSAGE_ID)
+    }
+
+    pub fn set_message_id(&mut self, message_id: &str) {
+        self.set_header(MESSAGE_ID, message_id)
+    }
 }
 
 impl Serializable for DataPacket {
@@ -148,4 +215,66 @@ mod tests {
 
         assert!(!data_packet.is_authorized(&empty_entitlements));
     }
+
+    #[test]
+    fn should_get_headers_regardless_of_case() {
+        let data_packet = DataPacket {
+            entitlements: HashSet::new(),
+            headers: HashMap::from([(b"Content-Type".into(), b"text/plain".into())]),
+            data: "Data 1".into(),
+        };
+
+        assert_eq!(data_packet.content_type(), Some("text/plain"));
+        assert_eq!(
+            data_packet.header(b"CONTENT-TYPE"),
+            Some(b"text/plain".as_slice())
+        );
+        assert_eq!(data_packet.content_encoding(), None);
+    }
+
+    #[test]
+    fn should_replace_headers_regardless_of_case() {
+        let mut data_packet = DataPacket {
+            entitlements: HashSet::new(),
+            headers: HashMap::from([(b"Content-Type".into(), b"text/plain".into())]),
+            data: "Data 1".into(),
+        };
+
+        data_packet.set_content_type("application/json");
+        data_packet.set_content_encoding("gzip");
+        data_packet.set_message_id("42");
+
+        let expected: HashMap<Vec<u8>, Vec<u8>> = HashMap::from([
+            (b"content-type".into(), b"application/json".into()),
+            (b"content-encoding".into(), b"gzip".into()),
+            (b"message-id".into(), b"42".into()),
+        ]);
+        assert_eq!(data_packet.headers, expected);
+        assert_eq!(data_packet.message_id(), Some("42"));
+    }
+
+    #[test]
+    fn should_roundtrip_timestamp() {
+        let mut data_packet = DataPacket::new(HashSet::new(), HashMap::new(), Vec::new());
+        let timestamp = UNIX_EPOCH + Duration::from_millis(1_700_000_000_123);
+
+        data_packet.set_timestamp(timestamp);
+
+        assert_eq!(
+            data_packet.header(TIMESTAMP),
+            Some(b"1700000000123".as_slice())
+        );
+        assert_eq!(data_packet.timestamp(), Some(timestamp));
+    }
+
+    #[test]
+    fn should_ignore_invalid_timestamp() {
+        let data_packet = DataPacket {
+            entitlements: HashSet::new(),
+            headers: HashMap::from([(TIMESTAMP.into(), b"yesterday".into())]),
+            data: Vec::new(),
+        };
+
+        assert_eq!(data_packet.timestamp(), None);
+    }
 }
@@ -0,0 +1,16 @@
+//! Well known data packet header names.
+//!
+//! Header names are compared without regard to ASCII case, but are sent in
+//! lower case. The values are UTF-8 strings.
+
+/// The media type of the data, e.g. "application/json".
+pub const CONTENT_TYPE: &[u8] = b"content-type";
+
+/// The encoding applied to the data, e.g. "gzip".
+pub const CONTENT_ENCODING: &[u8] = b"content-encoding";
+
+/// The time the data was created, as milliseconds since the UNIX epoch.
+pub const TIMESTAMP: &[u8] = b"timestamp";
+
+/// An identifier for the data, chosen by the publisher.
+pub const MESSAGE_ID: &[u8] = b"message-id";
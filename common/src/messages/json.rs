@@ -0,0 +1,147 @@
+//! Support for the JSON encoding of messages.
+//!
+//! Byte arrays are encoded as base64 strings and headers as a map of strings,
+//! so that messages can be handled by clients without a custom codec. Headers
+//! which are not UTF-8 are left out, so one publisher's binary header cannot
+//! stop the data reaching JSON clients.
+
+pub(crate) mod base64_bytes {
+    use base64::{engine::general_purpose::STANDARD, Engine};
+    use serde::{de::Error, Deserialize, Deserializer, Serializer};
+
+    pub fn serialize<S>(value: &[u8], serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        serializer.serialize_str(&STANDARD.encode(value))
+    }
+
+    pub fn deserialize<'de, D>(deserializer: D) -> Result<Vec<u8>, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        let text = String::deserialize(deserializer)?;
+        STANDARD.decode(text).map_err(D::Error::custom)
+    }
+}
+
+pub(crate) mod string_headers {
+    use std::collections::HashMap;
+
+    use serde::{de::Error as _, Deserialize, Deserializer, Serializer};
+
+    pub fn serialize<S>(value: &HashMap<Vec<u8>, Vec<u8>>, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        let mut headers: HashMap<&str, &str> = HashMap::with_capacity(value.len());
+        for (key, value) in value {
+            match (std::str::from_utf8(key), std::str::from_utf8(value)) {
+                (Ok(key), Ok(value)) => {
+                    headers.insert(key, value);
+                }
+                _ => log::debug!("Leaving out binary header {key:?} from json"),
+            }
+        }
+        serializer.collect_map(headers)
+    }
+
+    pub fn deserialize<'de, D>(deserializer: D) -> Result<HashMap<Vec<u8>, Vec<u8>>, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        let headers =
+            HashMap::<String, String>::deserialize(deserializer).map_err(D::Error::custom)?;
+        Ok(headers
+            .into_iter()
+            .map(|(key, value)| (key.into_bytes(), value.into_bytes()))
+            .collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::{HashMap, HashSet};
+
+    use serde_json::json;
+
+    use crate::messages::{DataPacket, Message};
+
+    #[test]
+    fn should_encode_multicast_data() {
+        let message = Message::MulticastData {
+            topic: "VOD.LSE".into(),
+            data_packets: vec![DataPacket::new(
+                HashSet::from([1]),
+                HashMap::from([(b"content-type".into(), b"text/plain".into())]),
+                "Hello, World!".into(),
+            )],
+        };
+
+        let actual = serde_json::to_value(&message).expect("should serialize");
+        let expected = json!({
+            "type": "MulticastData",
+            "topic": "VOD.LSE",
+            "data_packets": [
+                {
+                    "entitlements": [1],
+                    "headers": { "content-type": "text/plain" },
+                    "data": "SGVsbG8sIFdvcmxkIQ=="
+                }
+            ]
+        });
+        assert_eq!(actual, expected);
+    }
+
+    #[test]
+    fn should_roundtrip_authentication_request() {
+        let initial = Message::AuthenticationRequest {
+            method: "basic".into(),
+            credentials: vec![0, 1, 2, 255],
+        };
+
+        let text = serde_json::to_string(&initial).expect("should serialize");
+        let round_trip: Message = serde_json::from_str(&text).expect("should deserialize");
+        assert_eq!(initial, round_trip);
+    }
+
+    #[test]
+    fn should_decode_subscription_request() {
+        let text = r#"{"type": "SubscriptionRequest", "topic": "VOD.LSE", "is_add": true}"#;
+        let actual: Message = serde_json::from_str(text).expect("should deserialize");
+        let expected = Message::SubscriptionRequest {
+            topic: "VOD.LSE".into(),
+            is_add: true,
+            filter: None,
+            max_rate: None,
+        };
+        assert_eq!(actual, expected);
+    }
+
+    #[test]
+    fn should_reject_invalid_base64() {
+        let text = r#"{"type": "AuthenticationRequest", "method": "basic", "credentials": "!"}"#;
+        assert!(serde_json::from_str::<Message>(text).is_err());
+    }
+
+    #[test]
+    fn should_leave_out_binary_headers() {
+        let message = Message::MulticastData {
+            topic: "VOD.LSE".into(),
+            data_packets: vec![DataPacket::new(
+                HashSet::new(),
+                HashMap::from([
+                    (b"key".into(), vec![0xff, 0xfe]),
+                    (vec![0xff], b"value".into()),
+                    (b"content-type".into(), b"text/plain".into()),
+                ]),
+                Vec::new(),
+            )],
+        };
+        let actual = serde_json::to_value(&message).expect("should serialize");
+        assert_eq!(
+            actual["data_packets"][0]["headers"],
+            json!({ "content-type": "text/plain" })
+        );
+    }
+}
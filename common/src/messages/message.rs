@@ -1,20 +1,30 @@
 use std::io::{self, Cursor};
 
-use crate::io::Serializable;
+use crate::io::{serialization::deserialize_added, Serializable};
 
 use super::message_type::MessageType;
 
 use super::DataPacket;
 
-#[derive(Debug, PartialEq, Clone)]
+#[derive(Debug, PartialEq, Clone, serde::Serialize, serde::Deserialize)]
+#[serde(tag = "type")]
 pub enum Message {
     AuthenticationRequest {
         method: String,
+        #[serde(with = "super::json::base64_bytes")]
         credentials: Vec<u8>,
     },
     AuthenticationResponse {
         client_id: String,
     },
+    AuthenticationChallenge {
+        #[serde(with = "super::json::base64_bytes")]
+        challenge: Vec<u8>,
+    },
+    AuthenticationChallengeResponse {
+        #[serde(with = "super::json::base64_bytes")]
+        response: Vec<u8>,
+    },
     ForwardedMulticastData {
         host: String,
         user: String,
@@ -35,6 +45,12 @@ pub enum Message {
         topic: String,
         data_packets: Vec<DataPacket>,
     },
+    /// A request was refused, or the connection will be closed, as it
+    /// exceeded a limit of the server.
+    LimitExceeded {
+        limit: String,
+        detail: String,
+    },
     MulticastData {
         topic: String,
         data_packets: Vec<DataPacket>,
@@ -43,9 +59,31 @@ pub enum Message {
         pattern: String,
         is_add: bool,
     },
+    /// The client is no longer authorized for the notification, which has
+    /// been removed.
+    NotificationRevoked {
+        pattern: String,
+    },
+    /// The server is shutting down, and will close the connection once the
+    /// messages queued for the client have been sent.
+    Shutdown {
+        reason: String,
+    },
+    /// The subscription request was refused, and no subscription was added.
+    SubscriptionRejected {
+        topic: String,
+        reason: String,
+    },
     SubscriptionRequest {
         topic: String,
         is_add: bool,
+        filter: Option<String>,
+        max_rate: Option<u32>,
+    },
+    /// The client is no longer authorized for the subscription, which has
+    /// been removed.
+    SubscriptionRevoked {
+        topic: String,
     },
     UnicastData {
         client_id: String,
@@ -59,14 +97,23 @@ impl Message {
         match self {
             Message::AuthenticationRequest { .. } => MessageType::AuthenticationRequest,
             Message::AuthenticationResponse { .. } => MessageType::AuthenticationResponse,
+            Message::AuthenticationChallenge { .. } => MessageType::AuthenticationChallenge,
+            Message::AuthenticationChallengeResponse { .. } => {
+                MessageType::AuthenticationChallengeResponse
+            }
             Message::ForwardedMulticastData { .. } => MessageType::ForwardedMulticastData,
             Message::ForwardedSubscriptionRequest { .. } => {
                 MessageType::ForwardedSubscriptionRequest
             }
             Message::ForwardedUnicastData { .. } => MessageType::ForwardedUnicastData,
+            Message::LimitExceeded { .. } => MessageType::LimitExceeded,
             Message::MulticastData { .. } => MessageType::MulticastData,
             Message::NotificationRequest { .. } => MessageType::NotificationRequest,
+            Message::NotificationRevoked { .. } => MessageType::NotificationRevoked,
+            Message::Shutdown { .. } => MessageType::Shutdown,
+            Message::SubscriptionRejected { .. } => MessageType::SubscriptionRejected,
             Message::SubscriptionRequest { .. } => MessageType::SubscriptionRequest,
+            Message::SubscriptionRevoked { .. } => MessageType::SubscriptionRevoked,
             Message::UnicastData { .. } => MessageType::UnicastData,
         }
     }
@@ -87,6 +134,14 @@ impl Serializable for Message {
                 let client_id = String::deserialize(reader)?;
                 Ok(Message::AuthenticationResponse { client_id })
             }
+            Ok(MessageType::AuthenticationChallenge) => {
+                let challenge = Vec::deserialize(reader)?;
+                Ok(Message::AuthenticationChallenge { challenge })
+            }
+            Ok(MessageType::AuthenticationChallengeResponse) => {
+                let response = Vec::deserialize(reader)?;
+                Ok(Message::AuthenticationChallengeResponse { response })
+            }
             Ok(MessageType::ForwardedMulticastData) => {
                 let host = String::deserialize(reader)?;
                 let user = String::deserialize(reader)?;
@@ -140,10 +195,41 @@ impl Serializable for Message {
                 let is_add = bool::deserialize(reader)?;
                 Ok(Message::NotificationRequest { pattern, is_add })
             }
+            Ok(MessageType::NotificationRevoked) => {
+                let pattern = String::deserialize(reader)?;
+                Ok(Message::NotificationRevoked { pattern })
+            }
+            Ok(MessageType::LimitExceeded) => {
+                let limit = String::deserialize(reader)?;
+                let detail = String::deserialize(reader)?;
+                Ok(Message::LimitExceeded { limit, detail })
+            }
+            Ok(MessageType::Shutdown) => {
+                let reason = String::deserialize(reader)?;
+                Ok(Message::Shutdown { reason })
+            }
+            Ok(MessageType::SubscriptionRejected) => {
+                let topic = String::deserialize(reader)?;
+                let reason = String::deserialize(reader)?;
+                Ok(Message::SubscriptionRejected { topic, reason })
+            }
             Ok(MessageType::SubscriptionRequest) => {
                 let topic = String::deserialize(reader)?;
                 let is_add = bool::deserialize(reader)?;
-                Ok(Message::SubscriptionRequest { topic, is_add })
+                // Older clients end the message before the filter, or the
+                // rate.
+                let filter = deserialize_added::<Option<String>>(reader)?;
+                let max_rate = deserialize_added::<Option<u32>>(reader)?;
+                Ok(Message::SubscriptionRequest {
+                    topic,
+                    is_add,
+                    filter,
+                    max_rate,
+                })
+            }
+            Ok(MessageType::SubscriptionRevoked) => {
+                let topic = String::deserialize(reader)?;
+                Ok(Message::SubscriptionRevoked { topic })
             }
             Ok(MessageType::UnicastData) => {
                 let client_id = String::deserialize(reader)?;
@@ -174,6 +260,14 @@ impl Serializable for Message {
                 client_id.serialize(writer)?;
                 Ok(())
             }
+            Message::AuthenticationChallenge { challenge } => {
+                challenge.serialize(writer)?;
+                Ok(())
+            }
+            Message::AuthenticationChallengeResponse { response } => {
+                response.serialize(writer)?;
+                Ok(())
+            }
             Message::ForwardedMulticastData {
                 host,
                 user,
@@ -227,9 +321,38 @@ impl Serializable for Message {
                 is_add.serialize(writer)?;
                 Ok(())
             }
-            Message::SubscriptionRequest { topic, is_add } => {
+            Message::NotificationRevoked { pattern } => {
+                pattern.serialize(writer)?;
+                Ok(())
+            }
+            Message::LimitExceeded { limit, detail } => {
+                limit.serialize(writer)?;
+                detail.serialize(writer)?;
+                Ok(())
+            }
+            Message::Shutdown { reason } => {
+                reason.serialize(writer)?;
+                Ok(())
+            }
+            Message::SubscriptionRequest {
+                topic,
+                is_add,
+                filter,
+                max_rate,
+            } => {
                 topic.serialize(writer)?;
                 is_add.serialize(writer)?;
+                filter.serialize(writer)?;
+                max_rate.serialize(writer)?;
+                Ok(())
+            }
+            Message::SubscriptionRejected { topic, reason } => {
+                topic.serialize(writer)?;
+                reason.serialize(writer)?;
+                Ok(())
+            }
+            Message::SubscriptionRevoked { topic } => {
+                topic.serialize(writer)?;
                 Ok(())
             }
             Message::UnicastData {
@@ -253,6 +376,8 @@ impl Serializable for Message {
                     credentials,
                 } => method.size() + credentials.size(),
                 Message::AuthenticationResponse { client_id } => client_id.size(),
+                Message::AuthenticationChallenge { challenge } => challenge.size(),
+                Message::AuthenticationChallengeResponse { response } => response.size(),
                 Message::ForwardedMulticastData {
                     host,
                     user,
@@ -284,7 +409,17 @@ impl Serializable for Message {
                     data_packets,
                 } => topic.size() + data_packets.size(),
                 Message::NotificationRequest { pattern, is_add } => pattern.size() + is_add.size(),
-                Message::SubscriptionRequest { topic, is_add } => topic.size() + is_add.size(),
+                Message::NotificationRevoked { pattern } => pattern.size(),
+                Message::LimitExceeded { limit, detail } => limit.size() + detail.size(),
+                Message::Shutdown { reason } => reason.size(),
+                Message::SubscriptionRequest {
+                    topic,
+                    is_add,
+                    filter,
+                    max_rate,
+                } => topic.size() + is_add.size() + filter.size() + max_rate.size(),
+                Message::SubscriptionRejected { topic, reason } => topic.size() + reason.size(),
+                Message::SubscriptionRevoked { topic } => topic.size(),
                 Message::UnicastData {
                     client_id,
                     topic,
@@ -330,6 +465,34 @@ mod test_message {
         assert_eq!(initial, round_trip);
     }
 
+    #[test]
+    fn should_roundtrip_authentication_challenge() {
+        let initial = Message::AuthenticationChallenge {
+            challenge: "r=abc,s=def,i=4096".into(),
+        };
+
+        let mut cursor: Cursor<Vec<u8>> = Cursor::new(Vec::new());
+        initial.serialize(&mut cursor).expect("should serialize");
+
+        cursor.rewind().expect("should rewind");
+        let round_trip = Message::deserialize(&mut cursor).expect("should deserialize");
+        assert_eq!(initial, round_trip);
+    }
+
+    #[test]
+    fn should_roundtrip_authentication_challenge_response() {
+        let initial = Message::AuthenticationChallengeResponse {
+            response: "c=biws,r=abc,p=ghi".into(),
+        };
+
+        let mut cursor: Cursor<Vec<u8>> = Cursor::new(Vec::new());
+        initial.serialize(&mut cursor).expect("should serialize");
+
+        cursor.rewind().expect("should rewind");
+        let round_trip = Message::deserialize(&mut cursor).expect("should deserialize");
+        assert_eq!(initial, round_trip);
+    }
+
     #[test]
     fn should_roundtrip_forwarded_multicast_data() {
         let initial = Message::ForwardedMulticastData {
@@ -425,11 +588,121 @@ mod test_message {
         assert_eq!(initial, round_trip);
     }
 
+    #[test]
+    fn should_roundtrip_notification_revoked() {
+        let initial = Message::NotificationRevoked {
+            pattern: ".* LSE".into(),
+        };
+
+        let mut cursor: Cursor<Vec<u8>> = Cursor::new(Vec::new());
+        initial.serialize(&mut cursor).expect("should serialize");
+
+        cursor.rewind().expect("should rewind");
+        let round_trip = Message::deserialize(&mut cursor).unwrap();
+        assert_eq!(initial, round_trip);
+    }
+
+    #[test]
+    fn should_roundtrip_subscription_rejected() {
+        let initial = Message::SubscriptionRejected {
+            topic: "LSE.VOD".into(),
+            reason: "invalid filter: unexpected end of filter".into(),
+        };
+
+        let mut cursor: Cursor<Vec<u8>> = Cursor::new(Vec::new());
+        initial.serialize(&mut cursor).expect("should serialize");
+
+        cursor.rewind().expect("should rewind");
+        let round_trip = Message::deserialize(&mut cursor).unwrap();
+        assert_eq!(initial, round_trip);
+    }
+
+    #[test]
+    fn should_roundtrip_limit_exceeded() {
+        let initial = Message::LimitExceeded {
+            limit: "max_subscriptions_per_client".into(),
+            detail: "LSE.VOD".into(),
+        };
+
+        let mut cursor: Cursor<Vec<u8>> = Cursor::new(Vec::new());
+        initial.serialize(&mut cursor).expect("should serialize");
+
+        cursor.rewind().expect("should rewind");
+        let round_trip = Message::deserialize(&mut cursor).unwrap();
+        assert_eq!(initial, round_trip);
+    }
+
+    #[test]
+    fn should_roundtrip_shutdown() {
+        let initial = Message::Shutdown {
+            reason: "server shutting down".into(),
+        };
+
+        let mut cursor: Cursor<Vec<u8>> = Cursor::new(Vec::new());
+        initial.serialize(&mut cursor).expect("should serialize");
+
+        cursor.rewind().expect("should rewind");
+        let round_trip = Message::deserialize(&mut cursor).unwrap();
+        assert_eq!(initial, round_trip);
+    }
+
     #[test]
     fn should_roundtrip_subscription_request() {
         let initial = Message::SubscriptionRequest {
             topic: "VOD LSE".into(),
             is_add: true,
+            filter: Some("exchange = 'LSE'".into()),
+            max_rate: Some(4),
+        };
+
+        let mut cursor: Cursor<Vec<u8>> = Cursor::new(Vec::new());
+        initial.serialize(&mut cursor).expect("should serialize");
+
+        cursor.rewind().expect("should rewind");
+        let round_trip = Message::deserialize(&mut cursor).unwrap();
+        assert_eq!(initial, round_trip);
+    }
+
+    #[test]
+    fn should_deserialize_subscription_request_without_filter() {
+        // The message type, topic and is_add, as sent before filters.
+        let buf = vec![7, 0, 0, 0, 7, b'V', b'O', b'D', b'.', b'L', b'S', b'E', 1];
+        let mut cursor = Cursor::new(buf);
+        let actual = Message::deserialize(&mut cursor).expect("should deserialize");
+        assert_eq!(
+            actual,
+            Message::SubscriptionRequest {
+                topic: "VOD.LSE".into(),
+                is_add: true,
+                filter: None,
+                max_rate: None,
+            }
+        );
+    }
+
+    #[test]
+    fn should_deserialize_subscription_request_without_max_rate() {
+        // A filtered request, as sent before rates.
+        let initial = Message::SubscriptionRequest {
+            topic: "VOD.LSE".into(),
+            is_add: true,
+            filter: Some("exchange = 'LSE'".into()),
+            max_rate: None,
+        };
+        let mut cursor: Cursor<Vec<u8>> = Cursor::new(Vec::new());
+        initial.serialize(&mut cursor).expect("should serialize");
+        let mut buf = cursor.into_inner();
+        buf.pop();
+
+        let mut cursor = Cursor::new(buf);
+        let actual = Message::deserialize(&mut cursor).expect("should deserialize");
+        assert_eq!(actual, initial);
+    }
+
+    #[test]
+    fn should_roundtrip_subscription_revoked() {
+        let initial = Message::SubscriptionRevoked {
+            topic: "VOD LSE".into(),
         };
 
         let mut cursor: Cursor<Vec<u8>> = Cursor::new(Vec::new());
@@ -14,6 +14,13 @@ pub enum MessageType {
     SubscriptionRequest = 7,
     ForwardedMulticastData = 8,
     ForwardedUnicastData = 9,
+    AuthenticationChallenge = 10,
+    AuthenticationChallengeResponse = 11,
+    SubscriptionRevoked = 12,
+    NotificationRevoked = 13,
+    Shutdown = 14,
+    LimitExceeded = 15,
+    SubscriptionRejected = 16,
 }
 
 impl TryFrom<u8> for MessageType {
@@ -30,6 +37,13 @@ impl TryFrom<u8> for MessageType {
             7 => Ok(MessageType::SubscriptionRequest),
             8 => Ok(MessageType::ForwardedMulticastData),
             9 => Ok(MessageType::ForwardedUnicastData),
+            10 => Ok(MessageType::AuthenticationChallenge),
+            11 => Ok(MessageType::AuthenticationChallengeResponse),
+            12 => Ok(MessageType::SubscriptionRevoked),
+            13 => Ok(MessageType::NotificationRevoked),
+            14 => Ok(MessageType::Shutdown),
+            15 => Ok(MessageType::LimitExceeded),
+            16 => Ok(MessageType::SubscriptionRejected),
             _ => Err(()),
         }
     }
@@ -47,6 +61,13 @@ impl Into<u8> for MessageType {
             MessageType::SubscriptionRequest => 7,
             MessageType::ForwardedMulticastData => 8,
             MessageType::ForwardedUnicastData => 9,
+            MessageType::AuthenticationChallenge => 10,
+            MessageType::AuthenticationChallengeResponse => 11,
+            MessageType::SubscriptionRevoked => 12,
+            MessageType::NotificationRevoked => 13,
+            MessageType::Shutdown => 14,
+            MessageType::LimitExceeded => 15,
+            MessageType::SubscriptionRejected => 16,
         }
     }
 }
@@ -1,8 +1,18 @@
 mod data_packet;
 pub use data_packet::DataPacket;
 
+pub mod headers;
+
 mod message_type;
 pub use message_type::MessageType;
 
 mod message;
 pub use message::Message;
+
+mod json;
+
+#[cfg(test)]
+mod conformance;
+
+/// The version of the wire protocol described in `protocol/spec.json`.
+pub const PROTOCOL_VERSION: u32 = 8;
@@ -0,0 +1,509 @@
+//! SCRAM-SHA-256 authentication, as described in RFC 5802 and RFC 7677.
+//!
+//! The exchange takes place during the handshake:
+//!
+//! 1. The client sends an `AuthenticationRequest` with the method
+//!    `scram-sha-256` and the client first message as the credentials.
+//! 2. The server replies with an `AuthenticationChallenge` holding the server
+//!    first message.
+//! 3. The client sends an `AuthenticationChallengeResponse` holding the client
+//!    final message, which proves it knows the password.
+//! 4. The server sends an `AuthenticationChallenge` holding the server final
+//!    message, which proves it knows the credentials, followed by the
+//!    `AuthenticationResponse`.
+//!
+//! Channel binding is not supported, and passwords are used without SASLprep
+//! normalization.
+
+use std::fmt;
+use std::io::{self, ErrorKind};
+use std::num::NonZeroU32;
+use std::str::FromStr;
+
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use ring::rand::{SecureRandom, SystemRandom};
+use ring::{digest, hmac, pbkdf2};
+
+/// The name of the authentication method.
+pub const METHOD: &str = "scram-sha-256";
+
+/// The number of iterations used when generating credentials.
+pub const DEFAULT_ITERATIONS: u32 = 4096;
+
+// The header for a client which does not support channel binding.
+const GS2_HEADER: &str = "n,,";
+
+fn invalid(message: &str) -> io::Error {
+    io::Error::new(ErrorKind::InvalidData, message)
+}
+
+fn random_bytes(len: usize) -> io::Result<Vec<u8>> {
+    let mut bytes = vec![0; len];
+    SystemRandom::new()
+        .fill(&mut bytes)
+        .map_err(|_| io::Error::other("failed to generate random bytes"))?;
+    Ok(bytes)
+}
+
+/// Generate a printable nonce.
+pub fn generate_nonce() -> io::Result<String> {
+    Ok(STANDARD.encode(random_bytes(18)?))
+}
+
+fn hmac_sha256(key: &[u8], data: &[u8]) -> Vec<u8> {
+    let key = hmac::Key::new(hmac::HMAC_SHA256, key);
+    hmac::sign(&key, data).as_ref().to_vec()
+}
+
+fn sha256(data: &[u8]) -> Vec<u8> {
+    digest::digest(&digest::SHA256, data).as_ref().to_vec()
+}
+
+fn xor(lhs: &[u8], rhs: &[u8]) -> Vec<u8> {
+    lhs.iter().zip(rhs).map(|(a, b)| a ^ b).collect()
+}
+
+fn constant_time_eq(lhs: &[u8], rhs: &[u8]) -> bool {
+    lhs.len() == rhs.len() && lhs.iter().zip(rhs).fold(0, |acc, (a, b)| acc | (a ^ b)) == 0
+}
+
+fn salted_password(password: &str, salt: &[u8], iterations: NonZeroU32) -> Vec<u8> {
+    let mut salted_password = vec![0; digest::SHA256_OUTPUT_LEN];
+    pbkdf2::derive(
+        pbkdf2::PBKDF2_HMAC_SHA256,
+        iterations,
+        salt,
+        password.as_bytes(),
+        &mut salted_password,
+    );
+    salted_password
+}
+
+/// Escape a user name, as "," and "=" are reserved.
+fn encode_name(name: &str) -> String {
+    name.replace('=', "=3D").replace(',', "=2C")
+}
+
+fn decode_name(name: &str) -> io::Result<String> {
+    let mut decoded = String::new();
+    let mut rest = name;
+    while let Some(index) = rest.find('=') {
+        decoded.push_str(&rest[..index]);
+        match rest.get(index..index + 3) {
+            Some("=2C") => decoded.push(','),
+            Some("=3D") => decoded.push('='),
+            _ => return Err(invalid("invalid user name encoding")),
+        }
+        rest = &rest[index + 3..];
+    }
+    decoded.push_str(rest);
+    Ok(decoded)
+}
+
+/// Find the value of an attribute in a message such as "r=abc,s=def".
+fn attribute(message: &str, name: char) -> io::Result<&str> {
+    message
+        .split(',')
+        .find_map(|part| {
+            part.strip_prefix(name)
+                .and_then(|value| value.strip_prefix('='))
+        })
+        .ok_or_else(|| invalid(&format!("missing attribute \"{name}\"")))
+}
+
+fn decode_base64(value: &str) -> io::Result<Vec<u8>> {
+    STANDARD
+        .decode(value)
+        .map_err(|_| invalid("invalid base64"))
+}
+
+/// The credentials held by the server for a user, from which the password
+/// cannot be recovered.
+///
+/// These are written as `SCRAM-SHA-256$<iterations>:<salt>$<stored-key>:<server-key>`
+/// with base64 values, as used by PostgreSQL.
+#[derive(Debug, Clone, PartialEq)]
+pub struct StoredCredentials {
+    pub iterations: NonZeroU32,
+    pub salt: Vec<u8>,
+    pub stored_key: Vec<u8>,
+    pub server_key: Vec<u8>,
+}
+
+impl StoredCredentials {
+    pub fn new(password: &str, salt: &[u8], iterations: NonZeroU32) -> StoredCredentials {
+        let salted_password = salted_password(password, salt, iterations);
+        let client_key = hmac_sha256(&salted_password, b"Client Key");
+        StoredCredentials {
+            iterations,
+            salt: salt.to_vec(),
+            stored_key: sha256(&client_key),
+            server_key: hmac_sha256(&salted_password, b"Server Key"),
+        }
+    }
+
+    /// Create credentials with a random salt.
+    pub fn generate(password: &str) -> io::Result<StoredCredentials> {
+        let iterations = NonZeroU32::new(DEFAULT_ITERATIONS).expect("should be non-zero");
+        Ok(StoredCredentials::new(
+            password,
+            &random_bytes(16)?,
+            iterations,
+        ))
+    }
+}
+
+impl FromStr for StoredCredentials {
+    type Err = io::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid_credentials = || invalid("invalid scram credentials");
+
+        let rest = s
+            .strip_prefix("SCRAM-SHA-256$")
+            .ok_or_else(invalid_credentials)?;
+        let (iterations_and_salt, keys) = rest.split_once('$').ok_or_else(invalid_credentials)?;
+        let (iterations, salt) = iterations_and_salt
+            .split_once(':')
+            .ok_or_else(invalid_credentials)?;
+        let (stored_key, server_key) = keys.split_once(':').ok_or_else(invalid_credentials)?;
+
+        Ok(StoredCredentials {
+            iterations: iterations.parse().map_err(|_| invalid_credentials())?,
+            salt: decode_base64(salt)?,
+            stored_key: decode_base64(stored_key)?,
+            server_key: decode_base64(server_key)?,
+        })
+    }
+}
+
+impl fmt::Display for StoredCredentials {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "SCRAM-SHA-256${}:{}${}:{}",
+            self.iterations,
+            STANDARD.encode(&self.salt),
+            STANDARD.encode(&self.stored_key),
+            STANDARD.encode(&self.server_key)
+        )
+    }
+}
+
+/// Made up credentials for users who do not exist, so an unknown user fails
+/// the exchange in the same way, and in the same time, as a wrong password.
+///
+/// The keys are derived once, and each user is given a salt derived from a
+/// secret and the user name, so the salt does not change from one attempt to
+/// the next as it would if it were random.
+#[derive(Clone)]
+pub struct UnknownUsers {
+    secret: hmac::Key,
+    credentials: StoredCredentials,
+}
+
+impl UnknownUsers {
+    pub fn new() -> io::Result<UnknownUsers> {
+        Ok(UnknownUsers {
+            secret: hmac::Key::new(hmac::HMAC_SHA256, &random_bytes(32)?),
+            credentials: StoredCredentials::generate(&generate_nonce()?)?,
+        })
+    }
+
+    pub fn credentials(&self, username: &str) -> StoredCredentials {
+        let salt = hmac::sign(&self.secret, username.as_bytes());
+        StoredCredentials {
+            salt: salt.as_ref()[..16].to_vec(),
+            ..self.credentials.clone()
+        }
+    }
+}
+
+/// The client side of the exchange.
+pub struct ScramClient {
+    password: String,
+    client_nonce: String,
+    client_first_bare: String,
+    server_signature: Option<Vec<u8>>,
+}
+
+impl ScramClient {
+    pub fn new(username: &str, password: &str) -> io::Result<ScramClient> {
+        Ok(ScramClient::with_nonce(
+            username,
+            password,
+            &generate_nonce()?,
+        ))
+    }
+
+    pub fn with_nonce(username: &str, password: &str, client_nonce: &str) -> ScramClient {
+        ScramClient {
+            password: password.into(),
+            client_nonce: client_nonce.into(),
+            client_first_bare: format!("n={},r={}", encode_name(username), client_nonce),
+            server_signature: None,
+        }
+    }
+
+    pub fn client_first(&self) -> Vec<u8> {
+        format!("{GS2_HEADER}{}", self.client_first_bare).into_bytes()
+    }
+
+    /// Answer the server first message with the proof of the password.
+    pub fn client_final(&mut self, server_first: &[u8]) -> io::Result<Vec<u8>> {
+        let server_first =
+            std::str::from_utf8(server_first).map_err(|_| invalid("invalid server first"))?;
+        let nonce = attribute(server_first, 'r')?;
+        if !nonce.starts_with(&self.client_nonce) || nonce.len() == self.client_nonce.len() {
+            return Err(invalid("invalid server nonce"));
+        }
+        let salt = decode_base64(attribute(server_first, 's')?)?;
+        let iterations: NonZeroU32 = attribute(server_first, 'i')?
+            .parse()
+            .map_err(|_| invalid("invalid iteration count"))?;
+
+        let client_final_without_proof = format!("c={},r={}", STANDARD.encode(GS2_HEADER), nonce);
+        let auth_message = format!(
+            "{},{},{}",
+            self.client_first_bare, server_first, client_final_without_proof
+        );
+
+        let salted_password = salted_password(&self.password, &salt, iterations);
+        let client_key = hmac_sha256(&salted_password, b"Client Key");
+        let stored_key = sha256(&client_key);
+        let client_signature = hmac_sha256(&stored_key, auth_message.as_bytes());
+        let client_proof = xor(&client_key, &client_signature);
+
+        let server_key = hmac_sha256(&salted_password, b"Server Key");
+        self.server_signature = Some(hmac_sha256(&server_key, auth_message.as_bytes()));
+
+        Ok(format!(
+            "{},p={}",
+            client_final_without_proof,
+            STANDARD.encode(client_proof)
+        )
+        .into_bytes())
+    }
+
+    /// Check the server final message proves the server knows the
+    /// credentials.
+    pub fn verify_server_final(&self, server_final: &[u8]) -> io::Result<()> {
+        let server_final =
+            std::str::from_utf8(server_final).map_err(|_| invalid("invalid server final"))?;
+        if let Ok(error) = attribute(server_final, 'e') {
+            return Err(io::Error::new(
+                ErrorKind::PermissionDenied,
+                format!("authentication failed: {error}"),
+            ));
+        }
+        let server_signature = decode_base64(attribute(server_final, 'v')?)?;
+        match &self.server_signature {
+            Some(expected) if constant_time_eq(expected, &server_signature) => Ok(()),
+            _ => Err(io::Error::new(
+                ErrorKind::PermissionDenied,
+                "invalid server signature",
+            )),
+        }
+    }
+}
+
+/// The client first message, as received by the server.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ClientFirst {
+    pub username: String,
+    client_nonce: String,
+    client_first_bare: String,
+}
+
+impl ClientFirst {
+    pub fn parse(client_first: &[u8]) -> io::Result<ClientFirst> {
+        let client_first =
+            std::str::from_utf8(client_first).map_err(|_| invalid("invalid client first"))?;
+        let client_first_bare = client_first
+            .strip_prefix(GS2_HEADER)
+            .ok_or_else(|| invalid("channel binding is not supported"))?;
+        Ok(ClientFirst {
+            username: decode_name(attribute(client_first_bare, 'n')?)?,
+            client_nonce: attribute(client_first_bare, 'r')?.into(),
+            client_first_bare: client_first_bare.into(),
+        })
+    }
+}
+
+/// The server side of the exchange.
+pub struct ScramServer {
+    credentials: StoredCredentials,
+    client_first_bare: String,
+    server_first: String,
+    nonce: String,
+}
+
+impl ScramServer {
+    pub fn new(client_first: ClientFirst, credentials: StoredCredentials) -> io::Result<Self> {
+        Ok(ScramServer::with_nonce(
+            client_first,
+            credentials,
+            &generate_nonce()?,
+        ))
+    }
+
+    pub fn with_nonce(
+        client_first: ClientFirst,
+        credentials: StoredCredentials,
+        server_nonce: &str,
+    ) -> ScramServer {
+        let nonce = format!("{}{}", client_first.client_nonce, server_nonce);
+        let server_first = format!(
+            "r={},s={},i={}",
+            nonce,
+            STANDARD.encode(&credentials.salt),
+            credentials.iterations
+        );
+        ScramServer {
+            credentials,
+            client_first_bare: client_first.client_first_bare,
+            server_first,
+            nonce,
+        }
+    }
+
+    pub fn server_first(&self) -> Vec<u8> {
+        self.server_first.clone().into_bytes()
+    }
+
+    /// Verify the client proof, returning the server final message.
+    pub fn server_final(&self, client_final: &[u8]) -> io::Result<Vec<u8>> {
+        let client_final =
+            std::str::from_utf8(client_final).map_err(|_| invalid("invalid client final"))?;
+        if attribute(client_final, 'c')? != STANDARD.encode(GS2_HEADER) {
+            return Err(invalid("invalid channel binding"));
+        }
+        if attribute(client_final, 'r')? != self.nonce {
+            return Err(invalid("invalid nonce"));
+        }
+        let client_proof = decode_base64(attribute(client_final, 'p')?)?;
+        let (client_final_without_proof, _) = client_final
+            .rsplit_once(",p=")
+            .ok_or_else(|| invalid("missing proof"))?;
+
+        let auth_message = format!(
+            "{},{},{}",
+            self.client_first_bare, self.server_first, client_final_without_proof
+        );
+
+        let client_signature = hmac_sha256(&self.credentials.stored_key, auth_message.as_bytes());
+        let client_key = xor(&client_proof, &client_signature);
+        if client_proof.len() != client_signature.len()
+            || !constant_time_eq(&sha256(&client_key), &self.credentials.stored_key)
+        {
+            return Err(io::Error::new(ErrorKind::PermissionDenied, "invalid proof"));
+        }
+
+        let server_signature = hmac_sha256(&self.credentials.server_key, auth_message.as_bytes());
+        Ok(format!("v={}", STANDARD.encode(server_signature)).into_bytes())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // The example exchange from RFC 7677.
+    const CLIENT_NONCE: &str = "rOprNGfwEbeRWgbNEkqO";
+    const SERVER_NONCE: &str = "%hvYDpWUa2RaTCAfuxFIlj)hNlF$k0";
+    const SALT: &str = "W22ZaJ0SNY7soEsUEjb6gQ==";
+    const CLIENT_FIRST: &str = "n,,n=user,r=rOprNGfwEbeRWgbNEkqO";
+    const SERVER_FIRST: &str =
+        "r=rOprNGfwEbeRWgbNEkqO%hvYDpWUa2RaTCAfuxFIlj)hNlF$k0,s=W22ZaJ0SNY7soEsUEjb6gQ==,i=4096";
+    const CLIENT_FINAL: &str = "c=biws,r=rOprNGfwEbeRWgbNEkqO%hvYDpWUa2RaTCAfuxFIlj)hNlF$k0,p=dHzbZapWIk4jUhN+Ute9ytag9zjfMHgsqmmiz7AndVQ=";
+    const SERVER_FINAL: &str = "v=6rriTRBi23WpRR/wtup+mMhUZUn/dB5nLTJRsjl95G4=";
+
+    fn credentials(password: &str) -> StoredCredentials {
+        StoredCredentials::new(
+            password,
+            &STANDARD.decode(SALT).unwrap(),
+            NonZeroU32::new(4096).unwrap(),
+        )
+    }
+
+    #[test]
+    fn should_match_rfc_7677_example() {
+        let mut client = ScramClient::with_nonce("user", "pencil", CLIENT_NONCE);
+        assert_eq!(client.client_first(), CLIENT_FIRST.as_bytes());
+
+        let client_first = ClientFirst::parse(&client.client_first()).unwrap();
+        assert_eq!(client_first.username, "user");
+
+        let server = ScramServer::with_nonce(client_first, credentials("pencil"), SERVER_NONCE);
+        assert_eq!(server.server_first(), SERVER_FIRST.as_bytes());
+
+        let client_final = client.client_final(&server.server_first()).unwrap();
+        assert_eq!(client_final, CLIENT_FINAL.as_bytes());
+
+        let server_final = server.server_final(&client_final).unwrap();
+        assert_eq!(server_final, SERVER_FINAL.as_bytes());
+
+        client.verify_server_final(&server_final).unwrap();
+    }
+
+    #[test]
+    fn should_reject_wrong_password() {
+        let mut client = ScramClient::new("user", "wrong").unwrap();
+        let client_first = ClientFirst::parse(&client.client_first()).unwrap();
+        let server = ScramServer::new(client_first, credentials("pencil")).unwrap();
+
+        let client_final = client.client_final(&server.server_first()).unwrap();
+        assert!(server.server_final(&client_final).is_err());
+    }
+
+    #[test]
+    fn should_reject_wrong_server() {
+        // A server which does not know the credentials cannot prove it.
+        let mut client = ScramClient::new("user", "pencil").unwrap();
+        let client_first = ClientFirst::parse(&client.client_first()).unwrap();
+        let server = ScramServer::new(client_first, credentials("other")).unwrap();
+
+        client.client_final(&server.server_first()).unwrap();
+        assert!(client.verify_server_final(SERVER_FINAL.as_bytes()).is_err());
+    }
+
+    #[test]
+    fn should_escape_user_names() {
+        let client = ScramClient::with_nonce("a,b=c", "pencil", CLIENT_NONCE);
+        assert_eq!(
+            client.client_first(),
+            b"n,,n=a=2Cb=3Dc,r=rOprNGfwEbeRWgbNEkqO"
+        );
+        let client_first = ClientFirst::parse(&client.client_first()).unwrap();
+        assert_eq!(client_first.username, "a,b=c");
+
+        assert!(ClientFirst::parse(b"n,,n=a=2b,r=abc").is_err());
+        assert!(ClientFirst::parse(b"p=tls-unique,,n=user,r=abc").is_err());
+    }
+
+    #[test]
+    fn should_give_unknown_users_the_same_salt() {
+        let unknown_users = UnknownUsers::new().unwrap();
+        let tom = unknown_users.credentials("tom");
+        assert_eq!(tom, unknown_users.credentials("tom"));
+        assert_eq!(tom.salt.len(), 16);
+        assert_ne!(tom.salt, unknown_users.credentials("dick").salt);
+
+        // Another server gives different salts.
+        assert_ne!(
+            tom.salt,
+            UnknownUsers::new().unwrap().credentials("tom").salt
+        );
+    }
+
+    #[test]
+    fn should_roundtrip_stored_credentials() {
+        let credentials = credentials("pencil");
+        let text = credentials.to_string();
+        assert!(text.starts_with("SCRAM-SHA-256$4096:W22ZaJ0SNY7soEsUEjb6gQ==$"));
+        assert_eq!(text.parse::<StoredCredentials>().unwrap(), credentials);
+
+        assert!("md5$abc".parse::<StoredCredentials>().is_err());
+    }
+}
@@ -0,0 +1,758 @@
+//! An audit trail of authentication and authorization decisions.
+//!
+//! Events are emitted with `emit` from wherever the decision is made, and
+//! written by a background thread to the sink given on the command line: a
+//! file of JSON lines which is rotated by size, or a syslog socket. When no
+//! sink is configured the events are discarded.
+//!
+//! The queue to the thread is bounded, and events which do not fit are
+//! counted and dropped rather than holding up the hub. The events about data
+//! withheld from clients, which may happen for every message, are aggregated
+//! over an interval. When the server stops, `stop` writes what is left.
+
+use std::collections::HashMap;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufWriter, Write};
+use std::os::unix::net::UnixDatagram;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, SyncSender, TryRecvError, TrySendError};
+use std::sync::{Mutex, OnceLock};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant, SystemTime};
+
+use crate::clients::Client;
+use crate::metrics::METRICS;
+
+/// The largest file before it is rotated, unless given.
+pub const DEFAULT_MAX_FILE_SIZE: u64 = 10 * 1024 * 1024;
+/// The number of rotated files kept, unless given.
+pub const DEFAULT_MAX_FILES: usize = 5;
+
+/// The syslog facility for security and authorization messages.
+const LOG_AUTHPRIV: u8 = 10;
+const LOG_WARNING: u8 = 4;
+const LOG_INFO: u8 = 6;
+
+/// The events waiting to be written.
+const QUEUE_SIZE: usize = 10_000;
+/// How long the events about withheld data are aggregated.
+const AGGREGATE_INTERVAL: Duration = Duration::from_secs(10);
+/// The most withheld data events aggregated at once.
+const MAX_AGGREGATED: usize = 10_000;
+
+static AUDIT: OnceLock<Audit> = OnceLock::new();
+
+/// Where the audit events are written.
+#[derive(Debug, Clone, PartialEq)]
+pub enum AuditOption {
+    /// JSON lines, rotated to `<path>.1`, `<path>.2` ... when `max_size` bytes
+    /// are exceeded, keeping `max_files` rotated files.
+    File {
+        path: PathBuf,
+        max_size: u64,
+        max_files: usize,
+    },
+    /// A unix datagram socket, such as `/dev/log`.
+    Syslog(PathBuf),
+}
+
+/// What happened to a subscription or notification request.
+#[derive(Debug, Clone, Copy, PartialEq, serde::Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum AuditAction {
+    Added,
+    Removed,
+    Revoked,
+}
+
+#[derive(Debug, Clone, PartialEq, serde::Serialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum AuditEvent {
+    Connect {
+        client_id: String,
+        host: String,
+    },
+    AuthenticationSucceeded {
+        client_id: String,
+        host: String,
+        method: String,
+        user: String,
+        groups: Vec<String>,
+    },
+    AuthenticationFailed {
+        client_id: String,
+        host: String,
+        method: String,
+        reason: String,
+    },
+    Subscription {
+        client_id: String,
+        host: String,
+        user: String,
+        topic: String,
+        action: AuditAction,
+    },
+    Notification {
+        client_id: String,
+        host: String,
+        user: String,
+        pattern: String,
+        action: AuditAction,
+    },
+    /// The publisher and subscriber share no entitlements, so none of the
+    /// messages were sent.
+    PublishDenied {
+        publisher_id: String,
+        publisher: String,
+        subscriber_id: String,
+        subscriber: String,
+        topic: String,
+        messages: usize,
+    },
+    /// Some packets of the messages were withheld as the receiver is not
+    /// entitled to them.
+    PublishFiltered {
+        publisher_id: String,
+        publisher: String,
+        subscriber_id: String,
+        subscriber: String,
+        topic: String,
+        messages: usize,
+        packets: usize,
+    },
+    /// The sender and receiver share no entitlements, so none of the
+    /// messages were sent.
+    UnicastDenied {
+        sender_id: String,
+        sender: String,
+        receiver_id: String,
+        receiver: String,
+        topic: String,
+        messages: usize,
+    },
+    /// Events were dropped as the audit trail could not keep up.
+    Dropped {
+        events: u64,
+    },
+}
+
+/// The withheld data events are aggregated by their kind, the ids of the
+/// sender and receiver, and the topic.
+type AggregateKey = (&'static str, String, String, String);
+
+impl AuditEvent {
+    pub fn subscription(
+        client_id: &str,
+        client: &Client,
+        topic: &str,
+        action: AuditAction,
+    ) -> Self {
+        AuditEvent::Subscription {
+            client_id: client_id.into(),
+            host: client.host.clone(),
+            user: client.user.clone(),
+            topic: topic.into(),
+            action,
+        }
+    }
+
+    pub fn notification(
+        client_id: &str,
+        client: &Client,
+        pattern: &str,
+        action: AuditAction,
+    ) -> Self {
+        AuditEvent::Notification {
+            client_id: client_id.into(),
+            host: client.host.clone(),
+            user: client.user.clone(),
+            pattern: pattern.into(),
+            action,
+        }
+    }
+
+    fn aggregate_key(&self) -> Option<AggregateKey> {
+        match self {
+            AuditEvent::PublishDenied {
+                publisher_id,
+                subscriber_id,
+                topic,
+                ..
+            } => Some((
+                "publish_denied",
+                publisher_id.clone(),
+                subscriber_id.clone(),
+                topic.clone(),
+            )),
+            AuditEvent::PublishFiltered {
+                publisher_id,
+                subscriber_id,
+                topic,
+                ..
+            } => Some((
+                "publish_filtered",
+                publisher_id.clone(),
+                subscriber_id.clone(),
+                topic.clone(),
+            )),
+            AuditEvent::UnicastDenied {
+                sender_id,
+                receiver_id,
+                topic,
+                ..
+            } => Some((
+                "unicast_denied",
+                sender_id.clone(),
+                receiver_id.clone(),
+                topic.clone(),
+            )),
+            _ => None,
+        }
+    }
+
+    /// Add the counts of an event with the same aggregate key.
+    fn aggregate(&mut self, other: &AuditEvent) {
+        match (self, other) {
+            (
+                AuditEvent::PublishDenied { messages, .. },
+                AuditEvent::PublishDenied {
+                    messages: other_messages,
+                    ..
+                },
+            )
+            | (
+                AuditEvent::UnicastDenied { messages, .. },
+                AuditEvent::UnicastDenied {
+                    messages: other_messages,
+                    ..
+                },
+            ) => *messages += other_messages,
+            (
+                AuditEvent::PublishFiltered {
+                    messages, packets, ..
+                },
+                AuditEvent::PublishFiltered {
+                    messages: other_messages,
+                    packets: other_packets,
+                    ..
+                },
+            ) => {
+                *messages += other_messages;
+                *packets += other_packets;
+            }
+            _ => {}
+        }
+    }
+
+    /// Denials and failures are warnings, everything else is information.
+    fn is_warning(&self) -> bool {
+        match self {
+            AuditEvent::AuthenticationFailed { .. }
+            | AuditEvent::PublishDenied { .. }
+            | AuditEvent::PublishFiltered { .. }
+            | AuditEvent::UnicastDenied { .. }
+            | AuditEvent::Dropped { .. } => true,
+            AuditEvent::Subscription { action, .. } | AuditEvent::Notification { action, .. } => {
+                *action == AuditAction::Revoked
+            }
+            AuditEvent::Connect { .. } | AuditEvent::AuthenticationSucceeded { .. } => false,
+        }
+    }
+}
+
+#[derive(serde::Serialize)]
+struct AuditRecord<'a> {
+    timestamp: String,
+    #[serde(flatten)]
+    event: &'a AuditEvent,
+}
+
+fn to_json(timestamp: SystemTime, event: &AuditEvent) -> io::Result<String> {
+    let record = AuditRecord {
+        timestamp: humantime::format_rfc3339_millis(timestamp).to_string(),
+        event,
+    };
+    serde_json::to_string(&record).map_err(io::Error::other)
+}
+
+/// What is sent to the thread writing the events.
+enum Queued {
+    Event(SystemTime, AuditEvent),
+    /// Write the aggregated events, flush the sink, and finish.
+    Stop,
+}
+
+/// The queue to the thread writing the events.
+struct Audit {
+    tx: SyncSender<Queued>,
+    thread: Mutex<Option<JoinHandle<()>>>,
+    /// The withheld data events since they were last written, with the time
+    /// of the first.
+    aggregated: Mutex<HashMap<AggregateKey, (SystemTime, AuditEvent)>>,
+    /// The events dropped since the count was last written.
+    dropped: AtomicU64,
+}
+
+impl Audit {
+    fn new(tx: SyncSender<Queued>) -> Self {
+        Audit {
+            tx,
+            thread: Mutex::new(None),
+            aggregated: Mutex::new(HashMap::new()),
+            dropped: AtomicU64::new(0),
+        }
+    }
+
+    fn emit(&self, event: AuditEvent) {
+        let Some(key) = event.aggregate_key() else {
+            match self.tx.try_send(Queued::Event(SystemTime::now(), event)) {
+                Ok(()) => {}
+                Err(TrySendError::Full(_)) => self.drop_event(),
+                Err(TrySendError::Disconnected(_)) => log::error!("the audit trail has stopped"),
+            }
+            return;
+        };
+
+        let mut aggregated = self.aggregated.lock().unwrap();
+        if let Some((_, aggregate)) = aggregated.get_mut(&key) {
+            aggregate.aggregate(&event);
+        } else if aggregated.len() < MAX_AGGREGATED {
+            aggregated.insert(key, (SystemTime::now(), event));
+        } else {
+            self.drop_event();
+        }
+    }
+
+    fn drop_event(&self) {
+        self.dropped.fetch_add(1, Ordering::Relaxed);
+        METRICS.audit_events_dropped.inc();
+    }
+
+    /// Take the aggregated events, and the count of dropped events, to be
+    /// written.
+    fn take_aggregated(&self) -> Vec<(SystemTime, AuditEvent)> {
+        let mut events: Vec<_> = self
+            .aggregated
+            .lock()
+            .unwrap()
+            .drain()
+            .map(|(_, event)| event)
+            .collect();
+        events.sort_by_key(|(timestamp, _)| *timestamp);
+
+        let dropped = self.dropped.swap(0, Ordering::Relaxed);
+        if dropped > 0 {
+            log::warn!("dropped {dropped} audit events");
+            events.push((SystemTime::now(), AuditEvent::Dropped { events: dropped }));
+        }
+        events
+    }
+}
+
+/// Record an event, if auditing has been started.
+pub fn emit(event: AuditEvent) {
+    if let Some(audit) = AUDIT.get() {
+        audit.emit(event);
+    }
+}
+
+/// Start writing events to the sink.
+pub fn start(option: &AuditOption) -> io::Result<()> {
+    let sink: Box<dyn AuditSink> = match option {
+        AuditOption::File {
+            path,
+            max_size,
+            max_files,
+        } => Box::new(FileSink::new(path, *max_size, *max_files)?),
+        AuditOption::Syslog(path) => Box::new(SyslogSink::new(path)?),
+    };
+
+    let (tx, rx) = mpsc::sync_channel(QUEUE_SIZE);
+    AUDIT
+        .set(Audit::new(tx))
+        .map_err(|_| io::Error::other("audit already started"))?;
+    let audit = AUDIT.get().unwrap();
+
+    let thread = thread::Builder::new()
+        .name("audit".into())
+        .spawn(move || write_events(audit, rx, sink))?;
+    *audit.thread.lock().unwrap() = Some(thread);
+
+    log::info!("Auditing to {option:?}");
+
+    Ok(())
+}
+
+/// Write the events still queued and aggregated, and wait for the sink to be
+/// flushed. Events emitted afterwards are lost.
+pub fn stop() {
+    let Some(audit) = AUDIT.get() else {
+        return;
+    };
+    let Some(thread) = audit.thread.lock().unwrap().take() else {
+        return;
+    };
+    // Unlike the events, the request to stop waits for room in the queue.
+    if audit.tx.send(Queued::Stop).is_ok() && thread.join().is_err() {
+        log::error!("the audit trail failed while stopping");
+    }
+}
+
+/// Write the queued events as they arrive, and the aggregated events at each
+/// interval and when stopped. The sink is flushed whenever the queue is empty,
+/// and when stopped.
+fn write_events(audit: &Audit, rx: Receiver<Queued>, mut sink: Box<dyn AuditSink>) {
+    let write = |sink: &mut Box<dyn AuditSink>, timestamp, event: AuditEvent| {
+        if let Err(error) = sink.write(timestamp, &event) {
+            log::error!("failed to write audit event {event:?}: {error}");
+        }
+    };
+
+    let mut next_aggregate = Instant::now() + AGGREGATE_INTERVAL;
+    loop {
+        let received = match rx.try_recv() {
+            Ok(received) => Some(received),
+            Err(TryRecvError::Empty) => {
+                if let Err(error) = sink.flush() {
+                    log::error!("failed to flush audit events: {error}");
+                }
+                match rx.recv_timeout(next_aggregate.saturating_duration_since(Instant::now())) {
+                    Ok(received) => Some(received),
+                    Err(RecvTimeoutError::Timeout) => None,
+                    Err(RecvTimeoutError::Disconnected) => break,
+                }
+            }
+            Err(TryRecvError::Disconnected) => break,
+        };
+        match received {
+            Some(Queued::Event(timestamp, event)) => write(&mut sink, timestamp, event),
+            Some(Queued::Stop) => break,
+            None => {}
+        }
+
+        if Instant::now() >= next_aggregate {
+            for (timestamp, event) in audit.take_aggregated() {
+                write(&mut sink, timestamp, event);
+            }
+            next_aggregate = Instant::now() + AGGREGATE_INTERVAL;
+        }
+    }
+
+    for (timestamp, event) in audit.take_aggregated() {
+        write(&mut sink, timestamp, event);
+    }
+    if let Err(error) = sink.flush() {
+        log::error!("failed to flush audit events: {error}");
+    }
+}
+
+trait AuditSink: Send {
+    fn write(&mut self, timestamp: SystemTime, event: &AuditEvent) -> io::Result<()>;
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+struct FileSink {
+    path: PathBuf,
+    max_size: u64,
+    max_files: usize,
+    file: BufWriter<File>,
+    size: u64,
+}
+
+impl FileSink {
+    fn new(path: &Path, max_size: u64, max_files: usize) -> io::Result<Self> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        let size = file.metadata()?.len();
+        Ok(FileSink {
+            path: path.into(),
+            max_size,
+            max_files,
+            file: BufWriter::new(file),
+            size,
+        })
+    }
+
+    fn rotated_path(&self, index: usize) -> PathBuf {
+        let mut path = self.path.clone().into_os_string();
+        path.push(format!(".{index}"));
+        path.into()
+    }
+
+    /// Move each file up one place, dropping the oldest, and start a new
+    /// file.
+    fn rotate(&mut self) -> io::Result<()> {
+        self.file.flush()?;
+        if self.max_files == 0 {
+            self.file = BufWriter::new(File::create(&self.path)?);
+        } else {
+            for index in (1..self.max_files).rev() {
+                let from = self.rotated_path(index);
+                if from.exists() {
+                    fs::rename(&from, self.rotated_path(index + 1))?;
+                }
+            }
+            fs::rename(&self.path, self.rotated_path(1))?;
+            self.file = BufWriter::new(
+                OpenOptions::new()
+                    .create(true)
+                    .append(true)
+                    .open(&self.path)?,
+            );
+        }
+        self.size = 0;
+        Ok(())
+    }
+}
+
+impl AuditSink for FileSink {
+    fn write(&mut self, timestamp: SystemTime, event: &AuditEvent) -> io::Result<()> {
+        let mut line = to_json(timestamp, event)?;
+        line.push('\n');
+
+        if self.size > 0 && self.size + line.len() as u64 > self.max_size {
+            self.rotate()?;
+        }
+
+        self.file.write_all(line.as_bytes())?;
+        self.size += line.len() as u64;
+        Ok(())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.file.flush()
+    }
+}
+
+struct SyslogSink {
+    path: PathBuf,
+    socket: Option<UnixDatagram>,
+}
+
+impl SyslogSink {
+    fn new(path: &Path) -> io::Result<Self> {
+        let socket = UnixDatagram::unbound()?;
+        socket.connect(path)?;
+        Ok(SyslogSink {
+            path: path.into(),
+            socket: Some(socket),
+        })
+    }
+
+    fn send(&mut self, message: &[u8]) -> io::Result<()> {
+        let socket = match self.socket.take() {
+            Some(socket) => socket,
+            None => {
+                let socket = UnixDatagram::unbound()?;
+                socket.connect(&self.path)?;
+                socket
+            }
+        };
+        socket.send(message)?;
+        self.socket = Some(socket);
+        Ok(())
+    }
+}
+
+impl AuditSink for SyslogSink {
+    fn write(&mut self, timestamp: SystemTime, event: &AuditEvent) -> io::Result<()> {
+        let severity = if event.is_warning() {
+            LOG_WARNING
+        } else {
+            LOG_INFO
+        };
+        let message = format!(
+            "<{}>squawkbus[{}]: {}",
+            LOG_AUTHPRIV * 8 + severity,
+            std::process::id(),
+            to_json(timestamp, event)?
+        );
+        // The daemon may have restarted, so reconnect once on failure.
+        self.send(message.as_bytes())
+            .or_else(|_| self.send(message.as_bytes()))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    use super::*;
+
+    fn connect(client_id: &str) -> AuditEvent {
+        AuditEvent::Connect {
+            client_id: client_id.into(),
+            host: "127.0.0.1".into(),
+        }
+    }
+
+    #[test]
+    fn should_serialize_as_json() {
+        let timestamp = SystemTime::UNIX_EPOCH + Duration::from_millis(1500);
+        let event = AuditEvent::Subscription {
+            client_id: "client1".into(),
+            host: "127.0.0.1".into(),
+            user: "tom".into(),
+            topic: "LSE.TSCO".into(),
+            action: AuditAction::Revoked,
+        };
+        assert_eq!(
+            to_json(timestamp, &event).unwrap(),
+            r#"{"timestamp":"1970-01-01T00:00:01.500Z","event":"subscription","client_id":"client1","host":"127.0.0.1","user":"tom","topic":"LSE.TSCO","action":"revoked"}"#
+        );
+        assert!(event.is_warning());
+    }
+
+    #[test]
+    fn should_rotate_files() {
+        let dir = std::env::temp_dir().join(format!("audit-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("audit.log");
+
+        let line_size = to_json(SystemTime::UNIX_EPOCH, &connect("client1"))
+            .unwrap()
+            .len() as u64
+            + 1;
+        // Two lines fit in a file, and two rotated files are kept.
+        let mut sink = FileSink::new(&path, line_size * 2, 2).unwrap();
+        for client_id in [
+            "client1", "client2", "client3", "client4", "client5", "client6", "client7",
+        ] {
+            sink.write(SystemTime::UNIX_EPOCH, &connect(client_id))
+                .unwrap();
+        }
+        sink.flush().unwrap();
+
+        let read = |path: PathBuf| {
+            fs::read_to_string(path)
+                .unwrap()
+                .lines()
+                .map(|line| {
+                    serde_json::from_str::<serde_json::Value>(line).unwrap()["client_id"].clone()
+                })
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(read(path.clone()), vec!["client7"]);
+        assert_eq!(read(sink.rotated_path(1)), vec!["client5", "client6"]);
+        assert_eq!(read(sink.rotated_path(2)), vec!["client3", "client4"]);
+        assert!(!sink.rotated_path(3).exists());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn should_aggregate_withheld_data() {
+        let (tx, _rx) = mpsc::sync_channel(1);
+        let audit = Audit::new(tx);
+        let filtered = |subscriber_id: &str, messages, packets| AuditEvent::PublishFiltered {
+            publisher_id: "client1".into(),
+            publisher: "tom".into(),
+            subscriber_id: subscriber_id.into(),
+            subscriber: "dick".into(),
+            topic: "LSE.TSCO".into(),
+            messages,
+            packets,
+        };
+
+        audit.emit(filtered("client2", 1, 1));
+        audit.emit(filtered("client2", 1, 2));
+        audit.emit(filtered("client3", 1, 1));
+
+        let mut events: Vec<_> = audit
+            .take_aggregated()
+            .into_iter()
+            .map(|(_, event)| event)
+            .collect();
+        events.sort_by_key(|event| event.aggregate_key());
+        assert_eq!(
+            events,
+            vec![filtered("client2", 2, 3), filtered("client3", 1, 1)]
+        );
+        assert!(audit.take_aggregated().is_empty());
+    }
+
+    #[test]
+    fn should_count_dropped_events() {
+        let (tx, rx) = mpsc::sync_channel(1);
+        let audit = Audit::new(tx);
+
+        audit.emit(connect("client1"));
+        audit.emit(connect("client2"));
+        audit.emit(connect("client3"));
+
+        let Ok(Queued::Event(_, event)) = rx.try_recv() else {
+            panic!("expected an event");
+        };
+        assert_eq!(event, connect("client1"));
+        assert!(rx.try_recv().is_err());
+        assert_eq!(
+            audit.take_aggregated().pop().unwrap().1,
+            AuditEvent::Dropped { events: 2 }
+        );
+    }
+
+    /// Keeps the events written, and whether they were flushed.
+    struct MemorySink(Arc<Mutex<(Vec<AuditEvent>, bool)>>);
+
+    impl AuditSink for MemorySink {
+        fn write(&mut self, _: SystemTime, event: &AuditEvent) -> io::Result<()> {
+            let mut state = self.0.lock().unwrap();
+            state.0.push(event.clone());
+            state.1 = false;
+            Ok(())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            self.0.lock().unwrap().1 = true;
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn should_write_remaining_events_when_stopped() {
+        let (tx, rx) = mpsc::sync_channel(8);
+        let audit = Audit::new(tx);
+        let denied = AuditEvent::PublishDenied {
+            publisher_id: "client1".into(),
+            publisher: "tom".into(),
+            subscriber_id: "client2".into(),
+            subscriber: "dick".into(),
+            topic: "LSE.TSCO".into(),
+            messages: 1,
+        };
+
+        audit.emit(connect("client1"));
+        audit.emit(denied.clone());
+        audit.tx.send(Queued::Stop).unwrap();
+
+        // The aggregate is written long before the interval is up.
+        let state = Arc::new(Mutex::new((Vec::new(), false)));
+        write_events(&audit, rx, Box::new(MemorySink(state.clone())));
+        let (events, is_flushed) = state.lock().unwrap().clone();
+        assert_eq!(events, vec![connect("client1"), denied]);
+        assert!(is_flushed);
+    }
+
+    #[test]
+    fn should_send_to_syslog() {
+        let path = std::env::temp_dir().join(format!("audit-syslog-{}.sock", std::process::id()));
+        let _ = fs::remove_file(&path);
+        let server = UnixDatagram::bind(&path).unwrap();
+
+        let mut sink = SyslogSink::new(&path).unwrap();
+        sink.write(SystemTime::UNIX_EPOCH, &connect("client1"))
+            .unwrap();
+
+        let mut buf = [0; 1024];
+        let len = server.recv(&mut buf).unwrap();
+        let message = String::from_utf8_lossy(&buf[..len]);
+        assert!(message.starts_with(&format!("<86>squawkbus[{}]: {{", std::process::id())));
+        assert!(message.ends_with(r#""event":"connect","client_id":"client1","host":"127.0.0.1"}"#));
+
+        fs::remove_file(&path).unwrap();
+    }
+}
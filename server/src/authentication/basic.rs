@@ -0,0 +1,102 @@
+//! Authentication with an htpasswd file.
+
+use std::collections::HashMap;
+use std::fs::read_to_string;
+use std::io::{Error, ErrorKind, Result};
+use std::path::PathBuf;
+
+use futures_util::future::BoxFuture;
+use htpasswd_verify::Htpasswd;
+use http_auth_basic::Credentials;
+
+use super::{AuthenticationContext, Authenticator, Outcome};
+
+#[derive(Clone)]
+pub struct BasicAuthenticationManager {
+    path: PathBuf,
+    data: HashMap<String, String>,
+}
+
+impl BasicAuthenticationManager {
+    pub fn new(path: &PathBuf) -> Result<Self> {
+        Ok(BasicAuthenticationManager {
+            path: path.clone(),
+            data: load_htpasswd(path)?,
+        })
+    }
+
+    pub fn check(&self, username: &str, password: &str) -> bool {
+        let Some(value) = self.data.get(username) else {
+            return false;
+        };
+        let encoded = Htpasswd::from(value.as_str());
+        return encoded.check(username, password);
+    }
+
+    pub fn authenticate_user(&self, credentials: &[u8]) -> Result<String> {
+        let credentials = String::from_utf8(credentials.into())
+            .map_err(|e| Error::new(ErrorKind::Other, format!("invalid credentials: {}", e)))?;
+        let credentials = Credentials::decode(credentials)
+            .map_err(|e| Error::new(ErrorKind::Other, format!("invalid credentials: {}", e)))?;
+
+        let is_valid = self.check(credentials.user_id.as_str(), credentials.password.as_str());
+        match is_valid {
+            true => {
+                log::info!("Authenticated as \"{}\"", credentials.user_id.as_str());
+                Ok(credentials.user_id)
+            }
+            false => {
+                log::info!(
+                    "Failed to authenticate as \"{}\"",
+                    credentials.user_id.as_str()
+                );
+                Err(Error::new(
+                    ErrorKind::Other,
+                    format!("invalid user \"{}\"", credentials.user_id),
+                ))
+            }
+        }
+    }
+}
+
+impl Authenticator for BasicAuthenticationManager {
+    fn authenticate<'a>(
+        &'a self,
+        credentials: &'a [u8],
+        _context: &'a AuthenticationContext,
+    ) -> BoxFuture<'a, Result<Outcome>> {
+        Box::pin(async move {
+            let user = self.authenticate_user(credentials)?;
+            Ok(Outcome::Authenticated(user.into(), None))
+        })
+    }
+
+    fn claimed_user(&self, credentials: &[u8]) -> Option<String> {
+        basic_user(credentials)
+    }
+
+    fn reload(&self) -> Result<Option<Box<dyn Authenticator>>> {
+        Ok(Some(Box::new(BasicAuthenticationManager::new(&self.path)?)))
+    }
+}
+
+/// The user of basic credentials, whether or not the password is valid.
+pub fn basic_user(credentials: &[u8]) -> Option<String> {
+    let credentials = String::from_utf8(credentials.into()).ok()?;
+    Some(Credentials::decode(credentials).ok()?.user_id)
+}
+
+fn load_htpasswd(path: &PathBuf) -> Result<HashMap<String, String>> {
+    let contents = read_to_string(path)?;
+
+    let mut data = HashMap::new();
+
+    for line in contents.lines() {
+        let (username, _hash) = line
+            .split_once(':')
+            .ok_or_else(|| Error::new(ErrorKind::Other, "invalid_entry"))?;
+        data.insert(username.to_string(), line.to_owned());
+    }
+
+    Ok(data)
+}
@@ -0,0 +1,195 @@
+//! Authentication with a client certificate verified by the TLS handshake.
+
+use std::io::{Error, ErrorKind, Result};
+use std::str::FromStr;
+
+use futures_util::future::BoxFuture;
+use x509_parser::extensions::GeneralName;
+
+use super::{AuthenticationContext, Authenticator, Outcome};
+
+/// Where the user name is found in a client certificate.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum CertificateNameSource {
+    /// The common name of the subject.
+    CommonName,
+    /// The first DNS name in the subject alternative names.
+    Dns,
+    /// The first email address in the subject alternative names.
+    Email,
+    /// The first URI in the subject alternative names.
+    Uri,
+}
+
+impl FromStr for CertificateNameSource {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s {
+            "cn" => Ok(CertificateNameSource::CommonName),
+            "dns" => Ok(CertificateNameSource::Dns),
+            "email" => Ok(CertificateNameSource::Email),
+            "uri" => Ok(CertificateNameSource::Uri),
+            _ => Err(format!("invalid certificate name source \"{s}\"")),
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct CertificateAuthenticationManager {
+    name_source: CertificateNameSource,
+}
+
+impl CertificateAuthenticationManager {
+    pub fn new(name_source: CertificateNameSource) -> CertificateAuthenticationManager {
+        CertificateAuthenticationManager { name_source }
+    }
+
+    pub fn authenticate_user(&self, context: &AuthenticationContext) -> Result<String> {
+        let Some(certificate) = &context.peer_certificate else {
+            log::info!("Failed to authenticate without a client certificate");
+            return Err(Error::new(ErrorKind::Other, "no client certificate"));
+        };
+
+        let user = user_from_certificate(certificate, self.name_source)?;
+        log::info!("Authenticated as \"{}\"", user.as_str());
+        Ok(user)
+    }
+}
+
+impl Authenticator for CertificateAuthenticationManager {
+    fn authenticate<'a>(
+        &'a self,
+        _credentials: &'a [u8],
+        context: &'a AuthenticationContext,
+    ) -> BoxFuture<'a, Result<Outcome>> {
+        Box::pin(async move {
+            let user = self.authenticate_user(context)?;
+            Ok(Outcome::Authenticated(user.into(), None))
+        })
+    }
+}
+
+fn user_from_certificate(der: &[u8], name_source: CertificateNameSource) -> Result<String> {
+    let (_, certificate) = x509_parser::parse_x509_certificate(der)
+        .map_err(|e| Error::new(ErrorKind::Other, format!("invalid certificate: {}", e)))?;
+
+    let user = match name_source {
+        CertificateNameSource::CommonName => certificate
+            .subject()
+            .iter_common_name()
+            .next()
+            .and_then(|common_name| common_name.as_str().ok())
+            .map(|common_name| common_name.to_string()),
+        name_source => {
+            let subject_alternative_name = certificate
+                .subject_alternative_name()
+                .map_err(|e| Error::new(ErrorKind::Other, format!("invalid certificate: {}", e)))?;
+            subject_alternative_name.and_then(|extension| {
+                extension
+                    .value
+                    .general_names
+                    .iter()
+                    .find_map(|general_name| match (name_source, general_name) {
+                        (CertificateNameSource::Dns, GeneralName::DNSName(name))
+                        | (CertificateNameSource::Email, GeneralName::RFC822Name(name))
+                        | (CertificateNameSource::Uri, GeneralName::URI(name)) => {
+                            Some(name.to_string())
+                        }
+                        _ => None,
+                    })
+            })
+        }
+    };
+
+    user.ok_or_else(|| {
+        Error::new(
+            ErrorKind::Other,
+            format!("no {:?} name in client certificate", name_source),
+        )
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use pki_types::CertificateDer;
+
+    use super::*;
+
+    // A self signed certificate for "CN=harry" with the alternative names
+    // "DNS:feed1.example.com", "email:harry@example.com" and
+    // "URI:spiffe://example.com/harry".
+    const HARRY_PEM: &str = "-----BEGIN CERTIFICATE-----
+MIIB7jCCAZSgAwIBAgIUMIQoPHc2EMAcJgjp11U2Csp3y74wCgYIKoZIzj0EAwIw
+JDESMBAGA1UECgwJU3F1YXdrQnVzMQ4wDAYDVQQDDAVoYXJyeTAgFw0yNjEwMTgx
+NzMxMzhaGA8yMTI2MDkyNDE3MzEzOFowJDESMBAGA1UECgwJU3F1YXdrQnVzMQ4w
+DAYDVQQDDAVoYXJyeTBZMBMGByqGSM49AgEGCCqGSM49AwEHA0IABDKIZkST05TS
+yI7WeVq3nJd6fYGsZqLPUSk554YLPhc+c1oHQddiPXym+3U7x8YIYbO3ZtYU4GJ2
+av6Sfk08eKmjgaEwgZ4wHQYDVR0OBBYEFPy2q6r3TLS3IJy3GIN3GfyTt6hfMB8G
+A1UdIwQYMBaAFPy2q6r3TLS3IJy3GIN3GfyTt6hfMA8GA1UdEwEB/wQFMAMBAf8w
+SwYDVR0RBEQwQoIRZmVlZDEuZXhhbXBsZS5jb22BEWhhcnJ5QGV4YW1wbGUuY29t
+hhpzcGlmZmU6Ly9leGFtcGxlLmNvbS9oYXJyeTAKBggqhkjOPQQDAgNIADBFAiBD
+oLnnwl4E0BTPdHUc0OXP10iD19fpyZj95W5SRUULkAIhAMG5rC00QaFWQzQy6Tb1
+tA6e+tl3iwEsWIVtQz2PzeGd
+-----END CERTIFICATE-----
+";
+
+    // A self signed certificate with no common name or alternative names.
+    const ANONYMOUS_PEM: &str = "-----BEGIN CERTIFICATE-----
+MIIBfjCCASWgAwIBAgIUVm4jNyFeUSWrAQqrad9UXfpU9FEwCgYIKoZIzj0EAwIw
+FDESMBAGA1UECgwJU3F1YXdrQnVzMCAXDTI2MTAxODE3MzEzOFoYDzIxMjYwOTI0
+MTczMTM4WjAUMRIwEAYDVQQKDAlTcXVhd2tCdXMwWTATBgcqhkjOPQIBBggqhkjO
+PQMBBwNCAATkTdGgAG3vWmU5EXZhX3UjziGTzpWZrDy37fLVEOPqANho6PJzurV3
+oEVxEXH/iHOxOZUEnG5HNUXO09mwlYSEo1MwUTAdBgNVHQ4EFgQUgm6nAP7foRn9
+ez0XgzhuqNOOUzIwHwYDVR0jBBgwFoAUgm6nAP7foRn9ez0XgzhuqNOOUzIwDwYD
+VR0TAQH/BAUwAwEB/zAKBggqhkjOPQQDAgNHADBEAiA5KnQmYctuCftA6cQHq7Fb
+d1oBf1wsC+BDiLK8weDElwIgIs/PE8ssBML+aSwYY6EwvOBonYrXo9CF1Nq559dw
+V4Q=
+-----END CERTIFICATE-----
+";
+
+    fn load_certificate(pem: &str) -> CertificateDer<'static> {
+        rustls_pemfile::certs(&mut pem.as_bytes())
+            .next()
+            .expect("should have a certificate")
+            .expect("should parse certificate")
+    }
+
+    #[test]
+    fn should_get_user_from_certificate() {
+        let certificate = load_certificate(HARRY_PEM);
+
+        for (name_source, expected) in [
+            (CertificateNameSource::CommonName, "harry"),
+            (CertificateNameSource::Dns, "feed1.example.com"),
+            (CertificateNameSource::Email, "harry@example.com"),
+            (CertificateNameSource::Uri, "spiffe://example.com/harry"),
+        ] {
+            let actual =
+                user_from_certificate(&certificate, name_source).expect("should find user");
+            assert_eq!(actual, expected);
+        }
+    }
+
+    #[test]
+    fn should_fail_without_certificate_name() {
+        let certificate = load_certificate(ANONYMOUS_PEM);
+
+        assert!(user_from_certificate(&certificate, CertificateNameSource::CommonName).is_err());
+        assert!(user_from_certificate(&certificate, CertificateNameSource::Email).is_err());
+    }
+
+    #[test]
+    fn should_authenticate_with_certificate() {
+        let manager = CertificateAuthenticationManager::new(CertificateNameSource::CommonName);
+
+        let context = AuthenticationContext {
+            peer_certificate: Some(load_certificate(HARRY_PEM)),
+            ..Default::default()
+        };
+        assert_eq!(manager.authenticate_user(&context).unwrap(), "harry");
+
+        let context = AuthenticationContext::default();
+        assert!(manager.authenticate_user(&context).is_err());
+    }
+}
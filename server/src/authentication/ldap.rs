@@ -0,0 +1,775 @@
+//! Authentication with an LDAP server.
+//!
+//! The user's DN is either made from a template, or found by searching the
+//! directory as a service account. The password is checked by binding as the
+//! user, after which the groups the user belongs to may be looked up.
+
+use std::collections::HashMap;
+use std::fs::{self, read_to_string, File};
+use std::io::{BufReader, Error, ErrorKind, Result};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use futures_util::future::BoxFuture;
+use http_auth_basic::Credentials;
+use ldap3::{dn_escape, ldap_escape, Ldap, LdapConnAsync, LdapConnSettings, Scope, SearchEntry};
+
+use super::basic::basic_user;
+use super::{AuthenticationContext, Authenticator, Identity, Outcome};
+
+fn default_pool_size() -> usize {
+    4
+}
+
+fn default_timeout() -> u64 {
+    10
+}
+
+fn default_group_attribute() -> String {
+    "cn".into()
+}
+
+/// The configuration of LDAP authentication, loaded from a YAML file.
+#[derive(Debug, Clone, serde::Deserialize)]
+pub struct LdapConfig {
+    /// The server, as "ldap://host:port" or "ldaps://host:port".
+    pub url: String,
+    /// Upgrade an "ldap://" connection with StartTLS.
+    #[serde(default)]
+    pub starttls: bool,
+    /// A file of CA certificates used to verify the server. When not given
+    /// the system certificates are used.
+    pub ca_file: Option<PathBuf>,
+    /// Skip verification of the server certificate.
+    #[serde(default)]
+    pub no_tls_verify: bool,
+    /// A template for the user's DN, where "{user}" is replaced by the
+    /// escaped user. A template of just "{user}" takes the user to be the DN,
+    /// which is used as given.
+    pub bind_dn: Option<String>,
+    /// Find the user's DN with a search.
+    pub search: Option<LdapSearchConfig>,
+    /// Look up the groups the user belongs to.
+    pub groups: Option<LdapGroupsConfig>,
+    /// The number of idle connections kept for reuse.
+    #[serde(default = "default_pool_size")]
+    pub pool_size: usize,
+    /// The timeout in seconds for connecting and for each operation.
+    #[serde(default = "default_timeout")]
+    pub timeout: u64,
+}
+
+/// A search for the user's DN, made as a service account.
+#[derive(Debug, Clone, serde::Deserialize)]
+pub struct LdapSearchConfig {
+    pub base: String,
+    /// The filter, where "{user}" is replaced by the user.
+    pub filter: String,
+    /// The DN of the service account.
+    pub bind_dn: String,
+    /// A file holding the password of the service account.
+    pub password_file: PathBuf,
+}
+
+/// A search for the groups of a user.
+#[derive(Debug, Clone, serde::Deserialize)]
+pub struct LdapGroupsConfig {
+    pub base: String,
+    /// The filter, where "{dn}" is replaced by the user's DN and "{user}" by
+    /// the user.
+    pub filter: String,
+    /// The attribute of a group entry holding the group name.
+    #[serde(default = "default_group_attribute")]
+    pub attribute: String,
+}
+
+/// The template for a user who gives their DN in full.
+const USER_DN: &str = "{user}";
+
+impl LdapConfig {
+    /// The configuration used when only a URL is given, which binds with the
+    /// user as the DN.
+    fn from_url(url: &str) -> Self {
+        LdapConfig {
+            url: url.into(),
+            starttls: url.starts_with("ldap://"),
+            ca_file: None,
+            no_tls_verify: false,
+            bind_dn: Some(USER_DN.into()),
+            search: None,
+            groups: None,
+            pool_size: default_pool_size(),
+            timeout: default_timeout(),
+        }
+    }
+}
+
+/// An entry returned by a search.
+pub struct Entry {
+    pub dn: String,
+    pub attributes: HashMap<String, Vec<String>>,
+}
+
+/// A connection to a directory.
+pub trait Connection: Send {
+    fn bind<'a>(&'a mut self, dn: &'a str, password: &'a str) -> BoxFuture<'a, Result<()>>;
+
+    /// Search the subtree under the base.
+    fn search<'a>(
+        &'a mut self,
+        base: &'a str,
+        filter: &'a str,
+        attributes: &'a [&'a str],
+    ) -> BoxFuture<'a, Result<Vec<Entry>>>;
+
+    fn is_closed(&mut self) -> bool;
+}
+
+/// Opens connections to a directory.
+pub trait Connector: Send + Sync {
+    fn connect(&self) -> BoxFuture<'_, Result<Box<dyn Connection>>>;
+}
+
+struct LdapConnector {
+    url: String,
+    settings: LdapConnSettings,
+    timeout: Duration,
+}
+
+impl LdapConnector {
+    fn new(config: &LdapConfig) -> Result<Self> {
+        let timeout = Duration::from_secs(config.timeout);
+        let mut settings = LdapConnSettings::new()
+            .set_conn_timeout(timeout)
+            .set_starttls(config.starttls)
+            .set_no_tls_verify(config.no_tls_verify);
+        if let Some(ca_file) = &config.ca_file {
+            if config.no_tls_verify {
+                return Err(Error::new(
+                    ErrorKind::Other,
+                    "ca_file and no_tls_verify are exclusive",
+                ));
+            }
+            settings = settings.set_config(Arc::new(create_client_config(ca_file)?));
+        }
+        Ok(LdapConnector {
+            url: config.url.clone(),
+            settings,
+            timeout,
+        })
+    }
+}
+
+impl Connector for LdapConnector {
+    fn connect(&self) -> BoxFuture<'_, Result<Box<dyn Connection>>> {
+        Box::pin(async move {
+            let (conn, ldap) =
+                LdapConnAsync::with_settings(self.settings.clone(), &self.url).await?;
+            ldap3::drive!(conn);
+            Ok(Box::new(LdapConnection {
+                ldap,
+                timeout: self.timeout,
+            }) as Box<dyn Connection>)
+        })
+    }
+}
+
+struct LdapConnection {
+    ldap: Ldap,
+    timeout: Duration,
+}
+
+impl Connection for LdapConnection {
+    fn bind<'a>(&'a mut self, dn: &'a str, password: &'a str) -> BoxFuture<'a, Result<()>> {
+        Box::pin(async move {
+            self.ldap
+                .with_timeout(self.timeout)
+                .simple_bind(dn, password)
+                .await?
+                .success()?;
+            Ok(())
+        })
+    }
+
+    fn search<'a>(
+        &'a mut self,
+        base: &'a str,
+        filter: &'a str,
+        attributes: &'a [&'a str],
+    ) -> BoxFuture<'a, Result<Vec<Entry>>> {
+        Box::pin(async move {
+            let (entries, _) = self
+                .ldap
+                .with_timeout(self.timeout)
+                .search(base, Scope::Subtree, filter, attributes)
+                .await?
+                .success()?;
+            Ok(entries
+                .into_iter()
+                .map(SearchEntry::construct)
+                .map(|entry| Entry {
+                    dn: entry.dn,
+                    attributes: entry.attrs,
+                })
+                .collect())
+        })
+    }
+
+    fn is_closed(&mut self) -> bool {
+        self.ldap.is_closed()
+    }
+}
+
+/// Keeps idle connections for reuse.
+struct Pool {
+    connector: Box<dyn Connector>,
+    idle: Mutex<Vec<Box<dyn Connection>>>,
+    size: usize,
+}
+
+impl Pool {
+    fn new(connector: Box<dyn Connector>, size: usize) -> Self {
+        Pool {
+            connector,
+            idle: Mutex::new(Vec::new()),
+            size,
+        }
+    }
+
+    async fn acquire(&self) -> Result<Box<dyn Connection>> {
+        loop {
+            let connection = self.idle.lock().unwrap().pop();
+            match connection {
+                Some(mut connection) => {
+                    if !connection.is_closed() {
+                        return Ok(connection);
+                    }
+                }
+                None => return self.connector.connect().await,
+            }
+        }
+    }
+
+    fn release(&self, mut connection: Box<dyn Connection>) {
+        if connection.is_closed() {
+            return;
+        }
+        let mut idle = self.idle.lock().unwrap();
+        if idle.len() < self.size {
+            idle.push(connection);
+        }
+    }
+}
+
+pub struct LdapAuthenticationManager {
+    /// Either a URL or the path of a configuration file.
+    source: String,
+    config: LdapConfig,
+    service_password: Option<String>,
+    pool: Pool,
+}
+
+impl LdapAuthenticationManager {
+    pub fn new(source: &str) -> Result<Self> {
+        let config = match source.starts_with("ldap://") || source.starts_with("ldaps://") {
+            true => LdapConfig::from_url(source),
+            false => load_ldap_config(Path::new(source))?,
+        };
+        let connector = LdapConnector::new(&config)?;
+        let service_password = match &config.search {
+            Some(search) => Some(
+                String::from_utf8(fs::read(&search.password_file)?.trim_ascii_end().to_vec())
+                    .map_err(|e| {
+                        Error::new(ErrorKind::Other, format!("invalid password: {}", e))
+                    })?,
+            ),
+            None => None,
+        };
+        LdapAuthenticationManager::with_connector(
+            source,
+            config,
+            service_password,
+            Box::new(connector),
+        )
+    }
+
+    fn with_connector(
+        source: &str,
+        config: LdapConfig,
+        service_password: Option<String>,
+        connector: Box<dyn Connector>,
+    ) -> Result<Self> {
+        if config.bind_dn.is_some() == config.search.is_some() {
+            return Err(Error::new(
+                ErrorKind::Other,
+                "ldap authentication requires one of bind_dn or search",
+            ));
+        }
+
+        let pool = Pool::new(connector, config.pool_size);
+        Ok(LdapAuthenticationManager {
+            source: source.into(),
+            config,
+            service_password,
+            pool,
+        })
+    }
+
+    pub async fn authenticate_user(&self, credentials: &[u8]) -> Result<Identity> {
+        let credentials = String::from_utf8(credentials.into())
+            .map_err(|e| Error::new(ErrorKind::Other, format!("invalid credentials: {}", e)))?;
+        let credentials = Credentials::decode(credentials)
+            .map_err(|e| Error::new(ErrorKind::Other, format!("invalid credentials: {}", e)))?;
+
+        // A bind without a password is an anonymous bind, which would succeed.
+        if credentials.password.is_empty() {
+            log::info!(
+                "Failed to authenticate as \"{}\": empty password",
+                credentials.user_id
+            );
+            return Err(Error::new(
+                ErrorKind::Other,
+                format!("invalid user \"{}\"", credentials.user_id),
+            ));
+        }
+
+        let mut connection = self.pool.acquire().await?;
+        let result = self
+            .authenticate_with(
+                connection.as_mut(),
+                &credentials.user_id,
+                &credentials.password,
+            )
+            .await;
+        self.pool.release(connection);
+
+        match result {
+            Ok(groups) => {
+                log::info!("Authenticated as \"{}\"", credentials.user_id);
+                Ok(Identity {
+                    user: credentials.user_id,
+                    groups,
+                })
+            }
+            Err(e) => {
+                log::info!(
+                    "Failed to authenticate as \"{}\": {}",
+                    credentials.user_id,
+                    e
+                );
+                Err(Error::new(
+                    ErrorKind::Other,
+                    format!("invalid user \"{}\"", credentials.user_id),
+                ))
+            }
+        }
+    }
+
+    /// Bind as the user, returning their groups.
+    async fn authenticate_with(
+        &self,
+        connection: &mut dyn Connection,
+        user: &str,
+        password: &str,
+    ) -> Result<Vec<String>> {
+        let dn = match (&self.config.bind_dn, &self.config.search) {
+            (Some(bind_dn), _) if bind_dn == USER_DN => user.to_string(),
+            (Some(bind_dn), _) => expand(bind_dn, &[("{user}", &dn_escape(user))]),
+            (None, Some(search)) => {
+                self.bind_service(connection).await?;
+                let filter = expand(&search.filter, &[("{user}", &ldap_escape(user))]);
+                let entries = connection.search(&search.base, &filter, &["1.1"]).await?;
+                match entries.as_slice() {
+                    [entry] => entry.dn.clone(),
+                    [] => return Err(Error::new(ErrorKind::Other, "user not found")),
+                    _ => return Err(Error::new(ErrorKind::Other, "user is ambiguous")),
+                }
+            }
+            (None, None) => unreachable!(),
+        };
+
+        connection.bind(&dn, password).await?;
+
+        let Some(groups_config) = &self.config.groups else {
+            return Ok(Vec::new());
+        };
+
+        // Without a service account the groups are searched as the user.
+        if self.config.search.is_some() {
+            self.bind_service(connection).await?;
+        }
+        let filter = expand(
+            &groups_config.filter,
+            &[("{dn}", &ldap_escape(&dn)), ("{user}", &ldap_escape(user))],
+        );
+        let entries = connection
+            .search(
+                &groups_config.base,
+                &filter,
+                &[groups_config.attribute.as_str()],
+            )
+            .await?;
+
+        let mut groups: Vec<String> = Vec::new();
+        for entry in entries {
+            let values = entry
+                .attributes
+                .into_iter()
+                .filter(|(name, _)| name.eq_ignore_ascii_case(&groups_config.attribute))
+                .flat_map(|(_, values)| values);
+            for value in values {
+                if !groups.contains(&value) {
+                    groups.push(value);
+                }
+            }
+        }
+        Ok(groups)
+    }
+
+    async fn bind_service(&self, connection: &mut dyn Connection) -> Result<()> {
+        let (Some(search), Some(password)) = (&self.config.search, &self.service_password) else {
+            return Err(Error::new(ErrorKind::Other, "no service account"));
+        };
+        connection
+            .bind(&search.bind_dn, password)
+            .await
+            .map_err(|e| Error::new(ErrorKind::Other, format!("service bind failed: {}", e)))
+    }
+}
+
+impl Authenticator for LdapAuthenticationManager {
+    fn authenticate<'a>(
+        &'a self,
+        credentials: &'a [u8],
+        _context: &'a AuthenticationContext,
+    ) -> BoxFuture<'a, Result<Outcome>> {
+        Box::pin(async move {
+            let identity = self.authenticate_user(credentials).await?;
+            Ok(Outcome::Authenticated(identity, None))
+        })
+    }
+
+    fn claimed_user(&self, credentials: &[u8]) -> Option<String> {
+        basic_user(credentials)
+    }
+
+    fn reload(&self) -> Result<Option<Box<dyn Authenticator>>> {
+        Ok(Some(Box::new(LdapAuthenticationManager::new(
+            &self.source,
+        )?)))
+    }
+}
+
+/// Replace the placeholders in a template in a single pass, so values
+/// containing placeholders are not expanded.
+fn expand(template: &str, values: &[(&str, &str)]) -> String {
+    let mut result = String::new();
+    let mut rest = template;
+    while let Some(start) = rest.find('{') {
+        result.push_str(&rest[..start]);
+        rest = &rest[start..];
+        match values.iter().find(|(name, _)| rest.starts_with(name)) {
+            Some((name, value)) => {
+                result.push_str(value);
+                rest = &rest[name.len()..];
+            }
+            None => {
+                result.push('{');
+                rest = &rest[1..];
+            }
+        }
+    }
+    result.push_str(rest);
+    result
+}
+
+fn create_client_config(ca_file: &Path) -> Result<ldap3_rustls::ClientConfig> {
+    let mut roots = ldap3_rustls::RootCertStore::empty();
+    for cert in rustls_pemfile::certs(&mut BufReader::new(File::open(ca_file)?)) {
+        roots
+            .add(&ldap3_rustls::Certificate(cert?.to_vec()))
+            .map_err(|e| Error::new(ErrorKind::InvalidInput, e))?;
+    }
+    Ok(ldap3_rustls::ClientConfig::builder()
+        .with_safe_defaults()
+        .with_root_certificates(roots)
+        .with_no_client_auth())
+}
+
+fn load_ldap_config(path: &Path) -> Result<LdapConfig> {
+    let contents = read_to_string(path)?;
+    serde_yaml::from_str(&contents)
+        .map_err(|e| Error::new(ErrorKind::Other, format!("invalid ldap config: {}", e)))
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use super::*;
+
+    struct MockEntry {
+        dn: &'static str,
+        password: Option<&'static str>,
+        attributes: &'static [(&'static str, &'static str)],
+    }
+
+    /// An in-process directory, which understands filters of the form
+    /// "(attribute=value)".
+    struct MockConnector {
+        entries: Arc<Vec<MockEntry>>,
+        connections: Arc<AtomicUsize>,
+    }
+
+    struct MockConnection {
+        entries: Arc<Vec<MockEntry>>,
+        bound: bool,
+    }
+
+    impl Connector for MockConnector {
+        fn connect(&self) -> BoxFuture<'_, Result<Box<dyn Connection>>> {
+            self.connections.fetch_add(1, Ordering::SeqCst);
+            let entries = self.entries.clone();
+            Box::pin(async move {
+                Ok(Box::new(MockConnection {
+                    entries,
+                    bound: false,
+                }) as Box<dyn Connection>)
+            })
+        }
+    }
+
+    impl Connection for MockConnection {
+        fn bind<'a>(&'a mut self, dn: &'a str, password: &'a str) -> BoxFuture<'a, Result<()>> {
+            Box::pin(async move {
+                self.bound = self
+                    .entries
+                    .iter()
+                    .any(|entry| entry.dn == dn && entry.password == Some(password));
+                match self.bound {
+                    true => Ok(()),
+                    false => Err(Error::new(ErrorKind::Other, "invalid credentials")),
+                }
+            })
+        }
+
+        fn search<'a>(
+            &'a mut self,
+            base: &'a str,
+            filter: &'a str,
+            attributes: &'a [&'a str],
+        ) -> BoxFuture<'a, Result<Vec<Entry>>> {
+            Box::pin(async move {
+                assert!(self.bound, "search while unbound");
+                let (name, value) = filter
+                    .trim_start_matches('(')
+                    .trim_end_matches(')')
+                    .split_once('=')
+                    .unwrap();
+                Ok(self
+                    .entries
+                    .iter()
+                    .filter(|entry| entry.dn.ends_with(base))
+                    .filter(|entry| entry.attributes.contains(&(name, value)))
+                    .map(|entry| Entry {
+                        dn: entry.dn.into(),
+                        attributes: entry
+                            .attributes
+                            .iter()
+                            .filter(|(name, _)| attributes.contains(name))
+                            .fold(HashMap::new(), |mut map, (name, value)| {
+                                map.entry(name.to_string())
+                                    .or_insert_with(Vec::new)
+                                    .push(value.to_string());
+                                map
+                            }),
+                    })
+                    .collect())
+            })
+        }
+
+        fn is_closed(&mut self) -> bool {
+            false
+        }
+    }
+
+    fn directory() -> Vec<MockEntry> {
+        vec![
+            MockEntry {
+                dn: "cn=squawkbus,dc=example,dc=com",
+                password: Some("service"),
+                attributes: &[],
+            },
+            MockEntry {
+                dn: "uid=mary,ou=people,dc=example,dc=com",
+                password: Some("secret"),
+                attributes: &[("uid", "mary")],
+            },
+            MockEntry {
+                dn: "cn=traders,ou=groups,dc=example,dc=com",
+                password: None,
+                attributes: &[
+                    ("cn", "traders"),
+                    ("member", "uid=mary,ou=people,dc=example,dc=com"),
+                ],
+            },
+            MockEntry {
+                dn: "cn=admins,ou=groups,dc=example,dc=com",
+                password: None,
+                attributes: &[("cn", "admins")],
+            },
+        ]
+    }
+
+    fn manager(config: &str) -> (LdapAuthenticationManager, Arc<AtomicUsize>) {
+        manager_with(serde_yaml::from_str(config).unwrap())
+    }
+
+    fn manager_with(config: LdapConfig) -> (LdapAuthenticationManager, Arc<AtomicUsize>) {
+        let connections = Arc::new(AtomicUsize::new(0));
+        let connector = MockConnector {
+            entries: Arc::new(directory()),
+            connections: connections.clone(),
+        };
+        let service_password = config.search.as_ref().map(|_| "service".to_string());
+        let manager = LdapAuthenticationManager::with_connector(
+            "ldap.yaml",
+            config,
+            service_password,
+            Box::new(connector),
+        )
+        .unwrap();
+        (manager, connections)
+    }
+
+    fn credentials(user: &str, password: &str) -> Vec<u8> {
+        Credentials::new(user, password).encode().into_bytes()
+    }
+
+    const SEARCH_CONFIG: &str = "
+url: ldap://localhost
+search:
+  base: ou=people,dc=example,dc=com
+  filter: (uid={user})
+  bind_dn: cn=squawkbus,dc=example,dc=com
+  password_file: ldap.secret
+groups:
+  base: ou=groups,dc=example,dc=com
+  filter: (member={dn})
+";
+
+    #[tokio::test]
+    async fn should_bind_with_dn_template() {
+        let (manager, _) = manager(
+            "
+url: ldap://localhost
+bind_dn: uid={user},ou=people,dc=example,dc=com
+",
+        );
+
+        let identity = manager
+            .authenticate_user(&credentials("mary", "secret"))
+            .await
+            .unwrap();
+        assert_eq!(identity.user, "mary");
+        assert!(identity.groups.is_empty());
+
+        assert!(manager
+            .authenticate_user(&credentials("mary", "wrong"))
+            .await
+            .is_err());
+    }
+
+    #[tokio::test]
+    async fn should_bind_with_user_as_dn_given_url() {
+        let (manager, _) = manager_with(LdapConfig::from_url("ldap://localhost"));
+
+        let identity = manager
+            .authenticate_user(&credentials(
+                "uid=mary,ou=people,dc=example,dc=com",
+                "secret",
+            ))
+            .await
+            .unwrap();
+        assert_eq!(identity.user, "uid=mary,ou=people,dc=example,dc=com");
+
+        assert!(manager
+            .authenticate_user(&credentials("mary", "secret"))
+            .await
+            .is_err());
+    }
+
+    #[tokio::test]
+    async fn should_search_then_bind_with_groups() {
+        let (manager, _) = manager(SEARCH_CONFIG);
+
+        let identity = manager
+            .authenticate_user(&credentials("mary", "secret"))
+            .await
+            .unwrap();
+        assert_eq!(identity.user, "mary");
+        assert_eq!(identity.groups, vec!["traders".to_string()]);
+    }
+
+    #[tokio::test]
+    async fn should_reject_invalid_users() {
+        let (manager, _) = manager(SEARCH_CONFIG);
+
+        for (user, password) in [
+            ("mary", "wrong"),
+            ("mary", ""),
+            ("tom", "secret"),
+            ("*", "secret"),
+        ] {
+            assert!(
+                manager
+                    .authenticate_user(&credentials(user, password))
+                    .await
+                    .is_err(),
+                "{user}:{password}"
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn should_reuse_connections() {
+        let (manager, connections) = manager(SEARCH_CONFIG);
+
+        for _ in 0..3 {
+            manager
+                .authenticate_user(&credentials("mary", "secret"))
+                .await
+                .unwrap();
+        }
+        assert_eq!(connections.load(Ordering::SeqCst), 1);
+    }
+
+    #[test]
+    fn should_require_one_way_to_find_the_dn() {
+        let config: LdapConfig = serde_yaml::from_str("url: ldap://localhost").unwrap();
+        let connector = MockConnector {
+            entries: Arc::new(directory()),
+            connections: Arc::new(AtomicUsize::new(0)),
+        };
+        assert!(LdapAuthenticationManager::with_connector(
+            "ldap.yaml",
+            config,
+            None,
+            Box::new(connector)
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn should_expand_placeholders_once() {
+        assert_eq!(
+            expand(
+                "(&(member={dn})(uid={user}))",
+                &[("{dn}", "{user}"), ("{user}", "mary")]
+            ),
+            "(&(member={user})(uid=mary))"
+        );
+        assert_eq!(expand("{other}", &[("{user}", "mary")]), "{other}");
+    }
+}
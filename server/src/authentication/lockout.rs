@@ -0,0 +1,363 @@
+//! Protection against guessing credentials.
+//!
+//! Failed attempts are counted for the user the client claims to be, and for
+//! the address it connects from. Once either has failed too many times in a
+//! row it is locked out, for a time which doubles with each further failure.
+//! A successful attempt clears the count of the user, but not of the address,
+//! so a client cannot clear its address by signing in to an account it holds
+//! between guesses at others.
+//!
+//! An attempt is begun before the credentials are checked, and no more may be
+//! in progress for a subject than the failures it has left before it is
+//! locked out, so a client cannot make many guesses at once while the first
+//! are checked. Only attempts whose credentials were rejected are failures.
+//!
+//! The failures are kept in the order they happened, so those which are too
+//! old to matter are forgotten without a scan, and the least recent are
+//! forgotten when too many subjects are held.
+
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+use std::net::IpAddr;
+use std::time::{Duration, Instant};
+
+pub const DEFAULT_MAX_FAILURES: u32 = 5;
+pub const DEFAULT_LOCKOUT_SECONDS: u64 = 30;
+const DEFAULT_MAX_LOCKOUT_SECONDS: u64 = 3600;
+/// The most users and hosts failures are held for.
+const MAX_SUBJECTS: usize = 100_000;
+
+/// When users and hosts are locked out.
+#[derive(Debug, Clone, PartialEq, serde::Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct LockoutOption {
+    /// The failures in a row allowed before a lockout, where 0 disables it.
+    pub max_failures: u32,
+    /// The seconds of the first lockout.
+    pub seconds: u64,
+    /// The seconds a lockout is capped at. Failures are also forgotten after
+    /// this long.
+    pub max_seconds: u64,
+}
+
+impl Default for LockoutOption {
+    fn default() -> Self {
+        LockoutOption {
+            max_failures: DEFAULT_MAX_FAILURES,
+            seconds: DEFAULT_LOCKOUT_SECONDS,
+            max_seconds: DEFAULT_MAX_LOCKOUT_SECONDS,
+        }
+    }
+}
+
+/// What failed attempts are counted against.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum Subject {
+    User(String),
+    Host(IpAddr),
+}
+
+impl fmt::Display for Subject {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Subject::User(user) => write!(f, "user \"{user}\""),
+            Subject::Host(host) => write!(f, "host {host}"),
+        }
+    }
+}
+
+struct Failures {
+    count: u32,
+    /// When the last failure happened, and its sequence number.
+    last: (Instant, u64),
+    locked_until: Option<Instant>,
+}
+
+/// Why an attempt may not begin.
+#[derive(Debug, PartialEq)]
+pub enum Refusal {
+    /// The subject is locked out for the duration.
+    LockedOut(Subject, Duration),
+    /// The subject has as many attempts in progress as failures left.
+    InProgress(Subject),
+}
+
+impl fmt::Display for Refusal {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Refusal::LockedOut(subject, remaining) => write!(
+                f,
+                "{subject} is locked out for {} seconds",
+                remaining.as_secs_f64().ceil()
+            ),
+            Refusal::InProgress(subject) => {
+                write!(f, "{subject} has too many attempts in progress")
+            }
+        }
+    }
+}
+
+pub struct Lockout {
+    option: LockoutOption,
+    max_subjects: usize,
+    failures: HashMap<Subject, Failures>,
+    /// The subjects by their last failure, the least recent first.
+    by_last: BTreeMap<(Instant, u64), Subject>,
+    sequence: u64,
+    /// The attempts in progress for each subject.
+    in_progress: HashMap<Subject, u32>,
+}
+
+impl Lockout {
+    pub fn new(option: &LockoutOption) -> Self {
+        Lockout {
+            option: option.clone(),
+            max_subjects: MAX_SUBJECTS,
+            failures: HashMap::new(),
+            by_last: BTreeMap::new(),
+            sequence: 0,
+            in_progress: HashMap::new(),
+        }
+    }
+
+    /// Forget the failures which are too old to matter, and the least recent
+    /// while there is no room for another subject.
+    fn forget(&mut self, now: Instant, make_room: bool) {
+        let max_duration = Duration::from_secs(self.option.max_seconds);
+        while let Some(entry) = self.by_last.first_entry() {
+            let (last, _) = *entry.key();
+            if now.saturating_duration_since(last) < max_duration
+                && (!make_room || self.failures.len() < self.max_subjects)
+            {
+                break;
+            }
+            self.failures.remove(&entry.remove());
+        }
+    }
+
+    /// The first of the subjects which is locked out, and for how much longer.
+    pub fn check(&self, subjects: &[Subject], now: Instant) -> Option<(Subject, Duration)> {
+        subjects.iter().find_map(|subject| {
+            let locked_until = self.failures.get(subject)?.locked_until?;
+            (locked_until > now).then(|| (subject.clone(), locked_until - now))
+        })
+    }
+
+    /// Begin an attempt for each of the subjects, unless one is locked out or
+    /// has as many attempts in progress as failures left. Each attempt begun
+    /// must be ended, whether or not it failed.
+    pub fn begin(&mut self, subjects: &[Subject], now: Instant) -> Result<(), Refusal> {
+        if let Some((subject, remaining)) = self.check(subjects, now) {
+            return Err(Refusal::LockedOut(subject, remaining));
+        }
+
+        if self.option.max_failures != 0 {
+            self.forget(now, false);
+            for subject in subjects {
+                let count = self.failures.get(subject).map_or(0, |f| f.count);
+                // Once locked out, each failure locks the subject out again.
+                let failures_left = self.option.max_failures.saturating_sub(count).max(1);
+                if self.in_progress.get(subject).copied().unwrap_or(0) >= failures_left {
+                    return Err(Refusal::InProgress(subject.clone()));
+                }
+            }
+        }
+
+        for subject in subjects {
+            *self.in_progress.entry(subject.clone()).or_default() += 1;
+        }
+        Ok(())
+    }
+
+    /// End an attempt begun for each of the subjects.
+    pub fn end(&mut self, subjects: &[Subject]) {
+        for subject in subjects {
+            if let Some(count) = self.in_progress.get_mut(subject) {
+                *count -= 1;
+                if *count == 0 {
+                    self.in_progress.remove(subject);
+                }
+            }
+        }
+    }
+
+    /// Count a failed attempt against each of the subjects.
+    pub fn fail(&mut self, subjects: &[Subject], now: Instant) {
+        if self.option.max_failures == 0 {
+            return;
+        }
+
+        let max_duration = Duration::from_secs(self.option.max_seconds);
+        for subject in subjects {
+            self.forget(now, !self.failures.contains_key(subject));
+
+            self.sequence += 1;
+            let last = (now, self.sequence);
+            let failures = self.failures.entry(subject.clone()).or_insert(Failures {
+                count: 0,
+                last,
+                locked_until: None,
+            });
+            self.by_last.remove(&failures.last);
+            self.by_last.insert(last, subject.clone());
+            failures.count += 1;
+            failures.last = last;
+            if failures.count < self.option.max_failures {
+                continue;
+            }
+
+            let doublings = (failures.count - self.option.max_failures).min(31);
+            let duration = Duration::from_secs(self.option.seconds)
+                .saturating_mul(1 << doublings)
+                .min(max_duration);
+            failures.locked_until = Some(now + duration);
+            log::warn!(
+                "Locked out {subject} for {} after {} failed attempts",
+                humantime::format_duration(duration),
+                failures.count
+            );
+        }
+    }
+
+    /// Clear the failures of the users among the subjects.
+    pub fn succeed(&mut self, subjects: &[Subject]) {
+        for subject in subjects {
+            if let Subject::User(_) = subject {
+                if let Some(failures) = self.failures.remove(subject) {
+                    self.by_last.remove(&failures.last);
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn should_double_lockout() {
+        let mut lockout = Lockout::new(&LockoutOption {
+            max_failures: 2,
+            seconds: 10,
+            max_seconds: 25,
+        });
+        let tom = [Subject::User("tom".into())];
+        let start = Instant::now();
+
+        lockout.fail(&tom, start);
+        assert_eq!(lockout.check(&tom, start), None);
+        lockout.fail(&tom, start);
+        assert_eq!(
+            lockout.check(&tom, start),
+            Some((tom[0].clone(), Duration::from_secs(10)))
+        );
+
+        let now = start + Duration::from_secs(10);
+        assert_eq!(lockout.check(&tom, now), None);
+        lockout.fail(&tom, now);
+        assert_eq!(
+            lockout.check(&tom, now),
+            Some((tom[0].clone(), Duration::from_secs(20)))
+        );
+
+        // The lockout is capped.
+        lockout.fail(&tom, now);
+        assert_eq!(
+            lockout.check(&tom, now),
+            Some((tom[0].clone(), Duration::from_secs(25)))
+        );
+
+        lockout.succeed(&tom);
+        assert_eq!(lockout.check(&tom, now), None);
+    }
+
+    #[test]
+    fn should_lock_out_hosts_and_users_separately() {
+        let mut lockout = Lockout::new(&LockoutOption {
+            max_failures: 2,
+            ..Default::default()
+        });
+        let host = Subject::Host("10.0.0.1".parse().unwrap());
+        let tom = Subject::User("tom".into());
+        let dick = Subject::User("dick".into());
+        let now = Instant::now();
+
+        lockout.fail(&[tom.clone(), host.clone()], now);
+        lockout.fail(&[dick.clone(), host.clone()], now);
+
+        assert_eq!(lockout.check(std::slice::from_ref(&tom), now), None);
+        assert_eq!(
+            lockout
+                .check(&[tom.clone(), host.clone()], now)
+                .map(|(s, _)| s),
+            Some(host.clone())
+        );
+
+        // Succeeding clears the user, but not the host.
+        lockout.fail(&[tom.clone(), host.clone()], now);
+        lockout.succeed(&[tom.clone(), host.clone()]);
+        assert_eq!(lockout.check(std::slice::from_ref(&tom), now), None);
+        assert_eq!(
+            lockout.check(&[tom, host.clone()], now).map(|(s, _)| s),
+            Some(host)
+        );
+    }
+
+    #[test]
+    fn should_limit_attempts_in_progress() {
+        let mut lockout = Lockout::new(&LockoutOption {
+            max_failures: 2,
+            ..Default::default()
+        });
+        let tom = [Subject::User("tom".into())];
+        let now = Instant::now();
+
+        assert_eq!(lockout.begin(&tom, now), Ok(()));
+        assert_eq!(lockout.begin(&tom, now), Ok(()));
+        assert_eq!(
+            lockout.begin(&tom, now),
+            Err(Refusal::InProgress(tom[0].clone()))
+        );
+
+        // One failure leaves one attempt.
+        lockout.end(&tom);
+        lockout.fail(&tom, now);
+        assert_eq!(
+            lockout.begin(&tom, now),
+            Err(Refusal::InProgress(tom[0].clone()))
+        );
+        lockout.end(&tom);
+        assert_eq!(lockout.begin(&tom, now), Ok(()));
+        lockout.end(&tom);
+        assert!(lockout.in_progress.is_empty());
+    }
+
+    #[test]
+    fn should_forget_old_and_least_recent_failures() {
+        let mut lockout = Lockout::new(&LockoutOption {
+            max_failures: 1,
+            seconds: 10,
+            max_seconds: 60,
+        });
+        lockout.max_subjects = 2;
+        let user = |name: &str| Subject::User(name.into());
+        let start = Instant::now();
+
+        lockout.fail(&[user("tom")], start);
+        lockout.fail(&[user("dick")], start);
+        lockout.fail(&[user("tom")], start);
+        assert_eq!(lockout.failures[&user("tom")].count, 2);
+        lockout.fail(&[user("harry")], start);
+        assert_eq!(lockout.failures.len(), 2);
+        assert_eq!(lockout.by_last.len(), 2);
+        // Dick failed least recently, so is forgotten.
+        assert!(lockout.check(&[user("dick")], start).is_none());
+        assert_eq!(lockout.failures[&user("tom")].count, 2);
+
+        let later = start + Duration::from_secs(60);
+        lockout.fail(&[user("dick")], later);
+        assert_eq!(lockout.failures.len(), 1);
+        assert_eq!(lockout.by_last.len(), 1);
+    }
+}
@@ -0,0 +1,691 @@
+//! Authentication of clients during the handshake.
+//!
+//! Each backend implements `Authenticator`, and is registered with the
+//! `AuthenticationManager` under the method name the client sends in the
+//! `AuthenticationRequest`. Several backends may be registered for the same
+//! method, in which case they are tried in order until one succeeds.
+//!
+//! Users and hosts which fail to authenticate too often are locked out for a
+//! while, as described in `lockout`. Only credentials rejected by a backend
+//! count as failures, not errors in the exchange with the client or an
+//! attempt abandoned as the handshake times out.
+//!
+//! Reloading the configuration replaces the authenticators as a whole, so a
+//! client authenticates with the ones in place when it connected, without
+//! holding up a reload.
+
+use std::collections::HashMap;
+use std::io::{Error, ErrorKind, Result};
+use std::net::IpAddr;
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::Instant;
+
+use futures_util::future::BoxFuture;
+use pki_types::CertificateDer;
+use tokio::net::unix::UCred;
+
+use common::messages::Message;
+use common::MessageStream;
+
+use crate::audit::{self, AuditEvent};
+use crate::options::{AuthenticationMethodOption, AuthenticationOption};
+
+mod basic;
+pub use basic::BasicAuthenticationManager;
+
+mod bearer;
+pub use bearer::BearerAuthenticationManager;
+
+mod certificate;
+pub use certificate::{CertificateAuthenticationManager, CertificateNameSource};
+
+mod ldap;
+pub use ldap::LdapAuthenticationManager;
+
+mod lockout;
+use lockout::{Lockout, Subject};
+pub use lockout::{LockoutOption, DEFAULT_LOCKOUT_SECONDS, DEFAULT_MAX_FAILURES};
+
+mod peer;
+pub use peer::PeerAuthenticationManager;
+
+mod scram;
+pub use scram::ScramAuthenticationManager;
+
+/// The result of a successful authentication.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Identity {
+    pub user: String,
+    /// Groups or roles asserted by the authentication backend.
+    pub groups: Vec<String>,
+}
+
+impl From<String> for Identity {
+    fn from(user: String) -> Self {
+        Identity {
+            user,
+            groups: Vec::new(),
+        }
+    }
+}
+
+/// What is known about a connection before the client authenticates.
+#[derive(Clone, Default)]
+pub struct AuthenticationContext {
+    /// The id the client will be given.
+    pub client_id: String,
+    /// The address of the client, or the path of a Unix domain socket.
+    pub host: String,
+    /// The certificate presented by the client, which has been verified by
+    /// the TLS handshake.
+    pub peer_certificate: Option<CertificateDer<'static>>,
+    /// The credentials of the process connected to a Unix domain socket.
+    pub peer_credentials: Option<UCred>,
+    /// The address of the listener the client connected to.
+    pub listener: String,
+    /// The methods allowed by the listener, or all when empty.
+    pub allowed_methods: Vec<String>,
+}
+
+/// The outcome of a step of authentication.
+pub enum Outcome {
+    /// The client is authenticated. Any data is sent to the client in an
+    /// `AuthenticationChallenge` before the `AuthenticationResponse`.
+    Authenticated(Identity, Option<Vec<u8>>),
+    /// The client must answer the challenge with an
+    /// `AuthenticationChallengeResponse`.
+    Challenge(Vec<u8>, Box<dyn Continuation>),
+}
+
+/// The remainder of an authentication exchange which needs more than one
+/// round.
+pub trait Continuation: Send {
+    fn respond(self: Box<Self>, response: Vec<u8>) -> BoxFuture<'static, Result<Outcome>>;
+}
+
+/// An authentication backend.
+pub trait Authenticator: Send + Sync {
+    /// Authenticate with the credentials from the `AuthenticationRequest`.
+    fn authenticate<'a>(
+        &'a self,
+        credentials: &'a [u8],
+        context: &'a AuthenticationContext,
+    ) -> BoxFuture<'a, Result<Outcome>>;
+
+    /// The user the credentials claim to be, before they are checked, so
+    /// failed attempts can be counted against them.
+    fn claimed_user(&self, _credentials: &[u8]) -> Option<String> {
+        None
+    }
+
+    /// Reload any configuration, giving the authenticator to replace this
+    /// one, or none when there is nothing to reload.
+    fn reload(&self) -> Result<Option<Box<dyn Authenticator>>> {
+        Ok(None)
+    }
+}
+
+/// Accepts everyone as "nobody".
+pub struct NoneAuthenticator;
+
+impl Authenticator for NoneAuthenticator {
+    fn authenticate<'a>(
+        &'a self,
+        _credentials: &'a [u8],
+        _context: &'a AuthenticationContext,
+    ) -> BoxFuture<'a, Result<Outcome>> {
+        Box::pin(async { Ok(Outcome::Authenticated("nobody".to_string().into(), None)) })
+    }
+}
+
+pub fn create_authenticator(option: &AuthenticationOption) -> Result<Box<dyn Authenticator>> {
+    Ok(match option {
+        AuthenticationOption::None => Box::new(NoneAuthenticator),
+        AuthenticationOption::Basic(path) => Box::new(BasicAuthenticationManager::new(path)?),
+        AuthenticationOption::Ldap(source) => Box::new(LdapAuthenticationManager::new(source)?),
+        AuthenticationOption::Certificate(name_source) => {
+            Box::new(CertificateAuthenticationManager::new(*name_source))
+        }
+        AuthenticationOption::Bearer(path) => Box::new(BearerAuthenticationManager::new(path)?),
+        AuthenticationOption::Scram(path) => Box::new(ScramAuthenticationManager::new(path)?),
+        AuthenticationOption::Peer => Box::new(PeerAuthenticationManager),
+    })
+}
+
+/// The authenticators for each method.
+type Authenticators = HashMap<String, Vec<Arc<dyn Authenticator>>>;
+
+pub struct AuthenticationManager {
+    authenticators: RwLock<Arc<Authenticators>>,
+    lockout: Mutex<Lockout>,
+}
+
+impl AuthenticationManager {
+    pub fn new(options: &[AuthenticationMethodOption], lockout: &LockoutOption) -> Result<Self> {
+        let mut manager = AuthenticationManager {
+            authenticators: RwLock::new(Arc::new(HashMap::new())),
+            lockout: Mutex::new(Lockout::new(lockout)),
+        };
+        for option in options {
+            manager.register(&option.method, create_authenticator(&option.option)?);
+        }
+        Ok(manager)
+    }
+
+    /// Register an authenticator for a method. Authenticators registered for
+    /// the same method are tried in the order they were registered.
+    pub fn register(&mut self, method: &str, authenticator: Box<dyn Authenticator>) {
+        Arc::make_mut(self.authenticators.get_mut().unwrap())
+            .entry(method.to_string())
+            .or_default()
+            .push(Arc::from(authenticator));
+    }
+
+    /// The current authenticators.
+    fn authenticators(&self) -> Arc<Authenticators> {
+        self.authenticators.read().unwrap().clone()
+    }
+
+    pub async fn authenticate(
+        &self,
+        stream: &mut impl MessageStream,
+        context: &AuthenticationContext,
+    ) -> Result<Identity> {
+        let message = stream.read().await?;
+        let Message::AuthenticationRequest {
+            method,
+            credentials,
+        } = message
+        else {
+            return Err(Error::new(
+                ErrorKind::Other,
+                "expected authentication request",
+            ));
+        };
+
+        let authenticators = self.authenticators();
+        let subjects = subjects(&authenticators, &method, &credentials, context);
+        let begun = self
+            .lockout
+            .lock()
+            .unwrap()
+            .begin(&subjects, Instant::now());
+        let result = match begun {
+            Err(refusal) => Err(Error::other(refusal.to_string())),
+            Ok(()) => {
+                let attempt = Attempt {
+                    lockout: &self.lockout,
+                    subjects,
+                };
+                match authenticate_with(&authenticators, stream, &method, &credentials, context)
+                    .await
+                {
+                    Ok(identity) => {
+                        attempt.end(true);
+                        Ok(identity)
+                    }
+                    Err(Failure::Rejected(error)) => {
+                        attempt.end(false);
+                        Err(error)
+                    }
+                    Err(Failure::Failed(error)) => Err(error),
+                }
+            }
+        };
+
+        audit::emit(match &result {
+            Ok(identity) => AuditEvent::AuthenticationSucceeded {
+                client_id: context.client_id.clone(),
+                host: context.host.clone(),
+                method,
+                user: identity.user.clone(),
+                groups: identity.groups.clone(),
+            },
+            Err(error) => AuditEvent::AuthenticationFailed {
+                client_id: context.client_id.clone(),
+                host: context.host.clone(),
+                method,
+                reason: error.to_string(),
+            },
+        });
+
+        result
+    }
+
+    /// Reload the configuration of every backend. A backend which fails to
+    /// reload keeps its previous configuration, and the first error is
+    /// returned once the others have been reloaded. Clients already
+    /// authenticating carry on with the previous backends.
+    pub fn reset(&self) -> Result<()> {
+        let mut first_error = None;
+        let mut reloaded = Authenticators::new();
+        for (method, authenticators) in self.authenticators().iter() {
+            let reloaded = reloaded.entry(method.clone()).or_default();
+            for authenticator in authenticators {
+                reloaded.push(match authenticator.reload() {
+                    Ok(Some(authenticator)) => Arc::from(authenticator),
+                    Ok(None) => authenticator.clone(),
+                    Err(error) => {
+                        log::error!("Failed to reload \"{method}\" authentication: {error}");
+                        first_error.get_or_insert(error);
+                        authenticator.clone()
+                    }
+                });
+            }
+        }
+        *self.authenticators.write().unwrap() = Arc::new(reloaded);
+        first_error.map_or(Ok(()), Err)
+    }
+}
+
+/// An attempt begun in the lockout, which is ended when it is dropped, so an
+/// attempt abandoned by the handshake timing out is not left in progress.
+struct Attempt<'a> {
+    lockout: &'a Mutex<Lockout>,
+    subjects: Vec<Subject>,
+}
+
+impl Attempt<'_> {
+    /// End the attempt as a success or a failure.
+    fn end(mut self, is_success: bool) {
+        let subjects = std::mem::take(&mut self.subjects);
+        let mut lockout = self.lockout.lock().unwrap();
+        lockout.end(&subjects);
+        match is_success {
+            true => lockout.succeed(&subjects),
+            false => lockout.fail(&subjects, Instant::now()),
+        }
+    }
+}
+
+impl Drop for Attempt<'_> {
+    fn drop(&mut self) {
+        if !self.subjects.is_empty() {
+            self.lockout.lock().unwrap().end(&self.subjects);
+        }
+    }
+}
+
+/// Why an attempt to authenticate failed.
+enum Failure {
+    /// The credentials were rejected by the backends.
+    Rejected(Error),
+    /// The exchange with the client failed, or the method was not allowed,
+    /// before the credentials could be checked.
+    Failed(Error),
+}
+
+/// What a failed attempt counts against: the user, when the credentials
+/// name one, and the address of a client connected over IP.
+fn subjects(
+    authenticators: &Authenticators,
+    method: &str,
+    credentials: &[u8],
+    context: &AuthenticationContext,
+) -> Vec<Subject> {
+    let user = authenticators
+        .get(method)
+        .into_iter()
+        .flatten()
+        .find_map(|authenticator| authenticator.claimed_user(credentials));
+    let host = context.host.parse::<IpAddr>().ok();
+    user.map(Subject::User)
+        .into_iter()
+        .chain(host.map(Subject::Host))
+        .collect()
+}
+
+async fn authenticate_with(
+    authenticators: &Authenticators,
+    stream: &mut impl MessageStream,
+    method: &str,
+    credentials: &[u8],
+    context: &AuthenticationContext,
+) -> std::result::Result<Identity, Failure> {
+    if !context.allowed_methods.is_empty()
+        && !context
+            .allowed_methods
+            .iter()
+            .any(|allowed| allowed == method)
+    {
+        return Err(Failure::Failed(Error::other(format!(
+            "method {method} is not allowed on this listener"
+        ))));
+    }
+
+    let Some(authenticators) = authenticators.get(method) else {
+        return Err(Failure::Failed(Error::other(format!(
+            "invalid mode {method}"
+        ))));
+    };
+
+    let mut last_error = None;
+    for authenticator in authenticators {
+        log::debug!("Authenticating with \"{method}\"");
+        match authenticator.authenticate(credentials, context).await {
+            Ok(outcome) => return complete(stream, outcome).await,
+            Err(error) => {
+                log::debug!("Authentication with \"{method}\" failed: {error}");
+                last_error = Some(error);
+            }
+        }
+    }
+
+    Err(Failure::Rejected(
+        last_error.unwrap_or_else(|| Error::other("no authenticators")),
+    ))
+}
+
+/// Run the remaining rounds of an exchange with the client.
+async fn complete(
+    stream: &mut impl MessageStream,
+    outcome: Outcome,
+) -> std::result::Result<Identity, Failure> {
+    let mut outcome = outcome;
+    loop {
+        match outcome {
+            Outcome::Authenticated(identity, data) => {
+                if let Some(challenge) = data {
+                    stream
+                        .write(&Message::AuthenticationChallenge { challenge })
+                        .await
+                        .map_err(Failure::Failed)?;
+                }
+                return Ok(identity);
+            }
+            Outcome::Challenge(challenge, continuation) => {
+                stream
+                    .write(&Message::AuthenticationChallenge { challenge })
+                    .await
+                    .map_err(Failure::Failed)?;
+                let message = stream.read().await.map_err(Failure::Failed)?;
+                let Message::AuthenticationChallengeResponse { response } = message else {
+                    return Err(Failure::Failed(Error::other(
+                        "expected authentication challenge response",
+                    )));
+                };
+                outcome = continuation
+                    .respond(response)
+                    .await
+                    .map_err(Failure::Rejected)?;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    /// Accepts a single password, as the given user.
+    struct PasswordAuthenticator {
+        password: &'static [u8],
+        user: &'static str,
+    }
+
+    impl Authenticator for PasswordAuthenticator {
+        fn authenticate<'a>(
+            &'a self,
+            credentials: &'a [u8],
+            _context: &'a AuthenticationContext,
+        ) -> BoxFuture<'a, Result<Outcome>> {
+            Box::pin(async move {
+                match credentials == self.password {
+                    true => Ok(Outcome::Authenticated(self.user.to_string().into(), None)),
+                    false => Err(Error::new(ErrorKind::Other, "invalid password")),
+                }
+            })
+        }
+    }
+
+    async fn authenticate(
+        manager: &AuthenticationManager,
+        method: &str,
+        credentials: &[u8],
+    ) -> Result<Identity> {
+        authenticate_with_context(
+            manager,
+            method,
+            credentials,
+            &AuthenticationContext::default(),
+        )
+        .await
+    }
+
+    async fn authenticate_with_context(
+        manager: &AuthenticationManager,
+        method: &str,
+        credentials: &[u8],
+        context: &AuthenticationContext,
+    ) -> Result<Identity> {
+        let (server, client) = tokio::io::duplex(1024);
+        let mut server = common::MessageSocket::new(server);
+        let mut client = common::MessageSocket::new(client);
+
+        client
+            .write(&Message::AuthenticationRequest {
+                method: method.into(),
+                credentials: credentials.into(),
+            })
+            .await?;
+        manager.authenticate(&mut server, context).await
+    }
+
+    fn manager() -> AuthenticationManager {
+        let mut manager = AuthenticationManager {
+            authenticators: Default::default(),
+            lockout: Mutex::new(Lockout::new(&LockoutOption {
+                max_failures: 2,
+                ..Default::default()
+            })),
+        };
+        manager.register(
+            "basic",
+            Box::new(PasswordAuthenticator {
+                password: b"one",
+                user: "service",
+            }),
+        );
+        manager.register(
+            "basic",
+            Box::new(PasswordAuthenticator {
+                password: b"two",
+                user: "human",
+            }),
+        );
+        manager.register("none", Box::new(NoneAuthenticator));
+        manager
+    }
+
+    #[tokio::test]
+    async fn should_fall_back_in_order() {
+        let manager = manager();
+
+        let actual = authenticate(&manager, "basic", b"one").await.unwrap();
+        assert_eq!(actual.user, "service");
+
+        let actual = authenticate(&manager, "basic", b"two").await.unwrap();
+        assert_eq!(actual.user, "human");
+
+        assert!(authenticate(&manager, "basic", b"three").await.is_err());
+    }
+
+    #[tokio::test]
+    async fn should_select_by_method() {
+        let manager = manager();
+
+        let actual = authenticate(&manager, "none", b"").await.unwrap();
+        assert_eq!(actual.user, "nobody");
+
+        assert!(authenticate(&manager, "ldap", b"one").await.is_err());
+    }
+
+    #[tokio::test]
+    async fn should_only_allow_listener_methods() {
+        let manager = manager();
+        let context = AuthenticationContext {
+            allowed_methods: vec!["basic".into()],
+            ..Default::default()
+        };
+
+        let actual = authenticate_with_context(&manager, "basic", b"two", &context)
+            .await
+            .unwrap();
+        assert_eq!(actual.user, "human");
+
+        assert!(authenticate_with_context(&manager, "none", b"", &context)
+            .await
+            .is_err());
+    }
+
+    #[tokio::test]
+    async fn should_lock_out_host_after_failures() {
+        let manager = manager();
+        let context = AuthenticationContext {
+            host: "10.0.0.1".into(),
+            ..Default::default()
+        };
+
+        for _ in 0..2 {
+            assert!(
+                authenticate_with_context(&manager, "basic", b"three", &context)
+                    .await
+                    .is_err()
+            );
+        }
+        // The right password is refused while the host is locked out.
+        let error = authenticate_with_context(&manager, "basic", b"one", &context)
+            .await
+            .unwrap_err();
+        assert!(error.to_string().contains("locked out"));
+
+        // Other hosts are unaffected.
+        let actual = authenticate(&manager, "basic", b"one").await.unwrap();
+        assert_eq!(actual.user, "service");
+    }
+
+    /// Challenges the client, accepting any response.
+    struct ChallengeAuthenticator;
+
+    struct AcceptResponse;
+
+    impl Continuation for AcceptResponse {
+        fn respond(self: Box<Self>, _response: Vec<u8>) -> BoxFuture<'static, Result<Outcome>> {
+            Box::pin(async { Ok(Outcome::Authenticated("tom".to_string().into(), None)) })
+        }
+    }
+
+    impl Authenticator for ChallengeAuthenticator {
+        fn authenticate<'a>(
+            &'a self,
+            _credentials: &'a [u8],
+            _context: &'a AuthenticationContext,
+        ) -> BoxFuture<'a, Result<Outcome>> {
+            Box::pin(async {
+                Ok(Outcome::Challenge(
+                    b"nonce".to_vec(),
+                    Box::new(AcceptResponse),
+                ))
+            })
+        }
+    }
+
+    #[tokio::test]
+    async fn should_not_count_failed_exchanges() {
+        let mut manager = manager();
+        manager.register("challenge", Box::new(ChallengeAuthenticator));
+        let context = AuthenticationContext {
+            host: "10.0.0.1".into(),
+            ..Default::default()
+        };
+
+        // The client goes away instead of answering the challenge.
+        for _ in 0..3 {
+            let (server, client) = tokio::io::duplex(1024);
+            let mut server = common::MessageSocket::new(server);
+            let mut client = common::MessageSocket::new(client);
+            client
+                .write(&Message::AuthenticationRequest {
+                    method: "challenge".into(),
+                    credentials: Vec::new(),
+                })
+                .await
+                .unwrap();
+            drop(client);
+            assert!(manager.authenticate(&mut server, &context).await.is_err());
+        }
+
+        // The handshake times out while the client is challenged.
+        let (server, client) = tokio::io::duplex(1024);
+        let mut server = common::MessageSocket::new(server);
+        let mut client = common::MessageSocket::new(client);
+        client
+            .write(&Message::AuthenticationRequest {
+                method: "challenge".into(),
+                credentials: Vec::new(),
+            })
+            .await
+            .unwrap();
+        let authenticating = manager.authenticate(&mut server, &context);
+        assert!(
+            tokio::time::timeout(std::time::Duration::from_millis(10), authenticating)
+                .await
+                .is_err()
+        );
+
+        // Neither counted as failures, nor were left in progress.
+        let host = [Subject::Host("10.0.0.1".parse().unwrap())];
+        let mut lockout = manager.lockout.lock().unwrap();
+        for _ in 0..2 {
+            assert!(lockout.begin(&host, Instant::now()).is_ok());
+        }
+    }
+
+    /// Counts the reloads, which may fail.
+    struct ReloadingAuthenticator {
+        is_valid: bool,
+        reloads: std::sync::Arc<std::sync::atomic::AtomicUsize>,
+    }
+
+    impl Authenticator for ReloadingAuthenticator {
+        fn authenticate<'a>(
+            &'a self,
+            _credentials: &'a [u8],
+            _context: &'a AuthenticationContext,
+        ) -> BoxFuture<'a, Result<Outcome>> {
+            Box::pin(async { Err(Error::new(ErrorKind::Other, "unused")) })
+        }
+
+        fn reload(&self) -> Result<Option<Box<dyn Authenticator>>> {
+            match self.is_valid {
+                true => {
+                    self.reloads
+                        .fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+                    Ok(Some(Box::new(ReloadingAuthenticator {
+                        is_valid: self.is_valid,
+                        reloads: self.reloads.clone(),
+                    })))
+                }
+                false => Err(Error::new(ErrorKind::Other, "invalid file")),
+            }
+        }
+    }
+
+    #[test]
+    fn should_reset_others_when_one_fails() {
+        let reloads = std::sync::Arc::new(std::sync::atomic::AtomicUsize::new(0));
+        let mut manager = manager();
+        for is_valid in [true, false, true] {
+            manager.register(
+                "reloading",
+                Box::new(ReloadingAuthenticator {
+                    is_valid,
+                    reloads: reloads.clone(),
+                }),
+            );
+        }
+
+        assert!(manager.reset().is_err());
+        assert_eq!(reloads.load(std::sync::atomic::Ordering::SeqCst), 2);
+    }
+}
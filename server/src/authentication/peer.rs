@@ -0,0 +1,101 @@
+//! Authentication with the credentials of the process at the other end of a
+//! Unix domain socket, which the kernel reports with `SO_PEERCRED`.
+
+use std::ffi::CStr;
+use std::io::{Error, ErrorKind, Result};
+
+use futures_util::future::BoxFuture;
+
+use super::{AuthenticationContext, Authenticator, Outcome};
+
+#[derive(Clone)]
+pub struct PeerAuthenticationManager;
+
+impl PeerAuthenticationManager {
+    pub fn authenticate_user(&self, context: &AuthenticationContext) -> Result<String> {
+        let Some(credentials) = &context.peer_credentials else {
+            log::info!("Failed to authenticate without peer credentials");
+            return Err(Error::new(
+                ErrorKind::Other,
+                "peer authentication requires a Unix domain socket",
+            ));
+        };
+
+        let user = user_name(credentials.uid())?;
+        log::info!(
+            "Authenticated uid {} as \"{}\"",
+            credentials.uid(),
+            user.as_str()
+        );
+        Ok(user)
+    }
+}
+
+impl Authenticator for PeerAuthenticationManager {
+    fn authenticate<'a>(
+        &'a self,
+        _credentials: &'a [u8],
+        context: &'a AuthenticationContext,
+    ) -> BoxFuture<'a, Result<Outcome>> {
+        Box::pin(async move {
+            let user = self.authenticate_user(context)?;
+            Ok(Outcome::Authenticated(user.into(), None))
+        })
+    }
+}
+
+/// Find the name of a user in the password database.
+fn user_name(uid: u32) -> Result<String> {
+    let mut passwd: libc::passwd = unsafe { std::mem::zeroed() };
+    let mut result: *mut libc::passwd = std::ptr::null_mut();
+    let mut buffer = vec![0 as libc::c_char; 1024];
+    loop {
+        let status = unsafe {
+            libc::getpwuid_r(
+                uid,
+                &mut passwd,
+                buffer.as_mut_ptr(),
+                buffer.len(),
+                &mut result,
+            )
+        };
+        match status {
+            0 => break,
+            // The buffer is too small for the entry.
+            libc::ERANGE => buffer.resize(buffer.len() * 2, 0),
+            _ => return Err(Error::from_raw_os_error(status)),
+        }
+    }
+
+    if result.is_null() {
+        return Err(Error::new(ErrorKind::Other, format!("unknown uid {uid}")));
+    }
+    // The name points into the buffer, which outlives it.
+    let name = unsafe { CStr::from_ptr(passwd.pw_name) };
+    Ok(name.to_string_lossy().into_owned())
+}
+
+#[cfg(test)]
+mod test {
+    use tokio::net::UnixStream;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn should_authenticate_the_peer_process() {
+        let (server, _client) = UnixStream::pair().unwrap();
+        let context = AuthenticationContext {
+            peer_credentials: Some(server.peer_cred().unwrap()),
+            ..Default::default()
+        };
+
+        let user = PeerAuthenticationManager
+            .authenticate_user(&context)
+            .unwrap();
+        assert_eq!(user, user_name(unsafe { libc::getuid() }).unwrap());
+
+        assert!(PeerAuthenticationManager
+            .authenticate_user(&AuthenticationContext::default())
+            .is_err());
+    }
+}
@@ -0,0 +1,213 @@
+//! Authentication with SCRAM-SHA-256, using a file of stored credentials.
+
+use std::collections::HashMap;
+use std::fs::read_to_string;
+use std::io::{Error, ErrorKind, Result};
+use std::path::PathBuf;
+
+use futures_util::future::BoxFuture;
+
+use common::scram::{ClientFirst, ScramServer, StoredCredentials, UnknownUsers};
+
+use super::{AuthenticationContext, Authenticator, Continuation, Outcome};
+
+#[derive(Clone)]
+pub struct ScramAuthenticationManager {
+    path: PathBuf,
+    data: HashMap<String, StoredCredentials>,
+    unknown_users: UnknownUsers,
+}
+
+impl ScramAuthenticationManager {
+    pub fn new(path: &PathBuf) -> Result<Self> {
+        Ok(ScramAuthenticationManager {
+            path: path.clone(),
+            data: load_scram_credentials(path)?,
+            unknown_users: UnknownUsers::new()?,
+        })
+    }
+}
+
+impl Authenticator for ScramAuthenticationManager {
+    fn authenticate<'a>(
+        &'a self,
+        credentials: &'a [u8],
+        _context: &'a AuthenticationContext,
+    ) -> BoxFuture<'a, Result<Outcome>> {
+        Box::pin(async move {
+            let client_first = ClientFirst::parse(credentials)?;
+            let username = client_first.username.clone();
+
+            // An unknown user is challenged with made up credentials, so the
+            // exchange fails in the same way as for a wrong password.
+            let credentials = match self.data.get(&username) {
+                Some(credentials) => credentials.clone(),
+                None => self.unknown_users.credentials(&username),
+            };
+
+            let server = ScramServer::new(client_first, credentials)?;
+            let challenge = server.server_first();
+            Ok(Outcome::Challenge(
+                challenge,
+                Box::new(ScramContinuation { username, server }),
+            ))
+        })
+    }
+
+    fn claimed_user(&self, credentials: &[u8]) -> Option<String> {
+        Some(ClientFirst::parse(credentials).ok()?.username)
+    }
+
+    fn reload(&self) -> Result<Option<Box<dyn Authenticator>>> {
+        Ok(Some(Box::new(ScramAuthenticationManager {
+            path: self.path.clone(),
+            data: load_scram_credentials(&self.path)?,
+            unknown_users: self.unknown_users.clone(),
+        })))
+    }
+}
+
+/// Waits for the client final message.
+struct ScramContinuation {
+    username: String,
+    server: ScramServer,
+}
+
+impl Continuation for ScramContinuation {
+    fn respond(self: Box<Self>, response: Vec<u8>) -> BoxFuture<'static, Result<Outcome>> {
+        Box::pin(async move {
+            let server_final = match self.server.server_final(&response) {
+                Ok(server_final) => server_final,
+                Err(e) => {
+                    log::info!("Failed to authenticate as \"{}\": {}", self.username, e);
+                    return Err(Error::new(
+                        ErrorKind::Other,
+                        format!("invalid user \"{}\"", self.username),
+                    ));
+                }
+            };
+
+            log::info!("Authenticated as \"{}\"", self.username);
+            Ok(Outcome::Authenticated(
+                self.username.into(),
+                Some(server_final),
+            ))
+        })
+    }
+}
+
+/// Load a file of lines of the form `<user>:<credentials>`.
+fn load_scram_credentials(path: &PathBuf) -> Result<HashMap<String, StoredCredentials>> {
+    let contents = read_to_string(path)?;
+
+    let mut data = HashMap::new();
+
+    for line in contents.lines() {
+        if line.trim().is_empty() {
+            continue;
+        }
+        let (username, credentials) = line
+            .split_once(':')
+            .ok_or_else(|| Error::new(ErrorKind::Other, "invalid_entry"))?;
+        data.insert(username.to_string(), credentials.parse()?);
+    }
+
+    Ok(data)
+}
+
+#[cfg(test)]
+mod test {
+    use common::messages::Message;
+    use common::scram;
+    use common::{MessageSocket, MessageStream};
+
+    use super::super::{AuthenticationManager, Identity};
+    use super::*;
+
+    fn manager() -> AuthenticationManager {
+        let credentials =
+            StoredCredentials::new("pencil", b"salt", std::num::NonZeroU32::new(4096).unwrap());
+        let scram = ScramAuthenticationManager {
+            path: PathBuf::from("scram.passwd"),
+            data: HashMap::from([("user".to_string(), credentials)]),
+            unknown_users: UnknownUsers::new().unwrap(),
+        };
+        let mut manager = AuthenticationManager::new(&[], &Default::default()).unwrap();
+        manager.register(scram::METHOD, Box::new(scram));
+        manager
+    }
+
+    /// Run the client side of the exchange, returning the client id.
+    async fn scram_client(
+        stream: &mut impl MessageStream,
+        username: &str,
+        password: &str,
+    ) -> Result<String> {
+        let mut client = scram::ScramClient::new(username, password)?;
+        stream
+            .write(&Message::AuthenticationRequest {
+                method: scram::METHOD.into(),
+                credentials: client.client_first(),
+            })
+            .await?;
+        let Message::AuthenticationChallenge { challenge } = stream.read().await? else {
+            panic!("expected challenge");
+        };
+        let response = client.client_final(&challenge)?;
+        stream
+            .write(&Message::AuthenticationChallengeResponse { response })
+            .await?;
+        let Message::AuthenticationChallenge { challenge } = stream.read().await? else {
+            panic!("expected challenge");
+        };
+        client.verify_server_final(&challenge)?;
+        let Message::AuthenticationResponse { client_id } = stream.read().await? else {
+            panic!("expected response");
+        };
+        Ok(client_id)
+    }
+
+    async fn run_scram(username: &str, password: &str) -> (Result<Identity>, Result<String>) {
+        let manager = manager();
+        let (server, client) = tokio::io::duplex(1024);
+        let mut server = MessageSocket::new(server);
+        let mut client = MessageSocket::new(client);
+
+        tokio::join!(
+            // The server end closes when authentication finishes.
+            async move {
+                let identity = manager
+                    .authenticate(&mut server, &AuthenticationContext::default())
+                    .await?;
+                server
+                    .write(&Message::AuthenticationResponse {
+                        client_id: "client1".into(),
+                    })
+                    .await?;
+                Ok(identity)
+            },
+            scram_client(&mut client, username, password)
+        )
+    }
+
+    #[tokio::test]
+    async fn should_authenticate_with_scram() {
+        let (server, client) = run_scram("user", "pencil").await;
+        assert_eq!(server.unwrap().user, "user");
+        assert_eq!(client.unwrap(), "client1");
+    }
+
+    #[tokio::test]
+    async fn should_reject_scram_with_wrong_password() {
+        let (server, client) = run_scram("user", "wrong").await;
+        assert!(server.is_err());
+        assert!(client.is_err());
+    }
+
+    #[tokio::test]
+    async fn should_reject_scram_for_unknown_user() {
+        let (server, client) = run_scram("other", "pencil").await;
+        assert!(server.is_err());
+        assert!(client.is_err());
+    }
+}
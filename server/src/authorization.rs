@@ -1,9 +1,13 @@
+use std::cmp::Reverse;
 use std::collections::{HashMap, HashSet};
 use std::fs;
 use std::io::{self, ErrorKind, Result};
+use std::net::IpAddr;
 use std::path::Path;
+use std::sync::Mutex;
 
 use bitflags::bitflags;
+use ipnet::IpNet;
 use serde::{Deserialize, Serialize};
 use wildmatch::WildMatch;
 
@@ -18,73 +22,325 @@ bitflags! {
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct Authorization {
+    #[serde(default)]
     pub entitlements: HashSet<i32>,
     pub roles: Role,
+    /// Deny the entitlements, or all entitlements when there are none.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub deny: bool,
+    /// The networks the client must connect from. Any when empty.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub hosts: Vec<IpNet>,
+}
+
+/// Who an authorization applies to.
+#[derive(Debug, Clone)]
+pub enum Principal {
+    /// Users matching the pattern.
+    User(WildMatch),
+    /// Members of groups matching the pattern, written as "@<pattern>".
+    Group(WildMatch),
+}
+
+impl Principal {
+    pub fn parse(s: &str) -> Principal {
+        match s.strip_prefix('@') {
+            Some(group) => Principal::Group(WildMatch::new(group)),
+            None => Principal::User(WildMatch::new(s)),
+        }
+    }
 }
 
 #[derive(Debug, Clone)]
 pub struct AuthorizationSpec {
-    pub user_pattern: WildMatch,
+    pub principal: Principal,
     pub topic_pattern: WildMatch,
     pub entitlements: HashSet<i32>,
     pub roles: Role,
+    /// The networks the client must connect from. Any when empty.
+    pub hosts: Vec<IpNet>,
+    /// Deny the entitlements, or all entitlements when there are none.
+    pub is_deny: bool,
+}
+
+/// The authorization specs, with the groups defined alongside them.
+#[derive(Debug, Clone, Default)]
+pub struct Authorizations {
+    pub specs: Vec<AuthorizationSpec>,
+    /// The user patterns of the members of each group.
+    pub groups: HashMap<String, Vec<WildMatch>>,
+}
+
+impl From<Vec<AuthorizationSpec>> for Authorizations {
+    fn from(specs: Vec<AuthorizationSpec>) -> Self {
+        Authorizations {
+            specs,
+            groups: HashMap::new(),
+        }
+    }
+}
+
+/// The number of entitlements a cache holds before it is cleared.
+const CACHE_CAPACITY: usize = 10_000;
+
+/// The entitlements of a client by role and topic.
+#[derive(Default)]
+pub struct EntitlementCache {
+    state: Mutex<EntitlementCacheState>,
+}
+
+#[derive(Default)]
+struct EntitlementCacheState {
+    /// The generation of the authorizations the entries were made with.
+    generation: u64,
+    entries: HashMap<Role, HashMap<String, HashSet<i32>>>,
+    len: usize,
 }
 
 pub struct AuthorizationManager {
-    specs: Vec<AuthorizationSpec>,
+    authorizations: Authorizations,
+    /// Incremented when the authorizations are reset, invalidating caches.
+    generation: u64,
 }
 
 impl AuthorizationManager {
-    pub fn new(specs: Vec<AuthorizationSpec>) -> Self {
-        AuthorizationManager { specs }
+    pub fn new(authorizations: Authorizations) -> Self {
+        AuthorizationManager {
+            authorizations,
+            generation: 0,
+        }
+    }
+
+    pub fn reset(&mut self, authorizations: Authorizations) {
+        self.authorizations = authorizations;
+        self.generation += 1;
+    }
+
+    /// The entitlements, as given by `entitlements`, looked up in the cache of
+    /// the client first.
+    pub fn cached_entitlements(
+        &self,
+        cache: &EntitlementCache,
+        user_name: &str,
+        groups: &[String],
+        host: Option<IpAddr>,
+        topic: &str,
+        role: Role,
+    ) -> HashSet<i32> {
+        let mut state = cache.state.lock().unwrap();
+        if state.generation != self.generation || state.len >= CACHE_CAPACITY {
+            state.entries.clear();
+            state.len = 0;
+            state.generation = self.generation;
+        }
+
+        if let Some(entitlements) = state.entries.get(&role).and_then(|x| x.get(topic)) {
+            return entitlements.clone();
+        }
+
+        let entitlements = self.entitlements(user_name, groups, host, topic, role);
+        state
+            .entries
+            .entry(role)
+            .or_default()
+            .insert(topic.into(), entitlements.clone());
+        state.len += 1;
+        entitlements
     }
 
-    pub fn reset(&mut self, specs: Vec<AuthorizationSpec>) {
-        self.specs = specs
+    /// The entitlements of a user, who belongs to the groups given by the
+    /// authentication backend as well as any defined with the authorizations.
+    /// Specs restricted to hosts only apply to clients connecting from an IP
+    /// address in one of their networks.
+    ///
+    /// Each entitlement is allowed or denied by the most specific matching
+    /// spec which names it, where a deny without entitlements names them all.
+    /// See `precedence` for which spec is the most specific.
+    pub fn entitlements(
+        &self,
+        user_name: &str,
+        groups: &[String],
+        host: Option<IpAddr>,
+        topic: &str,
+        role: Role,
+    ) -> HashSet<i32> {
+        self.decide(user_name, groups, host, topic, role).0
     }
 
-    pub fn entitlements(&self, user_name: &str, topic: &str, role: Role) -> HashSet<i32> {
+    /// Whether a user may take a role for a topic, which may be a pattern.
+    /// This is decided as for `entitlements`, where an allow must grant an
+    /// entitlement which no more specific spec has decided, or grant none,
+    /// as the roles which need no entitlements do.
+    pub fn is_authorized(
+        &self,
+        user_name: &str,
+        groups: &[String],
+        host: Option<IpAddr>,
+        topic: &str,
+        role: Role,
+    ) -> bool {
+        self.decide(user_name, groups, host, topic, role).1
+    }
+
+    /// The entitlements allowed, and whether any allow took effect.
+    fn decide(
+        &self,
+        user_name: &str,
+        groups: &[String],
+        host: Option<IpAddr>,
+        topic: &str,
+        role: Role,
+    ) -> (HashSet<i32>, bool) {
         let mut entitlements = HashSet::new();
+        let mut decided: HashSet<i32> = HashSet::new();
+        let mut is_authorized = false;
 
-        for spec in &self.specs {
-            if spec.roles.contains(role)
-                && spec.user_pattern.matches(user_name)
-                && spec.topic_pattern.matches(topic)
-            {
-                entitlements.extend(spec.entitlements.iter());
+        for spec in self.matching_specs(user_name, groups, host, topic, role) {
+            if spec.is_deny && spec.entitlements.is_empty() {
+                break;
+            }
+            let mut is_granted = false;
+            for entitlement in &spec.entitlements {
+                if decided.insert(*entitlement) && !spec.is_deny {
+                    entitlements.insert(*entitlement);
+                    is_granted = true;
+                }
+            }
+            if !spec.is_deny && (is_granted || spec.entitlements.is_empty()) {
+                is_authorized = true;
             }
         }
 
-        entitlements
+        (entitlements, is_authorized)
+    }
+
+    /// The matching specs, the most specific first.
+    fn matching_specs(
+        &self,
+        user_name: &str,
+        groups: &[String],
+        host: Option<IpAddr>,
+        topic: &str,
+        role: Role,
+    ) -> Vec<&AuthorizationSpec> {
+        let mut specs: Vec<&AuthorizationSpec> = self
+            .authorizations
+            .specs
+            .iter()
+            .filter(|spec| {
+                spec.roles.contains(role)
+                    && self.is_principal(&spec.principal, user_name, groups)
+                    && is_host(&spec.hosts, host)
+                    && spec.topic_pattern.matches(topic)
+            })
+            .collect();
+        specs.sort_by_key(|spec| Reverse(precedence(spec)));
+        specs
+    }
+
+    fn is_principal(&self, principal: &Principal, user_name: &str, groups: &[String]) -> bool {
+        match principal {
+            Principal::User(user_pattern) => user_pattern.matches(user_name),
+            Principal::Group(group_pattern) => {
+                groups.iter().any(|group| group_pattern.matches(group))
+                    || self
+                        .authorizations
+                        .groups
+                        .iter()
+                        .filter(|(group, _)| group_pattern.matches(group))
+                        .any(|(_, members)| members.iter().any(|m| m.matches(user_name)))
+            }
+        }
     }
 }
 
+/// How specific a spec is, compared in order:
+///
+/// 1. The topic pattern with more characters other than wildcards.
+/// 2. A user named in full, then a group, then a user pattern with wildcards.
+/// 3. A deny over an allow.
+///
+/// The order of the specs therefore makes no difference.
+fn precedence(spec: &AuthorizationSpec) -> (usize, u8, bool) {
+    let is_wildcard = |c: &char| matches!(c, '*' | '?');
+    let topic = spec
+        .topic_pattern
+        .pattern_chars()
+        .iter()
+        .filter(|c| !is_wildcard(c))
+        .count();
+    let principal = match &spec.principal {
+        Principal::User(user) if !user.pattern_chars().iter().any(is_wildcard) => 2,
+        Principal::Group(_) => 1,
+        Principal::User(_) => 0,
+    };
+    (topic, principal, spec.is_deny)
+}
+
+fn is_host(hosts: &[IpNet], host: Option<IpAddr>) -> bool {
+    match host {
+        _ if hosts.is_empty() => true,
+        Some(host) => hosts.iter().any(|network| network.contains(&host)),
+        None => false,
+    }
+}
+
+/// An authorizations file with groups. Without groups the file may hold just
+/// the authorizations.
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct AuthorizationsConfig {
+    #[serde(default)]
+    groups: HashMap<String, Vec<String>>,
+    authorizations: HashMap<String, HashMap<String, Authorization>>,
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum AuthorizationsFile {
+    WithGroups(AuthorizationsConfig),
+    WithoutGroups(HashMap<String, HashMap<String, Authorization>>),
+}
+
 pub fn load_authorizations<P>(
     path: &Option<P>,
     specs: &[AuthorizationSpec],
-) -> Result<Vec<AuthorizationSpec>>
+) -> Result<Authorizations>
 where
     P: AsRef<Path>,
 {
     let mut specs: Vec<AuthorizationSpec> = specs.to_vec(); // specs.iter().map(|x| *x.clone()).collect();
+    let mut groups: HashMap<String, Vec<WildMatch>> = HashMap::new();
 
     // Either load from a file, or provide useful defaults.
     match path {
         Some(path) => {
             let file = fs::File::open(path)?;
-            let authorizations: HashMap<String, HashMap<String, Authorization>> =
+            let file: AuthorizationsFile =
                 serde_yaml::from_reader(file).map_err(|e| io::Error::new(ErrorKind::Other, e))?;
-            for (user, topic_authorization) in authorizations {
+            let authorizations = match file {
+                AuthorizationsFile::WithGroups(config) => {
+                    for (group, members) in config.groups {
+                        let members = members.iter().map(|m| WildMatch::new(m)).collect();
+                        groups.insert(group, members);
+                    }
+                    config.authorizations
+                }
+                AuthorizationsFile::WithoutGroups(authorizations) => authorizations,
+            };
+            for (principal, topic_authorization) in authorizations {
                 for (topic, authorization) in topic_authorization {
-                    let user_pattern = WildMatch::new(user.as_str());
+                    let principal = Principal::parse(principal.as_str());
                     let topic_pattern = WildMatch::new(topic.as_str());
                     let entitlements: HashSet<i32> = HashSet::from_iter(authorization.entitlements);
                     let roles = authorization.roles;
                     specs.push(AuthorizationSpec {
-                        user_pattern,
+                        principal,
                         topic_pattern,
                         entitlements,
                         roles,
+                        hosts: authorization.hosts,
+                        is_deny: authorization.deny,
                     });
                 }
             }
@@ -97,21 +353,23 @@ where
                 let entitlements = HashSet::from([0]);
                 let roles = Role::Subscriber | Role::Notifier | Role::Publisher;
 
-                let user_pattern = WildMatch::new(user);
+                let principal = Principal::User(WildMatch::new(user));
                 let topic_pattern = WildMatch::new(topic);
 
                 let spec = AuthorizationSpec {
-                    user_pattern,
+                    principal,
                     topic_pattern,
                     entitlements,
                     roles,
+                    hosts: Vec::new(),
+                    is_deny: false,
                 };
                 specs.push(spec)
             }
         }
     };
 
-    Ok(specs)
+    Ok(Authorizations { specs, groups })
 }
 
 #[cfg(test)]
@@ -122,55 +380,410 @@ mod test {
     fn smoke() {
         let user_entitlements_spec = vec![
             AuthorizationSpec {
-                user_pattern: WildMatch::new("*"),
+                principal: Principal::User(WildMatch::new("*")),
                 topic_pattern: WildMatch::new("PUB.*"),
                 entitlements: HashSet::from([0]),
                 roles: Role::Subscriber | Role::Notifier | Role::Publisher,
+                hosts: Vec::new(),
+                is_deny: false,
             },
             AuthorizationSpec {
-                user_pattern: WildMatch::new("joe"),
+                principal: Principal::User(WildMatch::new("joe")),
                 topic_pattern: WildMatch::new("*.LSE"),
                 entitlements: HashSet::from([1, 2]),
                 roles: Role::Subscriber | Role::Notifier,
+                hosts: Vec::new(),
+                is_deny: false,
             },
             AuthorizationSpec {
-                user_pattern: WildMatch::new("joe"),
+                principal: Principal::User(WildMatch::new("joe")),
                 topic_pattern: WildMatch::new("*.NSE"),
                 entitlements: HashSet::from([3, 4]),
                 roles: Role::Subscriber,
+                hosts: Vec::new(),
+                is_deny: false,
             },
         ];
-        let entitlements_manager = AuthorizationManager::new(user_entitlements_spec);
+        let entitlements_manager = AuthorizationManager::new(user_entitlements_spec.into());
 
-        let actual = entitlements_manager.entitlements("nobody", "PUB.foo", Role::Subscriber);
+        let actual =
+            entitlements_manager.entitlements("nobody", &[], None, "PUB.foo", Role::Subscriber);
         let expected: HashSet<i32> = HashSet::from([0]);
         assert_eq!(actual, expected);
 
-        let actual = entitlements_manager.entitlements("nobody", "PUB.foo", Role::Publisher);
+        let actual =
+            entitlements_manager.entitlements("nobody", &[], None, "PUB.foo", Role::Publisher);
         let expected: HashSet<i32> = HashSet::from([0]);
         assert_eq!(actual, expected);
 
-        let actual = entitlements_manager.entitlements("nobody", "PUB.foo", Role::Notifier);
+        let actual =
+            entitlements_manager.entitlements("nobody", &[], None, "PUB.foo", Role::Notifier);
         let expected: HashSet<i32> = HashSet::from([0]);
         assert_eq!(actual, expected);
 
-        let actual = entitlements_manager.entitlements("joe", "TSCO.LSE", Role::Subscriber);
+        let actual =
+            entitlements_manager.entitlements("joe", &[], None, "TSCO.LSE", Role::Subscriber);
         let expected: HashSet<i32> = HashSet::from([1, 2]);
         assert_eq!(actual, expected);
 
-        let actual = entitlements_manager.entitlements("joe", "TSCO.LSE", Role::Notifier);
+        let actual =
+            entitlements_manager.entitlements("joe", &[], None, "TSCO.LSE", Role::Notifier);
         let expected: HashSet<i32> = HashSet::from([1, 2]);
         assert_eq!(actual, expected);
 
-        let actual = entitlements_manager.entitlements("joe", "TSCO.LSE", Role::Publisher);
+        let actual =
+            entitlements_manager.entitlements("joe", &[], None, "TSCO.LSE", Role::Publisher);
         assert!(actual.is_empty());
 
-        let actual = entitlements_manager.entitlements("joe", "IBM.NSE", Role::Subscriber);
+        let actual =
+            entitlements_manager.entitlements("joe", &[], None, "IBM.NSE", Role::Subscriber);
         let expected: HashSet<i32> = HashSet::from([3, 4]);
         assert_eq!(actual, expected);
 
-        let actual = entitlements_manager.entitlements("joe", "MSFT.NDAQ", Role::Subscriber);
+        let actual =
+            entitlements_manager.entitlements("joe", &[], None, "MSFT.NDAQ", Role::Subscriber);
         let expected: HashSet<i32> = HashSet::from([]);
         assert_eq!(actual, expected);
     }
+
+    #[test]
+    fn groups() {
+        let authorizations = Authorizations {
+            specs: vec![AuthorizationSpec {
+                principal: Principal::parse("@traders"),
+                topic_pattern: WildMatch::new("*.LSE"),
+                entitlements: HashSet::from([1]),
+                roles: Role::Subscriber,
+                hosts: Vec::new(),
+                is_deny: false,
+            }],
+            groups: HashMap::from([("traders".to_string(), vec![WildMatch::new("tom")])]),
+        };
+        let entitlements_manager = AuthorizationManager::new(authorizations);
+
+        // A member of a group defined with the authorizations.
+        let actual =
+            entitlements_manager.entitlements("tom", &[], None, "TSCO.LSE", Role::Subscriber);
+        assert_eq!(actual, HashSet::from([1]));
+
+        // A member of a group given by the authentication backend.
+        let actual = entitlements_manager.entitlements(
+            "dick",
+            &["traders".to_string()],
+            None,
+            "TSCO.LSE",
+            Role::Subscriber,
+        );
+        assert_eq!(actual, HashSet::from([1]));
+
+        let actual = entitlements_manager.entitlements(
+            "harry",
+            &["sales".to_string()],
+            None,
+            "TSCO.LSE",
+            Role::Subscriber,
+        );
+        assert!(actual.is_empty());
+    }
+
+    #[test]
+    fn load_with_groups() {
+        let path = std::env::temp_dir().join(format!("authorizations-{}.yaml", std::process::id()));
+        fs::write(
+            &path,
+            "
+groups:
+  traders: [tom, dick]
+authorizations:
+  \"@traders\":
+    \"*.LSE\":
+      entitlements: [1]
+      roles: Subscriber
+  harry:
+    \"*.LSE\":
+      entitlements: [1, 2]
+      roles: Publisher
+",
+        )
+        .unwrap();
+        let authorizations = load_authorizations(&Some(&path), &[]);
+        fs::remove_file(&path).unwrap();
+        let entitlements_manager = AuthorizationManager::new(authorizations.unwrap());
+
+        let actual =
+            entitlements_manager.entitlements("dick", &[], None, "TSCO.LSE", Role::Subscriber);
+        assert_eq!(actual, HashSet::from([1]));
+
+        let actual =
+            entitlements_manager.entitlements("harry", &[], None, "TSCO.LSE", Role::Publisher);
+        assert_eq!(actual, HashSet::from([1, 2]));
+
+        let actual =
+            entitlements_manager.entitlements("harry", &[], None, "TSCO.LSE", Role::Subscriber);
+        assert!(actual.is_empty());
+    }
+
+    #[test]
+    fn hosts() {
+        let specs = vec![AuthorizationSpec {
+            principal: Principal::parse("*"),
+            topic_pattern: WildMatch::new("LSE.*"),
+            entitlements: HashSet::from([1]),
+            roles: Role::Publisher,
+            hosts: vec!["10.1.0.0/16".parse().unwrap(), "fd00::/8".parse().unwrap()],
+            is_deny: false,
+        }];
+        let entitlements_manager = AuthorizationManager::new(specs.into());
+
+        for (host, expected) in [
+            (Some("10.1.2.3"), HashSet::from([1])),
+            (Some("fd00::1"), HashSet::from([1])),
+            (Some("10.2.0.1"), HashSet::new()),
+            (None, HashSet::new()),
+        ] {
+            let host = host.map(|host| host.parse().unwrap());
+            let actual =
+                entitlements_manager.entitlements("feed", &[], host, "LSE.TSCO", Role::Publisher);
+            assert_eq!(actual, expected, "{host:?}");
+        }
+    }
+
+    #[test]
+    fn deny_and_allow() {
+        let specs: Vec<AuthorizationSpec> = [
+            "*:PUB.*:0:Subscriber|Publisher",
+            "!@contractors:PUB.*::Subscriber",
+            "tom:PUB.*:0,1:Subscriber",
+            "*:LSE.*:1,2:Subscriber",
+            "!dick:LSE.*:2:Subscriber",
+            "!*:LSE.*::Subscriber:192.168.0.0/16",
+        ]
+        .iter()
+        .map(|spec| spec.parse().unwrap())
+        .collect();
+        let authorizations = Authorizations {
+            specs,
+            groups: HashMap::from([("contractors".to_string(), vec![WildMatch::new("tom")])]),
+        };
+        let entitlements_manager = AuthorizationManager::new(authorizations);
+        let office = Some("10.0.0.1".parse().unwrap());
+        let home = Some("192.168.1.1".parse().unwrap());
+
+        // A deny for a group beats an allow for everyone, but not an allow
+        // for the user.
+        let actual =
+            entitlements_manager.entitlements("tom", &[], office, "PUB.foo", Role::Subscriber);
+        assert_eq!(actual, HashSet::from([0, 1]));
+        let actual = entitlements_manager.entitlements(
+            "dick",
+            &["contractors".into()],
+            office,
+            "PUB.foo",
+            Role::Subscriber,
+        );
+        assert!(actual.is_empty());
+
+        // The deny is only for the role it names.
+        let actual =
+            entitlements_manager.entitlements("tom", &[], office, "PUB.foo", Role::Publisher);
+        assert_eq!(actual, HashSet::from([0]));
+
+        let actual =
+            entitlements_manager.entitlements("harry", &[], office, "PUB.foo", Role::Subscriber);
+        assert_eq!(actual, HashSet::from([0]));
+
+        // A deny of some entitlements leaves the others.
+        let actual =
+            entitlements_manager.entitlements("dick", &[], office, "LSE.TSCO", Role::Subscriber);
+        assert_eq!(actual, HashSet::from([1]));
+
+        // A deny restricted to hosts.
+        let actual =
+            entitlements_manager.entitlements("harry", &[], home, "LSE.TSCO", Role::Subscriber);
+        assert!(actual.is_empty());
+    }
+
+    #[test]
+    fn most_specific_takes_precedence() {
+        let specs = [
+            "!*:LSE.*::Subscriber",
+            "*:LSE.TSCO:1,2:Subscriber",
+            "!*:LSE.TSCO:2:Subscriber",
+            "@traders:NYSE.*:1,2:Subscriber",
+            "!tom:NYSE.*:2:Subscriber",
+            "!@contractors:NYSE.*::Subscriber",
+            "dick:NYSE.*:3:Subscriber",
+        ];
+        let parse = |specs: &[&str]| -> Vec<AuthorizationSpec> {
+            specs.iter().map(|spec| spec.parse().unwrap()).collect()
+        };
+        let forwards = AuthorizationManager::new(parse(&specs).into());
+        let mut reversed = specs;
+        reversed.reverse();
+        let backwards = AuthorizationManager::new(parse(&reversed).into());
+
+        let traders = ["traders".to_string()];
+        let contractors = ["traders".to_string(), "contractors".to_string()];
+        for (user, groups, topic, expected) in [
+            // The more specific topic allows, but a deny of the same topic
+            // wins a tie.
+            ("harry", &traders[..], "LSE.TSCO", HashSet::from([1])),
+            ("harry", &traders[..], "LSE.VOD", HashSet::new()),
+            // The user beats the group.
+            ("tom", &traders[..], "NYSE.IBM", HashSet::from([1])),
+            ("harry", &traders[..], "NYSE.IBM", HashSet::from([1, 2])),
+            // Groups tie, so the deny wins, but the user beats both.
+            ("harry", &contractors[..], "NYSE.IBM", HashSet::new()),
+            ("dick", &contractors[..], "NYSE.IBM", HashSet::from([3])),
+        ] {
+            for manager in [&forwards, &backwards] {
+                let actual = manager.entitlements(user, groups, None, topic, Role::Subscriber);
+                assert_eq!(actual, expected, "{user} {groups:?} {topic}");
+                assert_eq!(
+                    manager.is_authorized(user, groups, None, topic, Role::Subscriber),
+                    !expected.is_empty(),
+                    "{user} {groups:?} {topic}"
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn load_with_deny() {
+        let path =
+            std::env::temp_dir().join(format!("authorizations-deny-{}.yaml", std::process::id()));
+        fs::write(
+            &path,
+            "
+\"*\":
+  \"PUB.*\":
+    entitlements: [0]
+    roles: Subscriber
+\"contractor-*\":
+  \"PUB.*\":
+    deny: true
+    roles: Subscriber
+",
+        )
+        .unwrap();
+        let authorizations = load_authorizations(&Some(&path), &[]);
+        fs::remove_file(&path).unwrap();
+        let entitlements_manager = AuthorizationManager::new(authorizations.unwrap());
+
+        let actual =
+            entitlements_manager.entitlements("tom", &[], None, "PUB.foo", Role::Subscriber);
+        assert_eq!(actual, HashSet::from([0]));
+
+        let actual = entitlements_manager.entitlements(
+            "contractor-tom",
+            &[],
+            None,
+            "PUB.foo",
+            Role::Subscriber,
+        );
+        assert!(actual.is_empty());
+    }
+
+    #[test]
+    fn cache_is_invalidated_on_reset() {
+        let spec = |entitlements: &str| -> Vec<AuthorizationSpec> {
+            vec![format!("*:PUB.*:{entitlements}:Subscriber")
+                .parse()
+                .unwrap()]
+        };
+        let mut entitlements_manager = AuthorizationManager::new(spec("1").into());
+        let cache = EntitlementCache::default();
+
+        let actual = entitlements_manager.cached_entitlements(
+            &cache,
+            "tom",
+            &[],
+            None,
+            "PUB.foo",
+            Role::Subscriber,
+        );
+        assert_eq!(actual, HashSet::from([1]));
+        assert_eq!(cache.state.lock().unwrap().len, 1);
+
+        // A hit leaves the cache unchanged.
+        let actual = entitlements_manager.cached_entitlements(
+            &cache,
+            "tom",
+            &[],
+            None,
+            "PUB.foo",
+            Role::Subscriber,
+        );
+        assert_eq!(actual, HashSet::from([1]));
+        assert_eq!(cache.state.lock().unwrap().len, 1);
+
+        let actual = entitlements_manager.cached_entitlements(
+            &cache,
+            "tom",
+            &[],
+            None,
+            "PUB.foo",
+            Role::Publisher,
+        );
+        assert!(actual.is_empty());
+        assert_eq!(cache.state.lock().unwrap().len, 2);
+
+        entitlements_manager.reset(spec("2").into());
+
+        let actual = entitlements_manager.cached_entitlements(
+            &cache,
+            "tom",
+            &[],
+            None,
+            "PUB.foo",
+            Role::Subscriber,
+        );
+        assert_eq!(actual, HashSet::from([2]));
+        assert_eq!(cache.state.lock().unwrap().len, 1);
+    }
+
+    #[test]
+    fn is_authorized() {
+        let specs: Vec<AuthorizationSpec> = [
+            "*:PUB.*::Subscriber|Notifier",
+            "!@contractors:PUB.*::Notifier",
+            "tom:LSE.*:1,2:Subscriber",
+            "!tom:LSE.*:1:Subscriber",
+            "tom:NSE.*:1:Subscriber",
+            "!tom:NSE.*:1:Subscriber",
+        ]
+        .iter()
+        .map(|spec| spec.parse().unwrap())
+        .collect();
+        let entitlements_manager = AuthorizationManager::new(specs.into());
+        let contractors = ["contractors".to_string()];
+
+        assert!(entitlements_manager.is_authorized("tom", &[], None, "PUB.*", Role::Notifier));
+        assert!(!entitlements_manager.is_authorized(
+            "tom",
+            &contractors,
+            None,
+            "PUB.*",
+            Role::Notifier
+        ));
+        assert!(entitlements_manager.is_authorized(
+            "tom",
+            &contractors,
+            None,
+            "PUB.foo",
+            Role::Subscriber
+        ));
+        assert!(!entitlements_manager.is_authorized("tom", &[], None, "*", Role::Subscriber));
+
+        // A deny of some entitlements leaves the role authorized, but a deny
+        // of all those allowed does not.
+        assert!(entitlements_manager.is_authorized("tom", &[], None, "LSE.TSCO", Role::Subscriber));
+        assert!(!entitlements_manager.is_authorized(
+            "tom",
+            &[],
+            None,
+            "NSE.INFY",
+            Role::Subscriber
+        ));
+    }
 }
@@ -1,8 +1,12 @@
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use std::io;
+use std::net::IpAddr;
 
 use tokio::sync::mpsc::Sender;
 
+use common::messages::Message;
+
+use crate::authorization::{AuthorizationManager, EntitlementCache, Role};
 use crate::events::ServerEvent;
 use crate::notifications::NotificationManager;
 use crate::publishing::PublisherManager;
@@ -11,7 +15,45 @@ use crate::subscriptions::SubscriptionManager;
 pub struct Client {
     pub tx: Sender<ServerEvent>,
     pub host: String,
+    /// The address of the host, when connected over IP.
+    pub ip: Option<IpAddr>,
     pub user: String,
+    /// The groups given by the authentication backend.
+    pub groups: Vec<String>,
+    pub entitlement_cache: EntitlementCache,
+}
+
+impl Client {
+    pub fn entitlements(
+        &self,
+        authorization_manager: &AuthorizationManager,
+        topic: &str,
+        role: Role,
+    ) -> HashSet<i32> {
+        authorization_manager.cached_entitlements(
+            &self.entitlement_cache,
+            &self.user,
+            &self.groups,
+            self.ip,
+            topic,
+            role,
+        )
+    }
+
+    pub fn is_authorized(
+        &self,
+        authorization_manager: &AuthorizationManager,
+        topic: &str,
+        role: Role,
+    ) -> bool {
+        authorization_manager.is_authorized(&self.user, &self.groups, self.ip, topic, role)
+    }
+
+    /// Send a message without waiting for the hub, dropping it when the
+    /// client's queue is full.
+    pub fn report(&self, message: Message) {
+        let _ = self.tx.try_send(ServerEvent::OnMessage(message));
+    }
 }
 
 pub struct ClientManager {
@@ -30,11 +72,23 @@ impl ClientManager {
         client_id: &str,
         host: String,
         user: String,
+        groups: Vec<String>,
         tx: Sender<ServerEvent>,
     ) {
-        log::debug!("client {client_id} connected for {user}@{host}");
-        self.clients
-            .insert(client_id.into(), Client { host, user, tx });
+        log::debug!("client {client_id} connected for {user}@{host} with groups {groups:?}");
+        // IPv4 clients of a dual stack listener have mapped addresses.
+        let ip = host.parse().ok().map(|ip: IpAddr| ip.to_canonical());
+        self.clients.insert(
+            client_id.into(),
+            Client {
+                host,
+                ip,
+                user,
+                groups,
+                entitlement_cache: EntitlementCache::default(),
+                tx,
+            },
+        );
     }
 
     pub async fn handle_close(
@@ -0,0 +1,249 @@
+//! Conflation of data for subscriptions with a maximum update rate.
+//!
+//! When data arrives for a topic sooner than the rate allows, it replaces any
+//! data already waiting, so the subscriber receives the latest data when the
+//! hub next flushes. The hub only flushes while data is waiting.
+
+use std::collections::HashMap;
+use std::io;
+use std::time::{Duration, Instant};
+
+use common::messages::Message;
+
+use crate::{clients::ClientManager, events::ServerEvent, subscriptions::SubscriptionManager};
+
+/// How often the hub flushes conflated data.
+pub const FLUSH_INTERVAL: Duration = Duration::from_millis(25);
+
+struct ConflatedTopic {
+    interval: Duration,
+    last_sent: Instant,
+    pending: Option<Message>,
+}
+
+pub struct ConflationManager {
+    topics_by_subscriber: HashMap<String, HashMap<String, ConflatedTopic>>,
+    /// The number of topics with data waiting.
+    pending: usize,
+}
+
+impl ConflationManager {
+    pub fn new() -> ConflationManager {
+        ConflationManager {
+            topics_by_subscriber: HashMap::new(),
+            pending: 0,
+        }
+    }
+
+    /// Whether any data is waiting to be flushed.
+    pub fn has_pending(&self) -> bool {
+        self.pending > 0
+    }
+
+    /// Returns the message if it can be sent now, otherwise keeps it as the
+    /// latest for the topic.
+    pub fn conflate(
+        &mut self,
+        subscriber_id: &str,
+        topic: &str,
+        max_rate: u32,
+        message: Message,
+        now: Instant,
+    ) -> Option<Message> {
+        let interval = Duration::from_secs(1) / max_rate.max(1);
+        let topics = self
+            .topics_by_subscriber
+            .entry(subscriber_id.into())
+            .or_default();
+
+        match topics.get_mut(topic) {
+            Some(conflated) if now.duration_since(conflated.last_sent) < interval => {
+                conflated.interval = interval;
+                if conflated.pending.replace(message).is_none() {
+                    self.pending += 1;
+                }
+                None
+            }
+            Some(conflated) => {
+                conflated.interval = interval;
+                conflated.last_sent = now;
+                if conflated.pending.take().is_some() {
+                    self.pending -= 1;
+                }
+                Some(message)
+            }
+            None => {
+                topics.insert(
+                    topic.into(),
+                    ConflatedTopic {
+                        interval,
+                        last_sent: now,
+                        pending: None,
+                    },
+                );
+                Some(message)
+            }
+        }
+    }
+
+    /// Take the messages which are due to be sent, as tuples of subscriber
+    /// id, topic and message.
+    fn take_due(&mut self, now: Instant) -> Vec<(String, String, Message)> {
+        let mut due = Vec::new();
+        let mut taken = 0;
+
+        for (subscriber_id, topics) in self.topics_by_subscriber.iter_mut() {
+            topics.retain(|topic, conflated| {
+                if now.duration_since(conflated.last_sent) < conflated.interval {
+                    return true;
+                }
+                match conflated.pending.take() {
+                    Some(message) => {
+                        conflated.last_sent = now;
+                        due.push((subscriber_id.clone(), topic.clone(), message));
+                        taken += 1;
+                        true
+                    }
+                    // Nothing has been held back for a whole interval, so the
+                    // next message can be sent immediately.
+                    None => false,
+                }
+            });
+        }
+
+        self.topics_by_subscriber
+            .retain(|_, topics| !topics.is_empty());
+        self.pending -= taken;
+
+        due
+    }
+
+    /// Send the conflated data which is due.
+    pub async fn flush(
+        &mut self,
+        client_manager: &ClientManager,
+        subscription_manager: &SubscriptionManager,
+    ) -> io::Result<()> {
+        for (subscriber_id, topic, message) in self.take_due(Instant::now()) {
+            if !subscription_manager.is_subscribed(&subscriber_id, &topic) {
+                log::debug!("flush: {subscriber_id} no longer subscribes to {topic}");
+                continue;
+            }
+
+            if let Some(subscriber) = client_manager.get(&subscriber_id) {
+                log::debug!("flush: sending conflated {topic} to {subscriber_id}");
+
+                let event = ServerEvent::OnMessage(message);
+
+                subscriber
+                    .tx
+                    .send(event)
+                    .await
+                    .map_err(|e| io::Error::new(io::ErrorKind::Other, e))?;
+            }
+        }
+
+        Ok(())
+    }
+
+    pub fn handle_close(&mut self, closed_client_id: &str) {
+        if let Some(topics) = self.topics_by_subscriber.remove(closed_client_id) {
+            self.pending -= topics
+                .values()
+                .filter(|conflated| conflated.pending.is_some())
+                .count();
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn message(text: &str) -> Message {
+        Message::ForwardedMulticastData {
+            host: "host1".into(),
+            user: "mary".into(),
+            topic: "VOD.LSE".into(),
+            data_packets: vec![common::messages::DataPacket::new(
+                Default::default(),
+                Default::default(),
+                text.into(),
+            )],
+        }
+    }
+
+    #[test]
+    fn should_conflate_to_latest() {
+        let mut manager = ConflationManager::new();
+        let start = Instant::now();
+
+        // The first message is sent immediately.
+        let actual = manager.conflate("sub1", "VOD.LSE", 4, message("1"), start);
+        assert_eq!(actual, Some(message("1")));
+
+        // Messages within the interval are held, keeping the latest.
+        let now = start + Duration::from_millis(100);
+        assert_eq!(
+            manager.conflate("sub1", "VOD.LSE", 4, message("2"), now),
+            None
+        );
+        assert_eq!(
+            manager.conflate("sub1", "VOD.LSE", 4, message("3"), now),
+            None
+        );
+        assert!(manager.take_due(now).is_empty());
+        assert!(manager.has_pending());
+
+        // The latest is sent when the interval has passed.
+        let now = start + Duration::from_millis(250);
+        let actual = manager.take_due(now);
+        assert_eq!(
+            actual,
+            vec![("sub1".to_string(), "VOD.LSE".to_string(), message("3"))]
+        );
+        assert!(!manager.has_pending());
+
+        // Another message within the interval is held.
+        let now = start + Duration::from_millis(300);
+        assert_eq!(
+            manager.conflate("sub1", "VOD.LSE", 4, message("4"), now),
+            None
+        );
+        assert!(manager.has_pending());
+
+        manager.handle_close("sub1");
+        assert!(!manager.has_pending());
+    }
+
+    #[test]
+    fn should_forget_idle_topics() {
+        let mut manager = ConflationManager::new();
+        let start = Instant::now();
+
+        manager.conflate("sub1", "VOD.LSE", 4, message("1"), start);
+        assert!(manager.take_due(start + Duration::from_secs(1)).is_empty());
+        assert!(manager.topics_by_subscriber.is_empty());
+
+        // After being idle the next message is sent immediately.
+        let now = start + Duration::from_millis(1100);
+        let actual = manager.conflate("sub1", "VOD.LSE", 4, message("2"), now);
+        assert_eq!(actual, Some(message("2")));
+    }
+
+    #[test]
+    fn should_conflate_topics_separately() {
+        let mut manager = ConflationManager::new();
+        let start = Instant::now();
+
+        assert!(manager
+            .conflate("sub1", "VOD.LSE", 1, message("1"), start)
+            .is_some());
+        assert!(manager
+            .conflate("sub1", "TSCO.LSE", 1, message("2"), start)
+            .is_some());
+        assert!(manager
+            .conflate("sub2", "VOD.LSE", 1, message("3"), start)
+            .is_some());
+    }
+}
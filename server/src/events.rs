@@ -2,13 +2,31 @@ use tokio::sync::mpsc::Sender;
 
 use common::messages::Message;
 
-use crate::authorization::AuthorizationSpec;
+use crate::authorization::Authorizations;
 
 pub enum ClientEvent {
-    OnConnect(String, String, String, Sender<ServerEvent>),
+    OnConnect(String, String, String, Vec<String>, Sender<ServerEvent>),
     OnClose(String),
     OnMessage(String, Message),
-    OnReset(Vec<AuthorizationSpec>),
+    OnReset(Authorizations),
+}
+
+impl ClientEvent {
+    /// The name of the event, for metrics.
+    pub fn name(&self) -> &'static str {
+        match self {
+            ClientEvent::OnConnect(..) => "connect",
+            ClientEvent::OnClose(_) => "close",
+            ClientEvent::OnMessage(_, message) => match message {
+                Message::MulticastData { .. } => "multicast_data",
+                Message::UnicastData { .. } => "unicast_data",
+                Message::SubscriptionRequest { .. } => "subscription_request",
+                Message::NotificationRequest { .. } => "notification_request",
+                _ => "message",
+            },
+            ClientEvent::OnReset(_) => "reset",
+        }
+    }
 }
 
 pub enum ServerEvent {
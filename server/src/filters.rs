@@ -0,0 +1,412 @@
+//! Subscription filters over data packet headers.
+//!
+//! A filter is an expression such as `exchange = 'LSE' AND size > 1000`.
+//! Comparisons are made between a header and a literal. When the literal is a
+//! number the header is compared numerically, otherwise the comparison is on
+//! the string value. Header names are matched without regard to case. A
+//! comparison with a header that is missing, or which is not a number when
+//! compared with one, is false.
+//!
+//! ```text
+//! expression := term ("OR" term)*
+//! term       := factor ("AND" factor)*
+//! factor     := "NOT" factor | "(" expression ")" | header operator literal
+//! operator   := "=" | "!=" | "<" | "<=" | ">" | ">="
+//! literal    := 'string' | number
+//! ```
+//!
+//! Filters come from clients, so their length and the nesting of `NOT` and
+//! parentheses are limited.
+
+use std::cmp::Ordering;
+use std::fmt;
+use std::str::FromStr;
+
+use common::messages::DataPacket;
+
+/// The longest filter, in bytes.
+pub const MAX_FILTER_LENGTH: usize = 1024;
+/// The deepest nesting of `NOT` and parentheses.
+pub const MAX_FILTER_DEPTH: usize = 32;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Operator {
+    Eq,
+    Ne,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+}
+
+impl Operator {
+    fn test(&self, ordering: Ordering) -> bool {
+        match self {
+            Operator::Eq => ordering == Ordering::Equal,
+            Operator::Ne => ordering != Ordering::Equal,
+            Operator::Lt => ordering == Ordering::Less,
+            Operator::Le => ordering != Ordering::Greater,
+            Operator::Gt => ordering == Ordering::Greater,
+            Operator::Ge => ordering != Ordering::Less,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Literal {
+    String(String),
+    Number(f64),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Expression {
+    Or(Box<Expression>, Box<Expression>),
+    And(Box<Expression>, Box<Expression>),
+    Not(Box<Expression>),
+    Compare(String, Operator, Literal),
+}
+
+impl Expression {
+    fn evaluate(&self, data_packet: &DataPacket) -> bool {
+        match self {
+            Expression::Or(lhs, rhs) => lhs.evaluate(data_packet) || rhs.evaluate(data_packet),
+            Expression::And(lhs, rhs) => lhs.evaluate(data_packet) && rhs.evaluate(data_packet),
+            Expression::Not(expression) => !expression.evaluate(data_packet),
+            Expression::Compare(name, operator, literal) => {
+                let Some(value) = data_packet.header_str(name.as_bytes()) else {
+                    return false;
+                };
+                let ordering = match literal {
+                    Literal::String(literal) => Some(value.cmp(literal.as_str())),
+                    Literal::Number(literal) => value
+                        .trim()
+                        .parse::<f64>()
+                        .ok()
+                        .and_then(|value| value.partial_cmp(literal)),
+                };
+                ordering.is_some_and(|ordering| operator.test(ordering))
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+    Identifier(String),
+    String(String),
+    Number(f64),
+    Operator(Operator),
+    And,
+    Or,
+    Not,
+    Open,
+    Close,
+}
+
+fn tokenize(text: &str) -> Result<Vec<Token>, String> {
+    let mut tokens = Vec::new();
+    let mut chars = text.chars().peekable();
+
+    while let Some(&c) = chars.peek() {
+        match c {
+            c if c.is_whitespace() => {
+                chars.next();
+            }
+            '(' => {
+                chars.next();
+                tokens.push(Token::Open);
+            }
+            ')' => {
+                chars.next();
+                tokens.push(Token::Close);
+            }
+            '=' => {
+                chars.next();
+                tokens.push(Token::Operator(Operator::Eq));
+            }
+            '!' => {
+                chars.next();
+                match chars.next() {
+                    Some('=') => tokens.push(Token::Operator(Operator::Ne)),
+                    _ => return Err("expected \"=\" after \"!\"".into()),
+                }
+            }
+            '<' | '>' => {
+                chars.next();
+                let is_equal = chars.next_if_eq(&'=').is_some();
+                tokens.push(Token::Operator(match (c, is_equal) {
+                    ('<', false) => Operator::Lt,
+                    ('<', true) => Operator::Le,
+                    ('>', false) => Operator::Gt,
+                    _ => Operator::Ge,
+                }));
+            }
+            '\'' => {
+                chars.next();
+                let mut value = String::new();
+                loop {
+                    match chars.next() {
+                        // A quote is escaped by doubling it.
+                        Some('\'') if chars.next_if_eq(&'\'').is_some() => value.push('\''),
+                        Some('\'') => break,
+                        Some(c) => value.push(c),
+                        None => return Err("unterminated string".into()),
+                    }
+                }
+                tokens.push(Token::String(value));
+            }
+            c if c.is_ascii_digit() || c == '-' || c == '.' => {
+                let mut value = String::new();
+                while let Some(c) =
+                    chars.next_if(|c| c.is_ascii_digit() || matches!(c, '-' | '.' | 'e' | 'E'))
+                {
+                    value.push(c);
+                }
+                let number = value
+                    .parse()
+                    .map_err(|_| format!("invalid number \"{value}\""))?;
+                tokens.push(Token::Number(number));
+            }
+            c if c.is_alphanumeric() || c == '_' => {
+                let mut value = String::new();
+                while let Some(c) =
+                    chars.next_if(|c| c.is_alphanumeric() || matches!(c, '_' | '-' | '.'))
+                {
+                    value.push(c);
+                }
+                tokens.push(match value.to_ascii_uppercase().as_str() {
+                    "AND" => Token::And,
+                    "OR" => Token::Or,
+                    "NOT" => Token::Not,
+                    _ => Token::Identifier(value),
+                });
+            }
+            c => return Err(format!("unexpected character \"{c}\"")),
+        }
+    }
+
+    Ok(tokens)
+}
+
+struct Parser {
+    tokens: Vec<Token>,
+    index: usize,
+    depth: usize,
+}
+
+impl Parser {
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.index)
+    }
+
+    fn next(&mut self) -> Option<Token> {
+        let token = self.tokens.get(self.index).cloned();
+        self.index += 1;
+        token
+    }
+
+    fn parse_expression(&mut self) -> Result<Expression, String> {
+        let mut expression = self.parse_term()?;
+        while self.peek() == Some(&Token::Or) {
+            self.next();
+            expression = Expression::Or(Box::new(expression), Box::new(self.parse_term()?));
+        }
+        Ok(expression)
+    }
+
+    fn parse_term(&mut self) -> Result<Expression, String> {
+        let mut expression = self.parse_factor()?;
+        while self.peek() == Some(&Token::And) {
+            self.next();
+            expression = Expression::And(Box::new(expression), Box::new(self.parse_factor()?));
+        }
+        Ok(expression)
+    }
+
+    /// Parse a nested factor or expression, unless it is nested too deeply.
+    fn parse_nested<T>(
+        &mut self,
+        parse: impl FnOnce(&mut Self) -> Result<T, String>,
+    ) -> Result<T, String> {
+        if self.depth >= MAX_FILTER_DEPTH {
+            return Err(format!("nested more than {MAX_FILTER_DEPTH} deep"));
+        }
+        self.depth += 1;
+        let result = parse(self);
+        self.depth -= 1;
+        result
+    }
+
+    fn parse_factor(&mut self) -> Result<Expression, String> {
+        match self.next() {
+            Some(Token::Not) => Ok(Expression::Not(Box::new(
+                self.parse_nested(Self::parse_factor)?,
+            ))),
+            Some(Token::Open) => {
+                let expression = self.parse_nested(Self::parse_expression)?;
+                match self.next() {
+                    Some(Token::Close) => Ok(expression),
+                    _ => Err("expected \")\"".into()),
+                }
+            }
+            Some(Token::Identifier(name)) => {
+                let Some(Token::Operator(operator)) = self.next() else {
+                    return Err(format!("expected an operator after \"{name}\""));
+                };
+                let literal = match self.next() {
+                    Some(Token::String(value)) => Literal::String(value),
+                    Some(Token::Number(value)) => Literal::Number(value),
+                    _ => return Err(format!("expected a literal after \"{name}\"")),
+                };
+                Ok(Expression::Compare(name, operator, literal))
+            }
+            Some(token) => Err(format!("unexpected {token:?}")),
+            None => Err("unexpected end of filter".into()),
+        }
+    }
+}
+
+/// A parsed subscription filter.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Filter {
+    text: String,
+    expression: Expression,
+}
+
+impl Filter {
+    pub fn matches(&self, data_packet: &DataPacket) -> bool {
+        self.expression.evaluate(data_packet)
+    }
+}
+
+impl FromStr for Filter {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s.len() > MAX_FILTER_LENGTH {
+            return Err(format!("longer than {MAX_FILTER_LENGTH} bytes"));
+        }
+        let mut parser = Parser {
+            tokens: tokenize(s)?,
+            index: 0,
+            depth: 0,
+        };
+        let expression = parser.parse_expression()?;
+        if let Some(token) = parser.peek() {
+            return Err(format!("unexpected {token:?}"));
+        }
+        Ok(Filter {
+            text: s.to_string(),
+            expression,
+        })
+    }
+}
+
+impl fmt::Display for Filter {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.text)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::{HashMap, HashSet};
+
+    use super::*;
+
+    fn data_packet(headers: &[(&str, &str)]) -> DataPacket {
+        DataPacket::new(
+            HashSet::new(),
+            headers
+                .iter()
+                .map(|(key, value)| (key.as_bytes().to_vec(), value.as_bytes().to_vec()))
+                .collect::<HashMap<_, _>>(),
+            Vec::new(),
+        )
+    }
+
+    fn matches(filter: &str, data_packet: &DataPacket) -> bool {
+        Filter::from_str(filter)
+            .expect("should parse")
+            .matches(data_packet)
+    }
+
+    #[test]
+    fn should_compare_strings_and_numbers() {
+        let packet = data_packet(&[("exchange", "LSE"), ("size", "1500")]);
+
+        assert!(matches("exchange = 'LSE'", &packet));
+        assert!(!matches("exchange != 'LSE'", &packet));
+        assert!(matches("size > 1000", &packet));
+        assert!(matches("size >= 1500", &packet));
+        assert!(!matches("size < 1000.5", &packet));
+        assert!(matches("size <= 1500", &packet));
+        assert!(matches("Exchange = 'LSE'", &packet));
+    }
+
+    #[test]
+    fn should_combine_expressions() {
+        let packet = data_packet(&[("exchange", "LSE"), ("size", "500")]);
+
+        assert!(!matches("exchange = 'LSE' AND size > 1000", &packet));
+        assert!(matches("exchange = 'LSE' or size > 1000", &packet));
+        assert!(matches("NOT (exchange = 'NYSE' OR size > 1000)", &packet));
+        assert!(matches(
+            "exchange = 'NYSE' OR exchange = 'LSE' AND size = 500",
+            &packet
+        ));
+    }
+
+    #[test]
+    fn should_not_match_missing_or_invalid_headers() {
+        let packet = data_packet(&[("size", "large")]);
+
+        assert!(!matches("exchange = 'LSE'", &packet));
+        assert!(!matches("exchange != 'LSE'", &packet));
+        assert!(!matches("size > 1000", &packet));
+    }
+
+    #[test]
+    fn should_handle_escaped_quotes() {
+        let packet = data_packet(&[("name", "O'Neil")]);
+
+        assert!(matches("name = 'O''Neil'", &packet));
+    }
+
+    #[test]
+    fn should_reject_invalid_filters() {
+        for text in [
+            "",
+            "exchange",
+            "exchange =",
+            "exchange = LSE",
+            "(exchange = 'LSE'",
+            "exchange = 'LSE",
+            "exchange = 'LSE' size > 1",
+            "exchange ! 'LSE'",
+        ] {
+            assert!(Filter::from_str(text).is_err(), "{text} should be invalid");
+        }
+    }
+
+    #[test]
+    fn should_limit_length_and_depth() {
+        let nested = |depth: usize| {
+            format!(
+                "{}exchange = 'LSE'{}",
+                "NOT (".repeat(depth),
+                ")".repeat(depth)
+            )
+        };
+
+        // Each level has a NOT and a parenthesis.
+        assert!(Filter::from_str(&nested(MAX_FILTER_DEPTH / 2)).is_ok());
+        assert!(Filter::from_str(&nested(MAX_FILTER_DEPTH / 2 + 1)).is_err());
+        assert!(Filter::from_str(&"NOT ".repeat(200_000)).is_err());
+        assert!(Filter::from_str(&"(".repeat(200_000)).is_err());
+
+        let long = vec!["size > 1"; MAX_FILTER_LENGTH / 8].join(" AND ");
+        assert!(long.len() > MAX_FILTER_LENGTH);
+        assert!(Filter::from_str(&long).is_err());
+    }
+}
@@ -1,18 +1,23 @@
+use std::collections::HashSet;
 use std::io;
 use std::sync::Arc;
 
 use tokio::sync::mpsc::{Receiver, Sender};
 use tokio::sync::Mutex;
+use tokio::time::MissedTickBehavior;
 
 use common::messages::Message;
 
 use crate::{
-    authorization::{AuthorizationManager, AuthorizationSpec},
+    authorization::{AuthorizationManager, Authorizations, Role},
     clients::ClientManager,
+    conflation::FLUSH_INTERVAL,
     events::{ClientEvent, ServerEvent},
+    limits::Limits,
+    metrics::METRICS,
     notifications::NotificationManager,
     publishing::PublisherManager,
-    subscriptions::SubscriptionManager,
+    subscriptions::{SubscriptionManager, SubscriptionRequest},
 };
 
 struct HubManager {
@@ -24,12 +29,12 @@ struct HubManager {
 }
 
 impl HubManager {
-    pub fn new(entitlement_manager: AuthorizationManager) -> Self {
+    pub fn new(entitlement_manager: AuthorizationManager, limits: &Limits) -> Self {
         HubManager {
             client_manager: ClientManager::new(),
-            subscription_manager: SubscriptionManager::new(),
-            notification_manager: NotificationManager::new(),
-            publisher_manager: PublisherManager::new(),
+            subscription_manager: SubscriptionManager::new(limits),
+            notification_manager: NotificationManager::new(limits),
+            publisher_manager: PublisherManager::new(limits),
             authorization_manager: entitlement_manager,
         }
     }
@@ -37,17 +42,81 @@ impl HubManager {
     pub async fn handle_event(&mut self, event: ClientEvent) -> io::Result<()> {
         match event {
             ClientEvent::OnMessage(id, msg) => self.handle_message(&id, msg).await,
-            ClientEvent::OnConnect(id, host, user, server_tx) => {
-                Ok(self.handle_connect(&id, host, user, server_tx))
+            ClientEvent::OnConnect(id, host, user, groups, server_tx) => {
+                Ok(self.handle_connect(&id, host, user, groups, server_tx))
             }
             ClientEvent::OnClose(id) => self.handle_close(&id).await,
-            ClientEvent::OnReset(specs) => Ok(self.handle_reset(specs)),
+            ClientEvent::OnReset(authorizations) => self.handle_reset(authorizations).await,
         }
     }
 
-    fn handle_reset(&mut self, specs: Vec<AuthorizationSpec>) {
+    fn has_pending(&self) -> bool {
+        self.publisher_manager.has_pending()
+    }
+
+    pub async fn handle_flush(&mut self) -> io::Result<()> {
+        self.publisher_manager
+            .flush(&self.client_manager, &self.subscription_manager)
+            .await
+    }
+
+    /// Replace the authorizations, revoking the subscriptions and
+    /// notifications which were authorized, but no longer are.
+    async fn handle_reset(&mut self, authorizations: Authorizations) -> io::Result<()> {
         log::debug!("Resetting authorizations");
-        self.authorization_manager.reset(specs);
+
+        // Keep the previous entitlements of the subscriptions to log changes.
+        let subscriptions = self.subscription_manager.subscriptions();
+        let previous: Vec<HashSet<i32>> = subscriptions
+            .iter()
+            .map(|(subscriber_id, topic)| self.subscriber_entitlements(subscriber_id, topic))
+            .collect();
+        let subscriptions_authorized = self
+            .subscription_manager
+            .authorized(&self.client_manager, &self.authorization_manager);
+        let notifications_authorized = self
+            .notification_manager
+            .authorized(&self.client_manager, &self.authorization_manager);
+
+        self.authorization_manager.reset(authorizations);
+
+        for ((subscriber_id, topic), previous) in subscriptions.iter().zip(previous) {
+            let current = self.subscriber_entitlements(subscriber_id, topic);
+            if current != previous {
+                let mut previous = Vec::from_iter(previous);
+                previous.sort();
+                let mut current = Vec::from_iter(current);
+                current.sort();
+                log::info!(
+                    "entitlements of {subscriber_id} for {topic} changed from {previous:?} to {current:?}"
+                );
+            }
+        }
+
+        self.subscription_manager
+            .revoke_unauthorized(
+                &subscriptions_authorized,
+                &self.client_manager,
+                &self.notification_manager,
+                &self.authorization_manager,
+            )
+            .await?;
+        self.notification_manager
+            .revoke_unauthorized(
+                &notifications_authorized,
+                &self.client_manager,
+                &self.authorization_manager,
+            )
+            .await
+    }
+
+    fn subscriber_entitlements(&self, subscriber_id: &str, topic: &str) -> HashSet<i32> {
+        match self.client_manager.get(subscriber_id) {
+            Some(subscriber) => {
+                subscriber.entitlements(&self.authorization_manager, topic, Role::Subscriber)
+            }
+            None => HashSet::new(),
+        }
     }
 
     fn handle_connect(
@@ -55,10 +124,11 @@ impl HubManager {
         client_id: &str,
         host: String,
         user: String,
+        groups: Vec<String>,
         server_tx: Sender<ServerEvent>,
     ) {
         self.client_manager
-            .handle_connect(client_id, host, user, server_tx)
+            .handle_connect(client_id, host, user, groups, server_tx)
     }
 
     async fn handle_close(&mut self, client_id: &str) -> io::Result<()> {
@@ -102,12 +172,21 @@ impl HubManager {
                     )
                     .await
             }
-            Message::SubscriptionRequest { topic, is_add } => {
+            Message::SubscriptionRequest {
+                topic,
+                is_add,
+                filter,
+                max_rate,
+            } => {
                 self.subscription_manager
                     .handle_subscription_request(
                         &client_id,
-                        topic,
-                        is_add,
+                        SubscriptionRequest {
+                            topic,
+                            is_add,
+                            filter,
+                            max_rate,
+                        },
                         &self.client_manager,
                         &self.notification_manager,
                     )
@@ -139,25 +218,49 @@ pub struct Hub {
 }
 
 impl Hub {
-    pub fn new(entitlement_manager: AuthorizationManager) -> Self {
+    pub fn new(entitlement_manager: AuthorizationManager, limits: &Limits) -> Self {
         Hub {
-            state: Arc::new(Mutex::new(HubManager::new(entitlement_manager))),
+            state: Arc::new(Mutex::new(HubManager::new(entitlement_manager, limits))),
         }
     }
     pub async fn run(
-        authorizations: Vec<AuthorizationSpec>,
+        authorizations: Authorizations,
+        limits: Limits,
         server_rx: Receiver<ClientEvent>,
     ) -> io::Result<()> {
-        let mut hub_runner = Self::new(AuthorizationManager::new(authorizations));
+        let mut hub_runner = Self::new(AuthorizationManager::new(authorizations), &limits);
         hub_runner.start(server_rx).await
     }
 
     async fn start(&mut self, mut server_rx: Receiver<ClientEvent>) -> io::Result<()> {
+        // Conflated data is flushed on a timer, which only runs while data is
+        // waiting.
+        let mut flush_interval = tokio::time::interval(FLUSH_INTERVAL);
+        flush_interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+
         loop {
-            let msg = server_rx.recv().await.unwrap();
-            let state = self.state.clone();
-            let mut state = state.lock().await;
-            state.handle_event(msg).await?
+            let has_pending = self.state.lock().await.has_pending();
+            tokio::select! {
+                msg = server_rx.recv() => {
+                    let msg = msg.unwrap();
+                    let _timer = METRICS
+                        .hub_event_seconds
+                        .with_label_values(&[msg.name()])
+                        .start_timer();
+                    let state = self.state.clone();
+                    let mut state = state.lock().await;
+                    state.handle_event(msg).await?
+                }
+                _ = flush_interval.tick(), if has_pending => {
+                    let _timer = METRICS
+                        .hub_event_seconds
+                        .with_label_values(&["flush"])
+                        .start_timer();
+                    let state = self.state.clone();
+                    let mut state = state.lock().await;
+                    state.handle_flush().await?
+                }
+            }
         }
     }
 }
@@ -1,50 +1,79 @@
 use std::io;
-use std::net::SocketAddr;
 use std::sync::Arc;
 
-use tokio::sync::mpsc::{self, Sender};
-use tokio::sync::RwLock;
+use tokio::sync::mpsc::{self, Receiver, Sender};
+use tokio::sync::watch;
+use tokio::time::Instant;
 
 use uuid::Uuid;
 
 use common::messages::Message;
 use common::MessageStream;
 
-use crate::authentication::AuthenticationManager;
+use crate::audit::{self, AuditEvent};
+use crate::authentication::{AuthenticationContext, AuthenticationManager, Identity};
 use crate::events::{ClientEvent, ServerEvent};
+use crate::limits::{ConnectionLimiter, UserPermit};
+use crate::metrics::METRICS;
 
-#[derive(Debug)]
 pub struct Interactor {
     pub id: String,
+    /// The number of messages from the hub waiting to be sent to the client.
+    queue_size: usize,
+    connection_limiter: Arc<ConnectionLimiter>,
 }
 
 impl Interactor {
-    pub fn new() -> Interactor {
+    pub fn new(queue_size: usize, connection_limiter: Arc<ConnectionLimiter>) -> Interactor {
         Interactor {
             id: Uuid::new_v4().into(),
+            queue_size,
+            connection_limiter,
         }
     }
 
     pub async fn run<'a>(
         &self,
         stream: &mut impl MessageStream,
-        addr: SocketAddr,
         hub: Sender<ClientEvent>,
-        authentication_manager: Arc<RwLock<AuthenticationManager>>,
+        authentication_manager: Arc<AuthenticationManager>,
+        authentication_context: AuthenticationContext,
+        deadline: Instant,
+        mut shutdown: watch::Receiver<bool>,
     ) -> io::Result<()> {
-        let (tx, mut rx) = mpsc::channel::<ServerEvent>(32);
+        let (tx, mut rx) = mpsc::channel::<ServerEvent>(self.queue_size);
 
-        let user = self.authenticate(stream, authentication_manager).await?;
+        let host = authentication_context.host.clone();
 
-        let host = match addr {
-            SocketAddr::V4(v4) => v4.ip().to_string(),
-            SocketAddr::V6(v6) => v6.ip().to_string(),
-        };
+        audit::emit(AuditEvent::Connect {
+            client_id: self.id.clone(),
+            host: host.clone(),
+        });
+
+        let (identity, _user_permit) = tokio::time::timeout_at(
+            deadline,
+            self.authenticate(stream, authentication_manager, &authentication_context),
+        )
+        .await
+        .map_err(|_| {
+            log::warn!("{} from {host} did not authenticate in time", self.id);
+            io::Error::new(io::ErrorKind::TimedOut, "authentication timed out")
+        })??;
+        let Identity { user, groups } = identity;
+
+        let _client = METRICS.connect(&authentication_context.listener, &user);
+        let _queue = METRICS.watch_queue(&self.id, &tx);
 
         // Inform the client
-        hub.send(ClientEvent::OnConnect(self.id.clone(), host, user, tx))
-            .await
-            .map_err(|e| io::Error::new(io::ErrorKind::Other, e))?;
+        hub.send(ClientEvent::OnConnect(
+            self.id.clone(),
+            host,
+            user,
+            groups,
+            tx,
+        ))
+        .await
+        .map_err(|e| io::Error::new(io::ErrorKind::Other, e))?;
 
         loop {
             tokio::select! {
@@ -54,34 +83,86 @@ impl Interactor {
                 }
                 // forward hub to client
                 result = rx.recv() => {
+                    if result.is_none() {
+                        // The hub dropped the client.
+                        log::debug!("Closing {} for the hub", self.id);
+                        return stream.close().await;
+                    }
                     self.forward_hub_to_client(result, stream).await
                 }
+                _ = shutdown.changed() => {
+                    return self.shut_down(stream, rx, &hub).await
+                }
             }?
         }
     }
 
+    /// Send the messages already queued for the client, tell it the server is
+    /// shutting down, and close the connection.
+    async fn shut_down(
+        &self,
+        stream: &mut impl MessageStream,
+        mut rx: Receiver<ServerEvent>,
+        hub: &Sender<ClientEvent>,
+    ) -> io::Result<()> {
+        log::debug!("Shutting down {}", self.id);
+
+        // Closing the channel stops the hub queueing more messages, while
+        // those already queued can still be received.
+        rx.close();
+        while let Some(event) = rx.recv().await {
+            self.forward_hub_to_client(Some(event), stream).await?;
+        }
+
+        stream
+            .write(&Message::Shutdown {
+                reason: "server shutting down".into(),
+            })
+            .await?;
+        stream.close().await?;
+
+        // The hub may already have stopped.
+        let _ = hub.send(ClientEvent::OnClose(self.id.clone())).await;
+
+        Ok(())
+    }
+
     async fn authenticate(
         &self,
         stream: &mut impl MessageStream,
-        authentication_manager: Arc<RwLock<AuthenticationManager>>,
-    ) -> io::Result<String> {
+        authentication_manager: Arc<AuthenticationManager>,
+        authentication_context: &AuthenticationContext,
+    ) -> io::Result<(Identity, UserPermit)> {
         // If successful, the authentication manager resolves the user for
         // authorization.
         // If unsuccessful an error will be returned and propagated up until
         // the connection is closed.
-        let user = authentication_manager
-            .read() // Acquire the lock.
-            .await
-            .authenticate(stream)
+        let identity = authentication_manager
+            .authenticate(stream, authentication_context)
             .await?;
 
+        // The user's connections are limited before the client is told it
+        // has connected.
+        let user_permit = match self.connection_limiter.acquire_user(&identity.user) {
+            Ok(user_permit) => user_permit,
+            Err(limit) => {
+                let message = limit.exceeded(&self.id, &identity.user);
+                stream.write(&message).await?;
+                stream.close().await?;
+                return Err(io::Error::other(format!(
+                    "too many connections for {}",
+                    identity.user
+                )));
+            }
+        };
+
         // The id is returned to the client.
         let response = Message::AuthenticationResponse {
             client_id: self.id.clone(),
         };
         stream.write(&response).await?;
 
-        Ok(user)
+        Ok((identity, user_permit))
     }
 
     async fn forward_client_to_hub(
@@ -91,6 +172,7 @@ impl Interactor {
     ) -> io::Result<()> {
         match result {
             Ok(message) => {
+                METRICS.received(&message);
                 hub.send(ClientEvent::OnMessage(self.id.clone(), message))
                     .await
                     .map_err(|e| io::Error::new(io::ErrorKind::Other, e))?;
@@ -116,6 +198,7 @@ impl Interactor {
                 let client_id = self.id.as_str();
                 log::debug!("Sent message to {client_id}: \"{message:?}\"");
                 stream.write(&message).await?;
+                METRICS.sent(&message);
             }
         }
 
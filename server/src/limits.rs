@@ -0,0 +1,440 @@
+//! Limits on connections, subscriptions, notifications and publishing.
+//!
+//! A client which exceeds a limit is sent a `LimitExceeded` message naming
+//! it. Requests over a limit are ignored, while connections over a limit are
+//! closed. Each violation is counted.
+
+use std::collections::HashMap;
+use std::io;
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+
+use common::messages::Message;
+
+use crate::metrics::METRICS;
+
+/// The limits, which are unlimited when not given.
+#[derive(Debug, Clone, Default, PartialEq, serde::Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Limits {
+    pub max_connections: Option<usize>,
+    pub max_connections_per_user: Option<usize>,
+    /// Only applies to clients connecting over IP.
+    pub max_connections_per_host: Option<usize>,
+    pub max_subscriptions_per_client: Option<usize>,
+    pub max_notifications_per_client: Option<usize>,
+    pub max_publish_messages_per_second: Option<u64>,
+    pub max_publish_bytes_per_second: Option<u64>,
+}
+
+impl Limits {
+    /// Set a limit by name, from a "<name>=<value>" option.
+    pub fn set(&mut self, option: &str) -> io::Result<()> {
+        let (name, value) = option.split_once('=').ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::Other,
+                format!("expected <limit>=<value>, found {option}"),
+            )
+        })?;
+        let invalid_value =
+            |_| io::Error::new(io::ErrorKind::Other, format!("invalid value for {name}"));
+        match name.parse::<Limit>()? {
+            Limit::Connections => {
+                self.max_connections = Some(value.parse().map_err(invalid_value)?)
+            }
+            Limit::ConnectionsPerUser => {
+                self.max_connections_per_user = Some(value.parse().map_err(invalid_value)?)
+            }
+            Limit::ConnectionsPerHost => {
+                self.max_connections_per_host = Some(value.parse().map_err(invalid_value)?)
+            }
+            Limit::Subscriptions => {
+                self.max_subscriptions_per_client = Some(value.parse().map_err(invalid_value)?)
+            }
+            Limit::Notifications => {
+                self.max_notifications_per_client = Some(value.parse().map_err(invalid_value)?)
+            }
+            Limit::PublishMessages => {
+                self.max_publish_messages_per_second = Some(value.parse().map_err(invalid_value)?)
+            }
+            Limit::PublishBytes => {
+                self.max_publish_bytes_per_second = Some(value.parse().map_err(invalid_value)?)
+            }
+        }
+        Ok(())
+    }
+
+    /// Take the limits which are not set from the others.
+    pub fn or(self, others: Limits) -> Limits {
+        Limits {
+            max_connections: self.max_connections.or(others.max_connections),
+            max_connections_per_user: self
+                .max_connections_per_user
+                .or(others.max_connections_per_user),
+            max_connections_per_host: self
+                .max_connections_per_host
+                .or(others.max_connections_per_host),
+            max_subscriptions_per_client: self
+                .max_subscriptions_per_client
+                .or(others.max_subscriptions_per_client),
+            max_notifications_per_client: self
+                .max_notifications_per_client
+                .or(others.max_notifications_per_client),
+            max_publish_messages_per_second: self
+                .max_publish_messages_per_second
+                .or(others.max_publish_messages_per_second),
+            max_publish_bytes_per_second: self
+                .max_publish_bytes_per_second
+                .or(others.max_publish_bytes_per_second),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Limit {
+    Connections,
+    ConnectionsPerUser,
+    ConnectionsPerHost,
+    Subscriptions,
+    Notifications,
+    PublishMessages,
+    PublishBytes,
+}
+
+impl Limit {
+    pub const ALL: [Limit; 7] = [
+        Limit::Connections,
+        Limit::ConnectionsPerUser,
+        Limit::ConnectionsPerHost,
+        Limit::Subscriptions,
+        Limit::Notifications,
+        Limit::PublishMessages,
+        Limit::PublishBytes,
+    ];
+
+    /// The name of the setting, which is also sent to the client.
+    pub fn name(&self) -> &'static str {
+        match self {
+            Limit::Connections => "max_connections",
+            Limit::ConnectionsPerUser => "max_connections_per_user",
+            Limit::ConnectionsPerHost => "max_connections_per_host",
+            Limit::Subscriptions => "max_subscriptions_per_client",
+            Limit::Notifications => "max_notifications_per_client",
+            Limit::PublishMessages => "max_publish_messages_per_second",
+            Limit::PublishBytes => "max_publish_bytes_per_second",
+        }
+    }
+
+    /// Count a violation of the limit, and make the message for the client.
+    pub fn exceeded(self, client: &str, detail: &str) -> Message {
+        let violations = METRICS.limit_violations.with_label_values(&[self.name()]);
+        violations.inc();
+        log::warn!(
+            "{client} exceeded {} ({} violations): {detail}",
+            self.name(),
+            violations.get()
+        );
+        Message::LimitExceeded {
+            limit: self.name().into(),
+            detail: detail.into(),
+        }
+    }
+}
+
+impl std::str::FromStr for Limit {
+    type Err = io::Error;
+
+    fn from_str(s: &str) -> io::Result<Self> {
+        Limit::ALL
+            .into_iter()
+            .find(|limit| limit.name() == s)
+            .ok_or_else(|| io::Error::new(io::ErrorKind::Other, format!("unknown limit {s}")))
+    }
+}
+
+/// Counts the connections to every listener, as they are accepted, and the
+/// connections of each user, as they authenticate.
+pub struct ConnectionLimiter {
+    max_connections: Option<usize>,
+    max_connections_per_host: Option<usize>,
+    max_connections_per_user: Option<usize>,
+    counts: Mutex<ConnectionCounts>,
+}
+
+#[derive(Default)]
+struct ConnectionCounts {
+    total: usize,
+    by_host: HashMap<String, usize>,
+    by_user: HashMap<String, usize>,
+}
+
+/// Stop counting a connection for a host or user.
+fn release(counts: &mut HashMap<String, usize>, key: &str) {
+    if let Some(count) = counts.get_mut(key) {
+        *count -= 1;
+        if *count == 0 {
+            counts.remove(key);
+        }
+    }
+}
+
+impl ConnectionLimiter {
+    pub fn new(limits: &Limits) -> Self {
+        ConnectionLimiter {
+            max_connections: limits.max_connections,
+            max_connections_per_host: limits.max_connections_per_host,
+            max_connections_per_user: limits.max_connections_per_user,
+            counts: Mutex::new(ConnectionCounts::default()),
+        }
+    }
+
+    /// Count a connection from the host, which is an IP address or the path
+    /// of a Unix domain socket. The connection is counted until the permit
+    /// is dropped.
+    pub fn acquire(self: &Arc<Self>, host: &str, is_ip: bool) -> Result<ConnectionPermit, Limit> {
+        let mut counts = self.counts.lock().unwrap();
+        if self
+            .max_connections
+            .is_some_and(|max_connections| counts.total >= max_connections)
+        {
+            return Err(Limit::Connections);
+        }
+        let host = is_ip.then(|| host.to_string());
+        if let Some(host) = &host {
+            let count = counts.by_host.get(host).copied().unwrap_or(0);
+            if self
+                .max_connections_per_host
+                .is_some_and(|max_connections| count >= max_connections)
+            {
+                return Err(Limit::ConnectionsPerHost);
+            }
+            counts.by_host.insert(host.clone(), count + 1);
+        }
+        counts.total += 1;
+
+        Ok(ConnectionPermit {
+            limiter: self.clone(),
+            host,
+        })
+    }
+
+    /// Count a connection as the user, once it has authenticated, and before
+    /// the client is told. The connection is counted until the permit is
+    /// dropped.
+    pub fn acquire_user(self: &Arc<Self>, user: &str) -> Result<UserPermit, Limit> {
+        let mut counts = self.counts.lock().unwrap();
+        let count = counts.by_user.get(user).copied().unwrap_or(0);
+        if self
+            .max_connections_per_user
+            .is_some_and(|max_connections| count >= max_connections)
+        {
+            return Err(Limit::ConnectionsPerUser);
+        }
+        counts.by_user.insert(user.into(), count + 1);
+
+        Ok(UserPermit {
+            limiter: self.clone(),
+            user: user.into(),
+        })
+    }
+}
+
+pub struct ConnectionPermit {
+    limiter: Arc<ConnectionLimiter>,
+    host: Option<String>,
+}
+
+impl Drop for ConnectionPermit {
+    fn drop(&mut self) {
+        let mut counts = self.limiter.counts.lock().unwrap();
+        counts.total -= 1;
+        if let Some(host) = &self.host {
+            release(&mut counts.by_host, host);
+        }
+    }
+}
+
+pub struct UserPermit {
+    limiter: Arc<ConnectionLimiter>,
+    user: String,
+}
+
+impl Drop for UserPermit {
+    fn drop(&mut self) {
+        let mut counts = self.limiter.counts.lock().unwrap();
+        release(&mut counts.by_user, &self.user);
+    }
+}
+
+/// Limits the messages and bytes a user publishes each second. Each allowance
+/// is refilled continuously, so a burst of up to a second's worth is allowed.
+/// A message of more than a second's worth of bytes is allowed when the
+/// allowance is full, which it then leaves in debt.
+pub struct RateLimiter {
+    messages: Option<(u64, f64)>,
+    bytes: Option<(u64, f64)>,
+    updated: Instant,
+}
+
+impl RateLimiter {
+    pub fn new(limits: &Limits, now: Instant) -> Self {
+        RateLimiter {
+            messages: limits
+                .max_publish_messages_per_second
+                .map(|rate| (rate, rate as f64)),
+            bytes: limits
+                .max_publish_bytes_per_second
+                .map(|rate| (rate, rate as f64)),
+            updated: now,
+        }
+    }
+
+    fn refill(&mut self, now: Instant) {
+        let elapsed = now.saturating_duration_since(self.updated).as_secs_f64();
+        self.updated = now;
+        for (rate, allowance) in [&mut self.messages, &mut self.bytes].into_iter().flatten() {
+            *allowance = (*allowance + elapsed * *rate as f64).min(*rate as f64);
+        }
+    }
+
+    /// Whether the allowances are full, when the limiter is no different to
+    /// a new one.
+    pub fn is_full(&mut self, now: Instant) -> bool {
+        self.refill(now);
+        [&self.messages, &self.bytes]
+            .into_iter()
+            .flatten()
+            .all(|(rate, allowance)| *allowance >= *rate as f64)
+    }
+
+    /// Take a message of the given size from the allowance, unless it would
+    /// exceed a limit.
+    pub fn take(&mut self, bytes: usize, now: Instant) -> Result<(), Limit> {
+        self.refill(now);
+
+        if let Some((_, allowance)) = &self.messages {
+            if *allowance < 1.0 {
+                return Err(Limit::PublishMessages);
+            }
+        }
+        if let Some((rate, allowance)) = &self.bytes {
+            if *allowance < bytes as f64 && *allowance < *rate as f64 {
+                return Err(Limit::PublishBytes);
+            }
+        }
+
+        if let Some((_, allowance)) = &mut self.messages {
+            *allowance -= 1.0;
+        }
+        if let Some((_, allowance)) = &mut self.bytes {
+            *allowance -= bytes as f64;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::Duration;
+
+    use super::*;
+
+    #[test]
+    fn should_limit_connections_per_host() {
+        let limiter = Arc::new(ConnectionLimiter::new(&Limits {
+            max_connections: Some(3),
+            max_connections_per_host: Some(2),
+            ..Default::default()
+        }));
+
+        let first = limiter.acquire("10.0.0.1", true).unwrap();
+        let _second = limiter.acquire("10.0.0.1", true).unwrap();
+        assert_eq!(
+            limiter.acquire("10.0.0.1", true).err(),
+            Some(Limit::ConnectionsPerHost)
+        );
+        // Unix domain sockets are not limited by host.
+        let _third = limiter.acquire("/run/squawkbus.sock", false).unwrap();
+        assert_eq!(
+            limiter.acquire("10.0.0.2", true).err(),
+            Some(Limit::Connections)
+        );
+
+        drop(first);
+        assert!(limiter.acquire("10.0.0.1", true).is_ok());
+    }
+
+    #[test]
+    fn should_limit_connections_per_user() {
+        let limiter = Arc::new(ConnectionLimiter::new(&Limits {
+            max_connections_per_user: Some(1),
+            ..Default::default()
+        }));
+
+        let first = limiter.acquire_user("tom").unwrap();
+        assert_eq!(
+            limiter.acquire_user("tom").err(),
+            Some(Limit::ConnectionsPerUser)
+        );
+        let _dick = limiter.acquire_user("dick").unwrap();
+
+        drop(first);
+        assert!(limiter.acquire_user("tom").is_ok());
+    }
+
+    #[test]
+    fn should_limit_publish_rate() {
+        let start = Instant::now();
+        let mut limiter = RateLimiter::new(
+            &Limits {
+                max_publish_messages_per_second: Some(2),
+                max_publish_bytes_per_second: Some(100),
+                ..Default::default()
+            },
+            start,
+        );
+
+        assert!(limiter.take(10, start).is_ok());
+        assert_eq!(limiter.take(95, start), Err(Limit::PublishBytes));
+        assert!(limiter.take(10, start).is_ok());
+        assert_eq!(limiter.take(10, start), Err(Limit::PublishMessages));
+
+        // Half a second restores one message.
+        let now = start + Duration::from_millis(500);
+        assert!(limiter.take(10, now).is_ok());
+        assert_eq!(limiter.take(10, now), Err(Limit::PublishMessages));
+    }
+
+    #[test]
+    fn should_allow_a_message_larger_than_the_rate() {
+        let start = Instant::now();
+        let mut limiter = RateLimiter::new(
+            &Limits {
+                max_publish_bytes_per_second: Some(100),
+                ..Default::default()
+            },
+            start,
+        );
+
+        // A full allowance lets a large message through, leaving it in debt.
+        assert!(limiter.take(250, start).is_ok());
+        assert!(!limiter.is_full(start));
+        let now = start + Duration::from_secs(2);
+        assert_eq!(limiter.take(60, now), Err(Limit::PublishBytes));
+        assert!(limiter.take(50, now).is_ok());
+        assert_eq!(limiter.take(250, now), Err(Limit::PublishBytes));
+
+        let now = start + Duration::from_secs(3);
+        assert!(limiter.is_full(now));
+        assert!(limiter.take(250, now).is_ok());
+    }
+
+    #[test]
+    fn should_set_limits_by_name() {
+        let mut limits = Limits::default();
+        limits.set("max_connections_per_user=4").unwrap();
+        assert_eq!(limits.max_connections_per_user, Some(4));
+        assert!(limits.set("max_connections_per_user=four").is_err());
+        assert!(limits.set("max_users=4").is_err());
+    }
+}
@@ -0,0 +1,451 @@
+//! Listeners accept connections from clients, and start an interactor for
+//! each.
+
+use std::fmt;
+use std::fs;
+use std::io;
+use std::net::{SocketAddr, ToSocketAddrs};
+use std::os::unix::fs::{FileTypeExt, PermissionsExt};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::net::{TcpListener, TcpStream, UnixListener, UnixStream};
+use tokio::sync::mpsc::Sender;
+use tokio::sync::watch;
+use tokio::task::JoinSet;
+use tokio::time::Instant;
+use tokio_rustls::TlsAcceptor;
+use tokio_tungstenite::tungstenite::handshake::server::{
+    Callback, ErrorResponse, Request, Response,
+};
+use tokio_tungstenite::tungstenite::http::header::{HeaderValue, SEC_WEBSOCKET_PROTOCOL};
+
+use common::io::message_socket;
+use common::messages::Message;
+use common::{
+    MessageSocket, MessageStream, MessageWebSocket, WebSocketEncoding, JSON_SUB_PROTOCOL,
+};
+
+use crate::authentication::{AuthenticationContext, AuthenticationManager};
+use crate::events::ClientEvent;
+use crate::interactor::Interactor;
+use crate::limits::{ConnectionLimiter, ConnectionPermit};
+use crate::options::{ListenerOption, Protocol};
+use crate::tls::TlsConfig;
+
+/// How long to wait after failing to accept a connection, which is likely to
+/// fail again straight away when it is for want of file descriptors.
+pub const ACCEPT_RETRY_DELAY: Duration = Duration::from_millis(100);
+
+/// Where a listener accepts connections.
+enum Address {
+    Inet(SocketAddr),
+    Unix(PathBuf),
+}
+
+impl fmt::Display for Address {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Address::Inet(addr) => addr.fmt(f),
+            Address::Unix(path) => path.display().fmt(f),
+        }
+    }
+}
+
+pub struct Listener {
+    address: Address,
+    protocol: Protocol,
+    /// The permissions of a Unix domain socket.
+    permissions: Option<u32>,
+    tls_config: Option<Arc<TlsConfig>>,
+    /// The authentication methods clients may use, or all when empty.
+    allowed_methods: Vec<String>,
+    client_tx: Sender<ClientEvent>,
+    authentication_manager: Arc<AuthenticationManager>,
+    connection_limiter: Arc<ConnectionLimiter>,
+    client_queue_size: usize,
+    /// How long a client has to complete the TLS handshake and authenticate.
+    handshake_timeout: Duration,
+}
+
+impl Listener {
+    pub fn new(
+        option: &ListenerOption,
+        tls_config: Option<Arc<TlsConfig>>,
+        client_tx: Sender<ClientEvent>,
+        authentication_manager: Arc<AuthenticationManager>,
+        connection_limiter: Arc<ConnectionLimiter>,
+        client_queue_size: usize,
+        handshake_timeout: Duration,
+    ) -> io::Result<Self> {
+        let address = match option.protocol {
+            Protocol::Unix => Address::Unix(option.endpoint.clone().into()),
+            Protocol::Socket | Protocol::WebSocket => Address::Inet(resolve(&option.endpoint)?),
+        };
+        Ok(Listener {
+            address,
+            protocol: option.protocol,
+            permissions: option.permissions()?,
+            tls_config,
+            allowed_methods: option.methods.clone(),
+            client_tx,
+            authentication_manager,
+            connection_limiter,
+            client_queue_size,
+            handshake_timeout,
+        })
+    }
+
+    /// Accept connections until the server shuts down, then wait for the
+    /// clients to be closed.
+    pub async fn run(self: Arc<Self>, mut shutdown: watch::Receiver<bool>) -> io::Result<()> {
+        log::info!(
+            "Listening on {} for {}{}{}",
+            &self.address,
+            match self.protocol {
+                Protocol::WebSocket => "web sockets",
+                Protocol::Socket => "sockets",
+                Protocol::Unix => "Unix domain sockets",
+            },
+            match self.tls_config {
+                Some(_) => " using TLS",
+                None => "",
+            },
+            match self.allowed_methods.is_empty() {
+                true => String::new(),
+                false => format!(" with {}", self.allowed_methods.join(", ")),
+            }
+        );
+
+        let mut interactors = JoinSet::new();
+
+        match &self.address {
+            Address::Inet(addr) => {
+                let listener = TcpListener::bind(addr).await?;
+                loop {
+                    tokio::select! {
+                        // Wait for a client to connect.
+                        result = listener.accept() => {
+                            let Some((stream, addr)) = self.accepted(result).await else {
+                                continue;
+                            };
+                            let permit = match self.admit(&addr.ip().to_string(), true) {
+                                Ok(permit) => permit,
+                                Err(message) => {
+                                    if let Ok(stream) = stream.into_std() {
+                                        self.refuse(stream, &message);
+                                    }
+                                    continue;
+                                }
+                            };
+
+                            // Start an interactor.
+                            let listener = self.clone();
+                            let shutdown = shutdown.clone();
+                            interactors.spawn(async move {
+                                let result = listener.start_interactor(stream, addr, shutdown).await;
+                                drop(permit);
+                                log_exit(result)
+                            });
+                        }
+                        Some(_) = interactors.join_next() => {}
+                        _ = shutdown.changed() => break
+                    }
+                }
+            }
+            Address::Unix(path) => {
+                let listener = bind_unix(path, self.permissions)?;
+                loop {
+                    tokio::select! {
+                        result = listener.accept() => {
+                            let Some((stream, _)) = self.accepted(result).await else {
+                                continue;
+                            };
+                            let host = path.display().to_string();
+                            let permit = match self.admit(&host, false) {
+                                Ok(permit) => permit,
+                                Err(message) => {
+                                    if let Ok(stream) = stream.into_std() {
+                                        self.refuse(stream, &message);
+                                    }
+                                    continue;
+                                }
+                            };
+
+                            let listener = self.clone();
+                            let path = path.clone();
+                            let shutdown = shutdown.clone();
+                            interactors.spawn(async move {
+                                let result = listener
+                                    .start_unix_interactor(stream, &path, shutdown)
+                                    .await;
+                                drop(permit);
+                                log_exit(result)
+                            });
+                        }
+                        Some(_) = interactors.join_next() => {}
+                        _ = shutdown.changed() => break
+                    }
+                }
+                fs::remove_file(path)?;
+            }
+        }
+
+        log::info!("Stopped listening on {}", &self.address);
+        while interactors.join_next().await.is_some() {}
+
+        Ok(())
+    }
+
+    /// The accepted connection, or none when accepting failed. Failures such
+    /// as running out of file descriptors pass, so are logged, and accepting
+    /// continues after a pause.
+    async fn accepted<T>(&self, result: io::Result<T>) -> Option<T> {
+        match result {
+            Ok(accepted) => Some(accepted),
+            Err(error) => {
+                log::warn!("Failed to accept a connection on {}: {error}", self.address);
+                tokio::time::sleep(ACCEPT_RETRY_DELAY).await;
+                None
+            }
+        }
+    }
+
+    /// Take a connection permit for a client which has just connected, before
+    /// any handshake, or give the reason it is refused.
+    fn admit(&self, host: &str, is_ip: bool) -> Result<ConnectionPermit, Message> {
+        self.connection_limiter
+            .acquire(host, is_ip)
+            .map_err(|limit| limit.exceeded(&format!("connection from {host}"), host))
+    }
+
+    /// Close a refused connection straight away. Where the client speaks the
+    /// protocol directly it is first sent the reason, as far as that can be
+    /// done without waiting.
+    fn refuse(&self, mut stream: impl std::io::Write, message: &Message) {
+        if self.tls_config.is_none() && self.protocol != Protocol::WebSocket {
+            if let Ok(frame) = message_socket::frame(message) {
+                let _ = stream.write(&frame);
+            }
+        }
+    }
+
+    async fn start_unix_interactor(
+        &self,
+        stream: UnixStream,
+        path: &Path,
+        shutdown: watch::Receiver<bool>,
+    ) -> io::Result<()> {
+        let deadline = Instant::now() + self.handshake_timeout;
+        let authentication_context = AuthenticationContext {
+            host: path.display().to_string(),
+            peer_credentials: Some(stream.peer_cred()?),
+            ..self.authentication_context()
+        };
+
+        println!("accepting unix socket connection on {}", path.display());
+        let stream = MessageSocket::new(stream);
+        self.serve(stream, authentication_context, deadline, shutdown)
+            .await
+    }
+
+    async fn start_interactor(
+        &self,
+        stream: TcpStream,
+        addr: SocketAddr,
+        shutdown: watch::Receiver<bool>,
+    ) -> io::Result<()> {
+        let deadline = Instant::now() + self.handshake_timeout;
+        let authentication_context = AuthenticationContext {
+            host: addr.ip().to_string(),
+            ..self.authentication_context()
+        };
+
+        // The acceptor is taken for each connection, as it may be reloaded.
+        let tls_acceptor: Option<TlsAcceptor> = self
+            .tls_config
+            .as_ref()
+            .map(|tls_config| tls_config.acceptor());
+
+        match tls_acceptor {
+            Some(acceptor) => {
+                let stream = handshake(deadline, acceptor.accept(stream)).await?;
+                // The client certificate has been verified by the handshake.
+                let authentication_context = AuthenticationContext {
+                    peer_certificate: stream
+                        .get_ref()
+                        .1
+                        .peer_certificates()
+                        .and_then(|certificates| certificates.first())
+                        .map(|certificate| certificate.clone().into_owned()),
+                    ..authentication_context
+                };
+                match self.protocol {
+                    Protocol::WebSocket => {
+                        println!("accepting web socket connection on {} over TLS", addr);
+                        let stream = handshake(deadline, accept_web_socket(stream)).await?;
+                        self.serve(stream, authentication_context, deadline, shutdown)
+                            .await
+                    }
+                    Protocol::Socket | Protocol::Unix => {
+                        println!("accepting socket connection on {} over TLS", addr);
+                        let stream = MessageSocket::new(stream);
+                        self.serve(stream, authentication_context, deadline, shutdown)
+                            .await
+                    }
+                }
+            }
+            None => match self.protocol {
+                Protocol::WebSocket => {
+                    println!("accepting web socket connection on {}", addr);
+                    let stream = handshake(deadline, accept_web_socket(stream)).await?;
+                    self.serve(stream, authentication_context, deadline, shutdown)
+                        .await
+                }
+                Protocol::Socket | Protocol::Unix => {
+                    println!("accepting socket connection on {}", addr);
+                    let stream = MessageSocket::new(stream);
+                    self.serve(stream, authentication_context, deadline, shutdown)
+                        .await
+                }
+            },
+        }
+    }
+
+    /// The context for a new client, before the connection is known.
+    fn authentication_context(&self) -> AuthenticationContext {
+        AuthenticationContext {
+            listener: self.address.to_string(),
+            allowed_methods: self.allowed_methods.clone(),
+            ..Default::default()
+        }
+    }
+
+    /// Run an interactor for the client, which must authenticate by the
+    /// deadline.
+    async fn serve(
+        &self,
+        mut stream: impl MessageStream,
+        authentication_context: AuthenticationContext,
+        deadline: Instant,
+        shutdown: watch::Receiver<bool>,
+    ) -> io::Result<()> {
+        let interactor = Interactor::new(self.client_queue_size, self.connection_limiter.clone());
+        let authentication_context = AuthenticationContext {
+            client_id: interactor.id.clone(),
+            ..authentication_context
+        };
+        interactor
+            .run(
+                &mut stream,
+                self.client_tx.clone(),
+                self.authentication_manager.clone(),
+                authentication_context,
+                deadline,
+                shutdown,
+            )
+            .await
+    }
+}
+
+/// Complete a step of the handshake by the deadline.
+async fn handshake<T>(
+    deadline: Instant,
+    future: impl std::future::Future<Output = io::Result<T>>,
+) -> io::Result<T> {
+    tokio::time::timeout_at(deadline, future)
+        .await
+        .map_err(|_| io::Error::new(io::ErrorKind::TimedOut, "handshake timed out"))?
+}
+
+fn log_exit(result: io::Result<()>) {
+    match result {
+        Ok(()) => log::debug!("Client exited normally"),
+        Err(e) => {
+            if e.kind() == io::ErrorKind::UnexpectedEof {
+                log::debug!("Client closed connection")
+            } else {
+                log::error!("Client exited with {}", e)
+            }
+        }
+    }
+}
+
+/// Bind a Unix domain socket. A socket left by a previous run is removed, but
+/// any other file at the path is an error.
+fn bind_unix(path: &Path, permissions: Option<u32>) -> io::Result<UnixListener> {
+    if let Ok(metadata) = fs::symlink_metadata(path) {
+        if metadata.file_type().is_socket() {
+            fs::remove_file(path)?;
+        }
+    }
+    let listener = UnixListener::bind(path)?;
+    if let Some(mode) = permissions {
+        fs::set_permissions(path, fs::Permissions::from_mode(mode))?;
+    }
+    Ok(listener)
+}
+
+/// Resolve an endpoint to the address to bind.
+pub fn resolve(endpoint: &str) -> io::Result<SocketAddr> {
+    endpoint
+        .to_socket_addrs()?
+        .next()
+        .ok_or_else(|| io::Error::from(io::ErrorKind::AddrNotAvailable))
+}
+
+/// Accept a web socket connection, using the JSON encoding if the client
+/// requested it as a sub-protocol.
+async fn accept_web_socket<S>(stream: S) -> io::Result<MessageWebSocket<S>>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let mut encoding = WebSocketEncoding::Binary;
+    let callback = SubProtocol {
+        encoding: &mut encoding,
+    };
+
+    let stream = tokio_tungstenite::accept_hdr_async(stream, callback)
+        .await
+        .map_err(|e| {
+            io::Error::new(
+                io::ErrorKind::Other,
+                format!("failed to accept websocket: {}", e),
+            )
+        })?;
+
+    log::debug!("accepted web socket using {:?} encoding", encoding);
+
+    Ok(MessageWebSocket::with_encoding(stream, encoding))
+}
+
+/// Chooses the encoding of a web socket from the sub-protocols the client
+/// requested.
+struct SubProtocol<'a> {
+    encoding: &'a mut WebSocketEncoding,
+}
+
+impl Callback for SubProtocol<'_> {
+    fn on_request(
+        self,
+        request: &Request,
+        mut response: Response,
+    ) -> Result<Response, ErrorResponse> {
+        if let Some(sub_protocols) = request
+            .headers()
+            .get(SEC_WEBSOCKET_PROTOCOL)
+            .and_then(|value| value.to_str().ok())
+        {
+            *self.encoding = WebSocketEncoding::from_sub_protocols(sub_protocols);
+            if *self.encoding == WebSocketEncoding::Json {
+                response.headers_mut().insert(
+                    SEC_WEBSOCKET_PROTOCOL,
+                    HeaderValue::from_static(JSON_SUB_PROTOCOL),
+                );
+            }
+        }
+        Ok(response)
+    }
+}
@@ -1,47 +1,64 @@
 //! A real time message bus.
 
+use std::collections::HashSet;
 use std::io;
-use std::net::{SocketAddr, ToSocketAddrs};
 use std::path::PathBuf;
 use std::sync::Arc;
 
-use tokio::net::{TcpListener, TcpStream};
+use tokio::net::TcpListener;
 use tokio::signal::unix::{signal, SignalKind};
 use tokio::sync::mpsc::{self, Sender};
-use tokio::sync::RwLock;
+use tokio::sync::watch;
 use tokio::task::JoinSet;
-use tokio_rustls::TlsAcceptor;
 
-use common::{MessageSocket, MessageWebSocket};
+use common::scram::StoredCredentials;
+
+mod audit;
 
 mod authentication;
 use authentication::AuthenticationManager;
 
 mod authorization;
-use authorization::{load_authorizations, AuthorizationSpec};
+use authorization::load_authorizations;
 
 mod clients;
 
+mod conflation;
+
 mod events;
 use events::ClientEvent;
 
+mod filters;
+
 mod hub;
 use hub::Hub;
 
 mod interactor;
-use interactor::Interactor;
+
+mod limits;
+use limits::ConnectionLimiter;
+
+mod listener;
+use listener::Listener;
+
+mod metrics;
 
 mod options;
-use options::Options;
+use options::{Options, Protocol};
 
 mod notifications;
 
 mod publishing;
 
+mod settings;
+
 mod subscriptions;
 
 mod tls;
-use tls::create_acceptor;
+use tls::TlsConfig;
+
+mod watcher;
+use watcher::{ConfigChange, ConfigWatcher};
 
 /// The server starts by creating a `hub` task to process messages. It then
 /// listens for client connections. When a client connects an interactor is
@@ -53,232 +70,279 @@ async fn main() -> io::Result<()> {
     // Command line options.
     let options = Options::load()?;
 
+    if let Some(user) = &options.scram_credentials {
+        return print_scram_credentials(user);
+    }
+
+    if options.check_config {
+        return check_config(&options);
+    }
+
+    if let Some(audit) = &options.audit {
+        audit::start(audit)?;
+    }
+
     let authorizations =
         load_authorizations(&options.authorizations_file, &options.authorizations)?;
-    let authentication_manager = Arc::new(RwLock::new(AuthenticationManager::new(
+    let authentication_manager = Arc::new(AuthenticationManager::new(
         &options.authentication,
-    )?));
+        &options.lockout,
+    )?);
 
     // Make the channel for the client-to-server communication.
-    let (client_tx, server_rx) = mpsc::channel::<ClientEvent>(32);
-
-    let mut join_set = JoinSet::new();
+    let (client_tx, server_rx) = mpsc::channel::<ClientEvent>(options.tuning.hub_queue_size);
 
     // Start the hub message processor. Note that is takes the receive end of
     // the mpsc channel.
-    join_set.spawn(async move { Hub::run(authorizations, server_rx).await });
+    let limits = options.limits.clone();
+    tokio::spawn(async move { Hub::run(authorizations, limits, server_rx).await });
+
+    // Connections are counted across the listeners.
+    let connection_limiter = Arc::new(ConnectionLimiter::new(&options.limits));
+
+    // Listeners without certificates of their own share the server's.
+    let tls_config = match options.tls.is_empty() {
+        true => None,
+        false => Some(Arc::new(TlsConfig::new(
+            options.tls.clone(),
+            options.tls_client_auth.clone(),
+        )?)),
+    };
+    let mut tls_configs: Vec<Arc<TlsConfig>> = tls_config.iter().cloned().collect();
+
+    let mut listeners = Vec::new();
+    for option in &options.listeners {
+        let listener_tls_config = match (option.tls, option.certificates.is_empty()) {
+            (false, _) => None,
+            (true, true) => tls_config.clone(),
+            (true, false) => {
+                let listener_tls_config = Arc::new(TlsConfig::new(
+                    option.certificates.clone(),
+                    options.tls_client_auth.clone(),
+                )?);
+                tls_configs.push(listener_tls_config.clone());
+                Some(listener_tls_config)
+            }
+        };
+        listeners.push(Arc::new(Listener::new(
+            option,
+            listener_tls_config,
+            client_tx.clone(),
+            authentication_manager.clone(),
+            connection_limiter.clone(),
+            options.tuning.client_queue_size,
+            options.handshake_timeout,
+        )?));
+    }
+
+    let options = Arc::new(options);
 
     handle_config_reset(
-        options.authorizations_file.clone(),
-        options.authorizations.clone(),
+        options.clone(),
         authentication_manager.clone(),
+        tls_configs,
         client_tx.clone(),
-    )
-    .await;
+    )?;
 
-    let tls_acceptor = match options.tls {
-        Some(option) => Some(create_acceptor(&option.certfile, &option.keyfile)?),
-        None => None,
-    };
+    let (shutdown_tx, shutdown_rx) = watch::channel(false);
+    let mut join_set = JoinSet::new();
+    for listener in listeners {
+        join_set.spawn(listener.run(shutdown_rx.clone()));
+    }
+    if let Some(endpoint) = &options.metrics_endpoint {
+        let listener = TcpListener::bind(listener::resolve(endpoint)?).await?;
+        join_set.spawn(metrics::serve(
+            listener,
+            client_tx.clone(),
+            shutdown_rx.clone(),
+        ));
+    }
 
-    let socket_addr = options
-        .socket_endpoint
-        .to_socket_addrs()?
-        .next()
-        .ok_or_else(|| io::Error::from(io::ErrorKind::AddrNotAvailable))?;
-    let socket_tls_acceptor = tls_acceptor.clone();
-    let socket_client_tx = client_tx.clone();
-    let socket_authentication_manager = authentication_manager.clone();
-
-    join_set.spawn(async move {
-        start_listener(
-            false,
-            socket_addr,
-            socket_tls_acceptor,
-            socket_client_tx,
-            socket_authentication_manager,
-        )
-        .await
-    });
+    wait_for_shutdown().await?;
 
-    let web_socket_addr = options
-        .web_socket_endpoint
-        .to_socket_addrs()?
-        .next()
-        .ok_or_else(|| io::Error::from(io::ErrorKind::AddrNotAvailable))?;
-    let web_socket_tls_acceptor = tls_acceptor.clone();
-    let web_socket_client_tx = client_tx.clone();
-    let web_socket_authentication_manager = authentication_manager.clone();
-
-    join_set.spawn(async move {
-        start_listener(
-            true,
-            web_socket_addr,
-            web_socket_tls_acceptor,
-            web_socket_client_tx,
-            web_socket_authentication_manager,
-        )
+    // The listeners stop accepting connections, and wait for their clients
+    // to receive the messages already queued for them.
+    let _ = shutdown_tx.send(true);
+    if tokio::time::timeout(options.shutdown_timeout, join_set.join_all())
         .await
-    });
+        .is_err()
+    {
+        log::warn!(
+            "Closing clients which did not shut down within {}s",
+            options.shutdown_timeout.as_secs()
+        );
+    }
+    audit::stop();
 
-    join_set.join_all().await;
+    log::info!("Stopped");
+    Ok(())
+}
 
+/// Wait for SIGTERM or SIGINT.
+async fn wait_for_shutdown() -> io::Result<()> {
+    let mut terminate_stream = signal(SignalKind::terminate())?;
+    let mut interrupt_stream = signal(SignalKind::interrupt())?;
+    tokio::select! {
+        _ = terminate_stream.recv() => log::info!("Received SIGTERM, shutting down"),
+        _ = interrupt_stream.recv() => log::info!("Received SIGINT, shutting down"),
+    }
     Ok(())
 }
 
-async fn start_listener(
-    is_web_socket: bool,
-    addr: SocketAddr,
-    tls_acceptor: Option<TlsAcceptor>,
-    client_tx: Sender<ClientEvent>,
-    authentication_manager: Arc<RwLock<AuthenticationManager>>,
-) -> io::Result<()> {
-    log::info!(
-        "Listening on {} for {}{}",
-        &addr,
-        match is_web_socket {
-            true => "web sockets",
-            false => "sockets",
-        },
-        match tls_acceptor {
-            Some(_) => " using TLS",
-            None => "",
+/// Load everything the configuration refers to, reporting the first problem.
+fn check_config(options: &Options) -> io::Result<()> {
+    let context = |what: &str| {
+        let what = what.to_string();
+        move |e: io::Error| io::Error::new(e.kind(), format!("invalid {what}: {e}"))
+    };
+
+    load_authorizations(&options.authorizations_file, &options.authorizations)
+        .map_err(context("authorizations"))?;
+    AuthenticationManager::new(&options.authentication, &options.lockout)
+        .map_err(context("authentication"))?;
+    if !options.tls.is_empty() {
+        TlsConfig::new(options.tls.clone(), options.tls_client_auth.clone())
+            .map_err(context("tls"))?;
+    }
+    for listener in &options.listeners {
+        if listener.protocol != Protocol::Unix {
+            listener::resolve(&listener.endpoint).map_err(context("endpoint"))?;
         }
-    );
+        if !listener.certificates.is_empty() {
+            TlsConfig::new(
+                listener.certificates.clone(),
+                options.tls_client_auth.clone(),
+            )
+            .map_err(context("tls"))?;
+        }
+    }
 
-    let listener = TcpListener::bind(&addr).await?;
+    if let Some(endpoint) = &options.metrics_endpoint {
+        listener::resolve(endpoint).map_err(context("metrics endpoint"))?;
+    }
+
+    println!("configuration ok");
+    Ok(())
+}
 
-    loop {
-        // Wait for a client to connect.
-        let (stream, addr) = listener.accept().await?;
+/// Print a line for the SCRAM credentials file, reading the password from
+/// stdin.
+fn print_scram_credentials(user: &str) -> io::Result<()> {
+    let mut password = String::new();
+    io::stdin().read_line(&mut password)?;
+    let password = password.trim_end_matches(['\r', '\n']);
 
-        // Start an interactor.
-        spawn_interactor(
-            is_web_socket,
-            stream,
-            addr,
-            tls_acceptor.clone(),
-            client_tx.clone(),
-            authentication_manager.clone(),
-        )
-        .await;
-    }
+    let credentials = StoredCredentials::generate(password)?;
+    println!("{user}:{credentials}");
+    Ok(())
 }
 
-async fn handle_config_reset(
-    authorizations_file: Option<PathBuf>,
-    authorizations: Vec<AuthorizationSpec>,
-    authentication_manager: Arc<RwLock<AuthenticationManager>>,
+/// Reload the configuration on SIGHUP, or when a configuration file changes.
+/// The new configuration is loaded before it replaces the old, so when a file
+/// is invalid the previous configuration is kept.
+fn handle_config_reset(
+    options: Arc<Options>,
+    authentication_manager: Arc<AuthenticationManager>,
+    tls_configs: Vec<Arc<TlsConfig>>,
     client_tx: Sender<ClientEvent>,
-) {
-    let mut hangup_stream = signal(SignalKind::hangup()).unwrap();
+) -> io::Result<()> {
+    let mut hangup_stream = signal(SignalKind::hangup())?;
+
+    let files = watched_files(&options);
+    let mut watcher = match files.is_empty() {
+        true => None,
+        false => match ConfigWatcher::new(&files) {
+            Ok(watcher) => Some(watcher),
+            Err(error) => {
+                log::warn!("Unable to watch configuration files, use SIGHUP to reload: {error}");
+                None
+            }
+        },
+    };
+
     tokio::spawn(async move {
         loop {
-            // Wait for SIGHUP.
-            hangup_stream.recv().await.unwrap();
-
-            authentication_manager.write().await.reset().unwrap();
-
-            log::info!("Reloading authorizations");
-            let authorizations =
-                load_authorizations(&authorizations_file, &authorizations).unwrap();
-            client_tx
-                .send(ClientEvent::OnReset(authorizations))
-                .await
-                .unwrap();
-        }
-    });
-}
+            let changes = tokio::select! {
+                _ = hangup_stream.recv() => {
+                    log::info!("Received SIGHUP");
+                    HashSet::from([
+                        ConfigChange::Authentication,
+                        ConfigChange::Authorization,
+                        ConfigChange::Tls,
+                    ])
+                }
+                changes = async {
+                    match &mut watcher {
+                        Some(watcher) => watcher.changed().await,
+                        None => std::future::pending().await,
+                    }
+                } => changes
+            };
+
+            if changes.contains(&ConfigChange::Authentication) {
+                log::info!("Reloading authentication");
+                // Each failure is logged by the manager.
+                if authentication_manager.reset().is_err() {
+                    log::error!("Keeping the previous configuration for failed authentication");
+                }
+            }
 
-async fn spawn_interactor(
-    is_web_socket: bool,
-    stream: TcpStream,
-    addr: SocketAddr,
-    tls_acceptor: Option<TlsAcceptor>,
-    client_tx: Sender<ClientEvent>,
-    authentication_manager: Arc<RwLock<AuthenticationManager>>,
-) {
-    tokio::spawn(async move {
-        let result = start_interactor(
-            is_web_socket,
-            stream,
-            addr,
-            tls_acceptor,
-            client_tx,
-            authentication_manager,
-        )
-        .await;
-
-        match result {
-            Ok(()) => log::debug!("Client exited normally"),
-            Err(e) => {
-                if e.kind() == io::ErrorKind::UnexpectedEof {
-                    log::debug!("Client closed connection")
-                } else {
-                    log::error!("Client exited with {}", e)
+            if changes.contains(&ConfigChange::Authorization) {
+                log::info!("Reloading authorizations");
+                match load_authorizations(&options.authorizations_file, &options.authorizations) {
+                    Ok(authorizations) => {
+                        if client_tx
+                            .send(ClientEvent::OnReset(authorizations))
+                            .await
+                            .is_err()
+                        {
+                            log::error!("Failed to reset authorizations as the hub has stopped");
+                            break;
+                        }
+                    }
+                    Err(error) => log::error!(
+                        "Failed to reload authorizations, keeping the previous authorizations: {error}"
+                    ),
+                }
+            }
+
+            if changes.contains(&ConfigChange::Tls) {
+                log::info!("Reloading TLS certificates");
+                for tls_config in &tls_configs {
+                    if let Err(error) = tls_config.reload() {
+                        log::error!(
+                            "Failed to reload TLS certificates, keeping the previous certificates: {error}"
+                        );
+                    }
                 }
             }
         }
     });
+
+    Ok(())
 }
 
-async fn start_interactor(
-    is_web_socket: bool,
-    stream: TcpStream,
-    addr: SocketAddr,
-    tls_acceptor: Option<TlsAcceptor>,
-    client_tx: Sender<ClientEvent>,
-    authentication_manager: Arc<RwLock<AuthenticationManager>>,
-) -> io::Result<()> {
-    let interactor = Interactor::new();
-
-    match tls_acceptor {
-        Some(acceptor) => {
-            let stream = acceptor.accept(stream).await?;
-            match is_web_socket {
-                true => {
-                    println!("accepting web socket connection on {} over TLS", addr);
-                    let stream = tokio_tungstenite::accept_async(stream).await.map_err(|e| {
-                        io::Error::new(
-                            io::ErrorKind::Other,
-                            format!("failed to accept websocket: {}", e),
-                        )
-                    })?;
-                    let mut stream = MessageWebSocket::new(stream);
-                    interactor
-                        .run(&mut stream, addr, client_tx, authentication_manager)
-                        .await
-                }
-                false => {
-                    println!("accepting socket connection on {} over TLS", addr);
-                    let mut stream = MessageSocket::new(stream);
-                    interactor
-                        .run(&mut stream, addr, client_tx, authentication_manager)
-                        .await
-                }
-            }
+/// The files which hold configuration, and what to reload when they change.
+fn watched_files(options: &Options) -> Vec<(PathBuf, ConfigChange)> {
+    let mut files = Vec::new();
+    if let Some(path) = &options.authorizations_file {
+        files.push((path.clone(), ConfigChange::Authorization));
+    }
+    for authentication in &options.authentication {
+        if let Some(path) = authentication.option.file() {
+            files.push((path, ConfigChange::Authentication));
         }
-        None => match is_web_socket {
-            true => {
-                println!("accepting web socket connection on {}", addr);
-                let stream = tokio_tungstenite::accept_async(stream).await.map_err(|e| {
-                    io::Error::new(
-                        io::ErrorKind::Other,
-                        format!("failed to accept websocket: {}", e),
-                    )
-                })?;
-                let mut stream = MessageWebSocket::new(stream);
-                interactor
-                    .run(&mut stream, addr, client_tx, authentication_manager)
-                    .await
-            }
-            false => {
-                println!("accepting socket connection on {}", addr);
-                let mut stream = MessageSocket::new(stream);
-                interactor
-                    .run(&mut stream, addr, client_tx, authentication_manager)
-                    .await
-            }
-        },
     }
+    let certificates = options
+        .listeners
+        .iter()
+        .flat_map(|listener| &listener.certificates);
+    for tls in options.tls.iter().chain(certificates) {
+        files.push((tls.certfile.clone(), ConfigChange::Tls));
+        files.push((tls.keyfile.clone(), ConfigChange::Tls));
+    }
+    if let Some(tls_client_auth) = &options.tls_client_auth {
+        files.push((tls_client_auth.cafile.clone(), ConfigChange::Tls));
+    }
+    files
 }
@@ -0,0 +1,440 @@
+//! Prometheus metrics, served over HTTP at `/metrics`.
+//!
+//! Counters and gauges are updated as the server runs, while the depths of
+//! the queues are measured when the metrics are scraped. Topics are counted
+//! by their prefix, the part before the first ".", to bound the number of
+//! series.
+
+use std::collections::HashMap;
+use std::io;
+use std::sync::{LazyLock, Mutex};
+use std::time::Duration;
+
+use prometheus::{
+    Encoder, HistogramOpts, HistogramVec, IntCounter, IntCounterVec, IntGauge, IntGaugeVec, Opts,
+    Registry, TextEncoder,
+};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::mpsc::{Sender, WeakSender};
+use tokio::sync::watch;
+
+use common::messages::{DataPacket, Message};
+
+use crate::events::{ClientEvent, ServerEvent};
+use crate::listener::ACCEPT_RETRY_DELAY;
+
+/// How long a scraper has to send its request.
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+const MAX_REQUEST_SIZE: usize = 8192;
+
+pub static METRICS: LazyLock<Metrics> = LazyLock::new(Metrics::new);
+
+pub struct Metrics {
+    registry: Registry,
+    clients: IntGaugeVec,
+    pub subscriptions: IntGauge,
+    pub notifications: IntGauge,
+    messages_in: IntCounterVec,
+    bytes_in: IntCounterVec,
+    messages_out: IntCounterVec,
+    bytes_out: IntCounterVec,
+    filtered_packets: IntCounterVec,
+    pub limit_violations: IntCounterVec,
+    pub audit_events_dropped: IntCounter,
+    hub_queue_depth: IntGauge,
+    client_queue_depth: IntGaugeVec,
+    pub hub_event_seconds: HistogramVec,
+    /// The queues of the connected clients, by client id.
+    client_queues: Mutex<HashMap<String, WeakSender<ServerEvent>>>,
+}
+
+impl Metrics {
+    fn new() -> Self {
+        let registry = Registry::new_custom(Some("squawkbus".into()), None).unwrap();
+        let metrics = Metrics {
+            clients: IntGaugeVec::new(
+                Opts::new("clients", "Connected clients"),
+                &["listener", "user"],
+            )
+            .unwrap(),
+            subscriptions: IntGauge::new("subscriptions", "Subscriptions of clients to topics")
+                .unwrap(),
+            notifications: IntGauge::new(
+                "notifications",
+                "Notification patterns requested by clients",
+            )
+            .unwrap(),
+            messages_in: IntCounterVec::new(
+                Opts::new("messages_in_total", "Data messages received from clients"),
+                &["prefix"],
+            )
+            .unwrap(),
+            bytes_in: IntCounterVec::new(
+                Opts::new("bytes_in_total", "Bytes of data received from clients"),
+                &["prefix"],
+            )
+            .unwrap(),
+            messages_out: IntCounterVec::new(
+                Opts::new("messages_out_total", "Data messages sent to clients"),
+                &["prefix"],
+            )
+            .unwrap(),
+            bytes_out: IntCounterVec::new(
+                Opts::new("bytes_out_total", "Bytes of data sent to clients"),
+                &["prefix"],
+            )
+            .unwrap(),
+            filtered_packets: IntCounterVec::new(
+                Opts::new(
+                    "filtered_packets_total",
+                    "Data packets withheld from subscribers by entitlements",
+                ),
+                &["prefix"],
+            )
+            .unwrap(),
+            limit_violations: IntCounterVec::new(
+                Opts::new("limit_violations_total", "Requests refused by limits"),
+                &["limit"],
+            )
+            .unwrap(),
+            audit_events_dropped: IntCounter::new(
+                "audit_events_dropped_total",
+                "Audit events dropped as the audit trail fell behind",
+            )
+            .unwrap(),
+            hub_queue_depth: IntGauge::new("hub_queue_depth", "Events waiting for the hub")
+                .unwrap(),
+            client_queue_depth: IntGaugeVec::new(
+                Opts::new(
+                    "client_queue_depth",
+                    "Messages waiting to be sent to each client",
+                ),
+                &["client_id"],
+            )
+            .unwrap(),
+            hub_event_seconds: HistogramVec::new(
+                HistogramOpts::new("hub_event_seconds", "Time the hub takes to handle events")
+                    .buckets(prometheus::exponential_buckets(0.00001, 4.0, 10).unwrap()),
+                &["event"],
+            )
+            .unwrap(),
+            client_queues: Mutex::new(HashMap::new()),
+            registry,
+        };
+
+        for collector in [
+            Box::new(metrics.clients.clone()) as Box<dyn prometheus::core::Collector>,
+            Box::new(metrics.subscriptions.clone()),
+            Box::new(metrics.notifications.clone()),
+            Box::new(metrics.messages_in.clone()),
+            Box::new(metrics.bytes_in.clone()),
+            Box::new(metrics.messages_out.clone()),
+            Box::new(metrics.bytes_out.clone()),
+            Box::new(metrics.filtered_packets.clone()),
+            Box::new(metrics.limit_violations.clone()),
+            Box::new(metrics.audit_events_dropped.clone()),
+            Box::new(metrics.hub_queue_depth.clone()),
+            Box::new(metrics.client_queue_depth.clone()),
+            Box::new(metrics.hub_event_seconds.clone()),
+        ] {
+            metrics.registry.register(collector).unwrap();
+        }
+
+        metrics
+    }
+
+    /// Count a connected client until the guard is dropped.
+    pub fn connect(&self, listener: &str, user: &str) -> ClientGuard {
+        let gauge = self.clients.with_label_values(&[listener, user]);
+        gauge.inc();
+        ClientGuard { gauge }
+    }
+
+    /// Measure the queue of a client until the guard is dropped.
+    pub fn watch_queue(&self, client_id: &str, tx: &Sender<ServerEvent>) -> QueueGuard {
+        self.client_queues
+            .lock()
+            .unwrap()
+            .insert(client_id.into(), tx.downgrade());
+        QueueGuard {
+            client_id: client_id.into(),
+        }
+    }
+
+    /// Count the data in a message received from a client.
+    pub fn received(&self, message: &Message) {
+        if let Some((topic, data_packets)) = data(message) {
+            let prefix = [topic_prefix(topic)];
+            self.messages_in.with_label_values(&prefix).inc();
+            self.bytes_in
+                .with_label_values(&prefix)
+                .inc_by(size(data_packets));
+        }
+    }
+
+    /// Count the data in a message sent to a client.
+    pub fn sent(&self, message: &Message) {
+        if let Some((topic, data_packets)) = data(message) {
+            let prefix = [topic_prefix(topic)];
+            self.messages_out.with_label_values(&prefix).inc();
+            self.bytes_out
+                .with_label_values(&prefix)
+                .inc_by(size(data_packets));
+        }
+    }
+
+    /// Count the packets withheld from a subscriber.
+    pub fn filtered(&self, topic: &str, packets: usize) {
+        self.filtered_packets
+            .with_label_values(&[topic_prefix(topic)])
+            .inc_by(packets as u64);
+    }
+
+    /// Measure the queues, and encode the metrics in the text format.
+    fn render(&self, hub: &Sender<ClientEvent>) -> io::Result<(String, Vec<u8>)> {
+        self.hub_queue_depth
+            .set((hub.max_capacity() - hub.capacity()) as i64);
+
+        self.client_queue_depth.reset();
+        for (client_id, tx) in self.client_queues.lock().unwrap().iter() {
+            if let Some(tx) = tx.upgrade() {
+                self.client_queue_depth
+                    .with_label_values(&[client_id])
+                    .set((tx.max_capacity() - tx.capacity()) as i64);
+            }
+        }
+
+        let encoder = TextEncoder::new();
+        let mut buffer = Vec::new();
+        encoder
+            .encode(&self.registry.gather(), &mut buffer)
+            .map_err(|e| io::Error::new(io::ErrorKind::Other, e))?;
+        Ok((encoder.format_type().into(), buffer))
+    }
+}
+
+pub struct ClientGuard {
+    gauge: IntGauge,
+}
+
+impl Drop for ClientGuard {
+    fn drop(&mut self) {
+        self.gauge.dec();
+    }
+}
+
+pub struct QueueGuard {
+    client_id: String,
+}
+
+impl Drop for QueueGuard {
+    fn drop(&mut self) {
+        METRICS
+            .client_queues
+            .lock()
+            .unwrap()
+            .remove(&self.client_id);
+        let _ = METRICS
+            .client_queue_depth
+            .remove_label_values(&[&self.client_id]);
+    }
+}
+
+/// The part of the topic before the first ".".
+fn topic_prefix(topic: &str) -> &str {
+    topic.split('.').next().unwrap_or(topic)
+}
+
+fn data(message: &Message) -> Option<(&str, &[DataPacket])> {
+    match message {
+        Message::MulticastData {
+            topic,
+            data_packets,
+        }
+        | Message::UnicastData {
+            topic,
+            data_packets,
+            ..
+        }
+        | Message::ForwardedMulticastData {
+            topic,
+            data_packets,
+            ..
+        }
+        | Message::ForwardedUnicastData {
+            topic,
+            data_packets,
+            ..
+        } => Some((topic, data_packets)),
+        _ => None,
+    }
+}
+
+fn size(data_packets: &[DataPacket]) -> u64 {
+    data_packets
+        .iter()
+        .map(|packet| packet.data.len() as u64)
+        .sum()
+}
+
+/// Answer requests for the metrics until the server shuts down.
+pub async fn serve(
+    listener: TcpListener,
+    hub: Sender<ClientEvent>,
+    mut shutdown: watch::Receiver<bool>,
+) -> io::Result<()> {
+    log::info!(
+        "Serving metrics on http://{}/metrics",
+        listener.local_addr()?
+    );
+
+    loop {
+        tokio::select! {
+            result = listener.accept() => {
+                let (stream, addr) = match result {
+                    Ok(accepted) => accepted,
+                    Err(error) => {
+                        log::warn!("Failed to accept a metrics connection: {error}");
+                        tokio::time::sleep(ACCEPT_RETRY_DELAY).await;
+                        continue;
+                    }
+                };
+                let hub = hub.clone();
+                tokio::spawn(async move {
+                    if let Err(error) = respond(stream, &hub).await {
+                        log::debug!("Failed to send metrics to {addr}: {error}");
+                    }
+                });
+            }
+            _ = shutdown.changed() => return Ok(())
+        }
+    }
+}
+
+async fn respond(mut stream: TcpStream, hub: &Sender<ClientEvent>) -> io::Result<()> {
+    let request = tokio::time::timeout(REQUEST_TIMEOUT, read_request(&mut stream))
+        .await
+        .map_err(|_| io::Error::new(io::ErrorKind::TimedOut, "request timed out"))??;
+
+    let (status, content_type, body) = match request {
+        (method, _) if method != "GET" => (
+            "405 Method Not Allowed",
+            "text/plain".into(),
+            b"method not allowed\n".to_vec(),
+        ),
+        (_, path) if path.split('?').next() != Some("/metrics") => (
+            "404 Not Found",
+            "text/plain".into(),
+            b"not found\n".to_vec(),
+        ),
+        _ => {
+            let (content_type, body) = METRICS.render(hub)?;
+            ("200 OK", content_type, body)
+        }
+    };
+
+    let head = format!(
+        "HTTP/1.1 {status}\r\nContent-Type: {content_type}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+        body.len()
+    );
+    stream.write_all(head.as_bytes()).await?;
+    stream.write_all(&body).await?;
+    stream.shutdown().await
+}
+
+/// Read the head of a request, returning the method and path.
+async fn read_request(stream: &mut TcpStream) -> io::Result<(String, String)> {
+    let mut buffer = Vec::new();
+    loop {
+        let mut chunk = [0; 1024];
+        let len = stream.read(&mut chunk).await?;
+        if len == 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                "incomplete request",
+            ));
+        }
+        buffer.extend_from_slice(&chunk[..len]);
+
+        let mut headers = [httparse::EMPTY_HEADER; 64];
+        let mut request = httparse::Request::new(&mut headers);
+        match request.parse(&buffer) {
+            Ok(httparse::Status::Complete(_)) => {
+                return Ok((
+                    request.method.unwrap_or_default().into(),
+                    request.path.unwrap_or_default().into(),
+                ))
+            }
+            Ok(httparse::Status::Partial) if buffer.len() < MAX_REQUEST_SIZE => continue,
+            Ok(httparse::Status::Partial) => {
+                return Err(io::Error::new(io::ErrorKind::Other, "request too large"))
+            }
+            Err(error) => return Err(io::Error::new(io::ErrorKind::Other, error)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use tokio::sync::mpsc;
+
+    use super::*;
+
+    async fn get(addr: std::net::SocketAddr, path: &str) -> String {
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        stream
+            .write_all(format!("GET {path} HTTP/1.1\r\nHost: localhost\r\n\r\n").as_bytes())
+            .await
+            .unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).await.unwrap();
+        response
+    }
+
+    #[tokio::test]
+    async fn should_serve_metrics() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let (hub_tx, _hub_rx) = mpsc::channel::<ClientEvent>(8);
+        let (client_tx, _client_rx) = mpsc::channel::<ServerEvent>(8);
+        let (_shutdown_tx, shutdown_rx) = watch::channel(false);
+        tokio::spawn(serve(listener, hub_tx.clone(), shutdown_rx));
+
+        hub_tx
+            .send(ClientEvent::OnClose("other".into()))
+            .await
+            .unwrap();
+        let _queue = METRICS.watch_queue("watched", &client_tx);
+        client_tx
+            .send(ServerEvent::OnMessage(Message::Shutdown {
+                reason: "test".into(),
+            }))
+            .await
+            .unwrap();
+        METRICS.received(&Message::MulticastData {
+            topic: "METRICS.TEST".into(),
+            data_packets: vec![DataPacket::new(
+                Default::default(),
+                Default::default(),
+                vec![0; 10],
+            )],
+        });
+
+        let response = get(addr, "/metrics").await;
+        assert!(response.starts_with("HTTP/1.1 200 OK"));
+        assert!(response.contains("squawkbus_hub_queue_depth 1"));
+        assert!(response.contains("squawkbus_client_queue_depth{client_id=\"watched\"} 1"));
+        assert!(response.contains("squawkbus_bytes_in_total{prefix=\"METRICS\"} 10"));
+
+        let response = get(addr, "/").await;
+        assert!(response.starts_with("HTTP/1.1 404 Not Found"));
+    }
+
+    #[test]
+    fn should_count_topics_by_prefix() {
+        assert_eq!(topic_prefix("LSE.TSCO"), "LSE");
+        assert_eq!(topic_prefix("LSE"), "LSE");
+        assert_eq!(topic_prefix(""), "");
+    }
+}
@@ -1,9 +1,20 @@
-use std::{collections::HashMap, io};
+use std::{
+    collections::{HashMap, HashSet},
+    io,
+};
 
 use common::messages::Message;
 use wildmatch::WildMatch;
 
-use crate::{clients::ClientManager, events::ServerEvent, subscriptions::SubscriptionManager};
+use crate::{
+    audit::{self, AuditAction, AuditEvent},
+    authorization::{AuthorizationManager, Role},
+    clients::ClientManager,
+    events::ServerEvent,
+    limits::{Limit, Limits},
+    metrics::METRICS,
+    subscriptions::SubscriptionManager,
+};
 
 struct Notification {
     pattern: WildMatch,
@@ -21,15 +32,35 @@ impl Notification {
 
 pub struct NotificationManager {
     notifications: HashMap<String, Notification>,
+    max_notifications_per_client: Option<usize>,
 }
 
 impl NotificationManager {
-    pub fn new() -> NotificationManager {
+    pub fn new(limits: &Limits) -> NotificationManager {
         NotificationManager {
             notifications: HashMap::new(),
+            max_notifications_per_client: limits.max_notifications_per_client,
         }
     }
 
+    /// Whether another notification for the pattern would exceed the limit.
+    /// A repeated request for a pattern is not counted.
+    fn is_over_limit(&self, listener_id: &str, pattern: &str) -> bool {
+        let Some(max_notifications) = self.max_notifications_per_client else {
+            return false;
+        };
+        let is_listening = self
+            .notifications
+            .get(pattern)
+            .is_some_and(|notification| notification.listeners.contains_key(listener_id));
+        let count = self
+            .notifications
+            .values()
+            .filter(|notification| notification.listeners.contains_key(listener_id))
+            .count();
+        !is_listening && count >= max_notifications
+    }
+
     pub async fn handle_notification_request(
         &mut self,
         client_id: &str,
@@ -39,6 +70,19 @@ impl NotificationManager {
         subscription_manager: &SubscriptionManager,
     ) -> io::Result<()> {
         if is_add {
+            let Some(client) = client_manager.get(client_id) else {
+                return Ok(());
+            };
+            if self.is_over_limit(client_id, &pattern) {
+                client.report(Limit::Notifications.exceeded(client_id, &pattern));
+                return Ok(());
+            }
+            audit::emit(AuditEvent::notification(
+                client_id,
+                client,
+                &pattern,
+                AuditAction::Added,
+            ));
             self.add_notification(
                 client_id,
                 pattern.as_str(),
@@ -47,6 +91,14 @@ impl NotificationManager {
             )
             .await
         } else {
+            if let Some(client) = client_manager.get(client_id) {
+                audit::emit(AuditEvent::notification(
+                    client_id,
+                    client,
+                    &pattern,
+                    AuditAction::Removed,
+                ));
+            }
             self.remove_notification(client_id, pattern.as_str(), false)
                 .await
         }
@@ -70,6 +122,7 @@ impl NotificationManager {
             *count += 1;
         } else {
             notification.listeners.insert(listener_id.into(), 1);
+            METRICS.notifications.inc();
         }
 
         for (topic, subscribers) in subscription_manager.find_subscriptions(&notification.pattern) {
@@ -121,6 +174,7 @@ impl NotificationManager {
 
         if *count == 0 {
             notification.listeners.remove(listener_id);
+            METRICS.notifications.dec();
             log::debug!("removed all notifications for {listener_id} on {pattern}")
         } else {
             log::debug!("removed one notification for {listener_id} on {pattern}")
@@ -186,6 +240,65 @@ impl NotificationManager {
         Ok(())
     }
 
+    /// The listener and pattern of the notifications clients are authorized
+    /// for.
+    pub fn authorized(
+        &self,
+        client_manager: &ClientManager,
+        authorization_manager: &AuthorizationManager,
+    ) -> HashSet<(String, String)> {
+        let mut authorized: HashSet<(String, String)> = HashSet::new();
+        for (pattern, notification) in &self.notifications {
+            for listener_id in notification.listeners.keys() {
+                if let Some(listener) = client_manager.get(listener_id) {
+                    if listener.is_authorized(authorization_manager, pattern, Role::Notifier) {
+                        authorized.insert((listener_id.clone(), pattern.clone()));
+                    }
+                }
+            }
+        }
+        authorized
+    }
+
+    /// Remove the notifications clients were authorized for, but no longer
+    /// are, and inform them.
+    pub async fn revoke_unauthorized(
+        &mut self,
+        previously_authorized: &HashSet<(String, String)>,
+        client_manager: &ClientManager,
+        authorization_manager: &AuthorizationManager,
+    ) -> io::Result<()> {
+        let authorized = self.authorized(client_manager, authorization_manager);
+        let mut revoked: Vec<(String, String)> = previously_authorized
+            .difference(&authorized)
+            .cloned()
+            .collect();
+        revoked.sort();
+
+        for (listener_id, pattern) in revoked {
+            log::info!("revoking notification from {listener_id} for {pattern}");
+            self.remove_notification(&listener_id, &pattern, true)
+                .await?;
+
+            if let Some(listener) = client_manager.get(&listener_id) {
+                audit::emit(AuditEvent::notification(
+                    &listener_id,
+                    listener,
+                    &pattern,
+                    AuditAction::Revoked,
+                ));
+                let event = ServerEvent::OnMessage(Message::NotificationRevoked { pattern });
+                listener
+                    .tx
+                    .send(event)
+                    .await
+                    .map_err(|e| io::Error::new(io::ErrorKind::Other, e))?;
+            }
+        }
+
+        Ok(())
+    }
+
     fn find_listener_patterns(&self, listener_id: &str) -> Vec<String> {
         let mut patterns: Vec<String> = Vec::new();
         for (pattern, notification) in &self.notifications {
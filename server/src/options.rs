@@ -2,65 +2,266 @@
 
 use std::path::PathBuf;
 use std::str::FromStr;
-use std::{collections::HashSet, io};
+use std::time::Duration;
+use std::{
+    collections::{HashMap, HashSet},
+    io,
+};
 
+use ipnet::IpNet;
 use wildmatch::WildMatch;
 
-use crate::authorization::{AuthorizationSpec, Role};
+use crate::audit::{AuditOption, DEFAULT_MAX_FILES, DEFAULT_MAX_FILE_SIZE};
+use crate::authentication::{
+    CertificateNameSource, LockoutOption, DEFAULT_LOCKOUT_SECONDS, DEFAULT_MAX_FAILURES,
+};
+use crate::authorization::{AuthorizationSpec, Principal, Role};
+use crate::limits::Limits;
+use crate::settings::{Settings, Tuning};
 
 const DEFAULT_SOCKET_ENDPOINT: &str = "0.0.0.0:8558";
 const DEFAULT_WEB_SOCKET_ENDPOINT: &str = "0.0.0.0:8559";
+const DEFAULT_SHUTDOWN_TIMEOUT: u64 = 5;
+const DEFAULT_HANDSHAKE_TIMEOUT: u64 = 10;
 
-/// Parses the string <user-pattern>:<topic-pattern>:<entitlements>:<roles>
+/// Parses the string <principal>:<topic-pattern>:<entitlements>:<roles>, where
+/// the principal is a user pattern, or "@" followed by a group pattern. This
+/// may be followed by :<hosts>, a comma separated list of networks in CIDR
+/// notation. As IPv6 networks contain colons the hosts must come last. A
+/// leading "!" makes the spec deny the entitlements, or all entitlements when
+/// none are given.
 impl FromStr for AuthorizationSpec {
     type Err = String;
 
     fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
-        let args: Vec<&str> = s.split(':').collect();
-        if args.len() != 4 {
-            return Err(format!("expected 4 parts, found {}", args.len()));
+        let (is_deny, s) = match s.strip_prefix('!') {
+            Some(s) => (true, s),
+            None => (false, s),
+        };
+        let args: Vec<&str> = s.splitn(5, ':').collect();
+        if args.len() < 4 {
+            return Err(format!("expected 4 or 5 parts, found {}", args.len()));
         }
 
-        let user_pattern = args[0];
+        let principal = args[0];
         let topic_pattern = args[1];
         let entitlements = args[2];
         let roles = args[3];
 
-        let user_pattern = WildMatch::new(user_pattern);
+        let principal = Principal::parse(principal);
         let topic_pattern = WildMatch::new(topic_pattern);
         let entitlements = entitlements
             .split(',')
+            .filter(|x| !x.is_empty())
             .map(|x| x.parse().map_err(|e| format!("invalid entitlement {}", e)))
             .collect::<std::result::Result<HashSet<i32>, String>>()?;
         let roles: Role =
             bitflags::parser::from_str(roles).map_err(|e| format!("invalid roles: {}", e))?;
+        let hosts = match args.get(4) {
+            Some(hosts) => hosts
+                .split(',')
+                .map(|x| x.parse().map_err(|e| format!("invalid host {}: {}", x, e)))
+                .collect::<std::result::Result<Vec<IpNet>, String>>()?,
+            None => Vec::new(),
+        };
         Ok(AuthorizationSpec {
-            user_pattern,
+            principal,
             topic_pattern,
             entitlements,
             roles,
+            hosts,
+            is_deny,
         })
     }
 }
 
+#[derive(Debug, Clone, PartialEq, serde::Deserialize)]
+#[serde(deny_unknown_fields)]
 pub struct TLSOption {
     pub keyfile: PathBuf,
     pub certfile: PathBuf,
 }
 
+#[derive(Clone)]
+pub struct TLSClientAuthOption {
+    pub cafile: PathBuf,
+    pub is_required: bool,
+}
+
 pub enum AuthenticationOption {
     None,
     Basic(PathBuf),
     Ldap(String),
+    Certificate(CertificateNameSource),
+    Bearer(PathBuf),
+    Scram(PathBuf),
+    Peer,
+}
+
+impl AuthenticationOption {
+    /// The method name clients use by default.
+    pub fn method(&self) -> &'static str {
+        match self {
+            AuthenticationOption::None => "none",
+            AuthenticationOption::Basic(_) => "basic",
+            AuthenticationOption::Ldap(_) => "ldap",
+            AuthenticationOption::Certificate(_) => "certificate",
+            AuthenticationOption::Bearer(_) => "bearer",
+            AuthenticationOption::Scram(_) => common::scram::METHOD,
+            AuthenticationOption::Peer => "peer",
+        }
+    }
+
+    /// The file holding the configuration of the backend, if any.
+    pub fn file(&self) -> Option<PathBuf> {
+        match self {
+            AuthenticationOption::Basic(path)
+            | AuthenticationOption::Bearer(path)
+            | AuthenticationOption::Scram(path) => Some(path.clone()),
+            AuthenticationOption::Ldap(source)
+                if !source.starts_with("ldap://") && !source.starts_with("ldaps://") =>
+            {
+                Some(source.into())
+            }
+            AuthenticationOption::Ldap(_)
+            | AuthenticationOption::None
+            | AuthenticationOption::Certificate(_)
+            | AuthenticationOption::Peer => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, serde::Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Protocol {
+    Socket,
+    WebSocket,
+    /// A Unix domain socket, for clients on the same host.
+    Unix,
+}
+
+/// An endpoint on which the server accepts connections.
+#[derive(Debug, Clone, PartialEq, serde::Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ListenerOption {
+    /// The address, or the path of a Unix domain socket.
+    pub endpoint: String,
+    pub protocol: Protocol,
+    #[serde(default)]
+    pub tls: bool,
+    /// The certificates for the listener, rather than those of the server.
+    #[serde(default)]
+    pub certificates: Vec<TLSOption>,
+    /// The authentication methods clients may use, or all when empty.
+    #[serde(default)]
+    pub methods: Vec<String>,
+    /// The permissions of a Unix domain socket, as an octal string such as
+    /// "660".
+    #[serde(default)]
+    pub mode: Option<String>,
+}
+
+impl ListenerOption {
+    /// The permissions of a Unix domain socket.
+    pub fn permissions(&self) -> io::Result<Option<u32>> {
+        self.mode
+            .as_ref()
+            .map(|mode| {
+                u32::from_str_radix(mode, 8).map_err(|_| {
+                    io::Error::new(io::ErrorKind::Other, format!("invalid mode {mode}"))
+                })
+            })
+            .transpose()
+    }
+}
+
+/// Parses <scheme>://<endpoint>[?<key>=<value>&...], where the scheme is one
+/// of "tcp", "tls", "ws", "wss" or "unix", for which the endpoint is a path.
+/// The keys are "methods", a comma separated list of the authentication
+/// methods allowed, "certfile" and "keyfile" for a certificate of the
+/// listener's own, and "mode" for the permissions of a Unix domain socket.
+impl FromStr for ListenerOption {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        let (scheme, rest) = s
+            .split_once("://")
+            .ok_or_else(|| format!("expected <scheme>://<endpoint>, found {s}"))?;
+        let (protocol, tls) = match scheme {
+            "tcp" => (Protocol::Socket, false),
+            "tls" => (Protocol::Socket, true),
+            "ws" => (Protocol::WebSocket, false),
+            "wss" => (Protocol::WebSocket, true),
+            "unix" => (Protocol::Unix, false),
+            _ => return Err(format!("invalid scheme {scheme}")),
+        };
+        let (endpoint, query) = match rest.split_once('?') {
+            Some((endpoint, query)) => (endpoint, Some(query)),
+            None => (rest, None),
+        };
+
+        let mut methods = Vec::new();
+        let mut certfile: Option<PathBuf> = None;
+        let mut keyfile: Option<PathBuf> = None;
+        let mut mode: Option<String> = None;
+        for parameter in query.into_iter().flat_map(|query| query.split('&')) {
+            match parameter.split_once('=') {
+                Some(("methods", value)) => {
+                    methods.extend(value.split(',').map(|method| method.to_string()))
+                }
+                Some(("certfile", value)) => certfile = Some(value.into()),
+                Some(("keyfile", value)) => keyfile = Some(value.into()),
+                Some(("mode", value)) => mode = Some(value.into()),
+                _ => return Err(format!("invalid listener parameter {parameter}")),
+            }
+        }
+        let certificates = match (certfile, keyfile) {
+            (Some(certfile), Some(keyfile)) => vec![TLSOption { certfile, keyfile }],
+            (None, None) => Vec::new(),
+            _ => return Err("certfile and keyfile must be given together".into()),
+        };
+
+        Ok(ListenerOption {
+            endpoint: endpoint.into(),
+            protocol,
+            tls,
+            certificates,
+            methods,
+            mode,
+        })
+    }
+}
+
+/// An authentication backend, and the method name clients use to select it.
+pub struct AuthenticationMethodOption {
+    pub method: String,
+    pub option: AuthenticationOption,
 }
 
 pub struct Options {
-    pub socket_endpoint: String,
-    pub web_socket_endpoint: String,
+    pub listeners: Vec<ListenerOption>,
+    /// Where Prometheus metrics are served, when given.
+    pub metrics_endpoint: Option<String>,
     pub authorizations: Vec<AuthorizationSpec>,
     pub authorizations_file: Option<PathBuf>,
-    pub tls: Option<TLSOption>,
-    pub authentication: AuthenticationOption,
+    /// The certificates for TLS. The first is the default, and the others
+    /// are chosen by the server name the client requests.
+    pub tls: Vec<TLSOption>,
+    pub tls_client_auth: Option<TLSClientAuthOption>,
+    pub authentication: Vec<AuthenticationMethodOption>,
+    /// How long a client has to complete the TLS handshake and authenticate.
+    pub handshake_timeout: Duration,
+    /// When users and hosts which fail to authenticate are locked out.
+    pub lockout: LockoutOption,
+    pub scram_credentials: Option<String>,
+    pub audit: Option<AuditOption>,
+    pub tuning: Tuning,
+    pub limits: Limits,
+    /// How long clients are given to receive their queued messages when the
+    /// server shuts down.
+    pub shutdown_timeout: Duration,
+    /// Check the configuration and exit.
+    pub check_config: bool,
 }
 
 fn fetch_arg(arg_name: &str, args: &[String], arg_index: &mut usize) -> io::Result<String> {
@@ -111,31 +312,114 @@ fn check_fetch_two_args<T>(
     Ok((arg1, arg2))
 }
 
+fn check_listeners(
+    listeners: &[ListenerOption],
+    tls: &[TLSOption],
+    authentication: &[AuthenticationMethodOption],
+) -> io::Result<()> {
+    for listener in listeners {
+        let endpoint = &listener.endpoint;
+        if listener.tls && listener.certificates.is_empty() && tls.is_empty() {
+            return Err(io::Error::new(
+                io::ErrorKind::Other,
+                format!("the listener on {endpoint} requires certificates"),
+            ));
+        }
+        if !listener.tls && !listener.certificates.is_empty() {
+            return Err(io::Error::new(
+                io::ErrorKind::Other,
+                format!("the listener on {endpoint} has certificates without TLS"),
+            ));
+        }
+        if listener.protocol == Protocol::Unix && listener.tls {
+            return Err(io::Error::new(
+                io::ErrorKind::Other,
+                format!("the listener on {endpoint} is a Unix domain socket, which cannot use TLS"),
+            ));
+        }
+        if listener.protocol != Protocol::Unix && listener.mode.is_some() {
+            return Err(io::Error::new(
+                io::ErrorKind::Other,
+                format!("the listener on {endpoint} has a mode, but is not a Unix domain socket"),
+            ));
+        }
+        listener.permissions()?;
+        for method in &listener.methods {
+            if !authentication.iter().any(|option| &option.method == method) {
+                return Err(io::Error::new(
+                    io::ErrorKind::Other,
+                    format!("the listener on {endpoint} allows the unknown method {method}"),
+                ));
+            }
+        }
+    }
+    Ok(())
+}
+
 impl Options {
     pub fn parse(args: &[String]) -> io::Result<Self> {
+        Self::parse_with_environment(args, None)
+    }
+
+    /// Parse the arguments, with settings from the configuration file and
+    /// environment where they are not given. The environment may be given
+    /// for testing, otherwise the process environment is used.
+    pub fn parse_with_environment(
+        args: &[String],
+        environment: Option<HashMap<String, String>>,
+    ) -> io::Result<Self> {
+        let mut config: Option<PathBuf> = None;
+        let mut check_config = false;
         let mut socket_endpoint: Option<String> = None;
         let mut websocket_endpoint: Option<String> = None;
+        let mut metrics_endpoint: Option<String> = None;
+        let mut listeners: Vec<ListenerOption> = Vec::new();
         let mut authorizations: Vec<AuthorizationSpec> = Vec::new();
         let mut authorizations_file: Option<PathBuf> = None;
-        let mut tls: Option<TLSOption> = None;
-        let mut authentication: Option<AuthenticationOption> = None;
+        let mut tls: Vec<TLSOption> = Vec::new();
+        let mut tls_client_auth: Option<TLSClientAuthOption> = None;
+        let mut authentication: Vec<AuthenticationMethodOption> = Vec::new();
+        let mut scram_credentials: Option<String> = None;
+        let mut audit: Option<AuditOption> = None;
+        let mut audit_rotate: Option<(u64, usize)> = None;
+        let mut shutdown_timeout: Option<u64> = None;
+        let mut handshake_timeout: Option<u64> = None;
+        let mut lockout: Option<(u32, u64)> = None;
+        let mut limits = Limits::default();
 
         let mut arg_index = 1;
         while arg_index < args.len() {
             let arg_name = args.get(arg_index).unwrap().as_str();
             match arg_name {
+                "--config" => {
+                    let filename = check_fetch_arg(arg_name, &config, args, &mut arg_index)?;
+                    config = Some(filename.into());
+                }
+                "--check-config" => check_config = true,
                 "--socket-endpoint" => {
                     let endpoint =
-                        check_fetch_arg(arg_name, &socket_endpoint, &args, &mut arg_index)?;
+                        check_fetch_arg(arg_name, &socket_endpoint, args, &mut arg_index)?;
                     socket_endpoint = Some(endpoint);
                 }
                 "--web-socket-endpoint" => {
                     let endpoint =
-                        check_fetch_arg(arg_name, &websocket_endpoint, &args, &mut arg_index)?;
+                        check_fetch_arg(arg_name, &websocket_endpoint, args, &mut arg_index)?;
                     websocket_endpoint = Some(endpoint);
                 }
+                "--metrics-endpoint" => {
+                    let endpoint =
+                        check_fetch_arg(arg_name, &metrics_endpoint, args, &mut arg_index)?;
+                    metrics_endpoint = Some(endpoint);
+                }
+                "--listener" => {
+                    let listener = fetch_arg(arg_name, args, &mut arg_index)?;
+                    let listener = listener
+                        .parse()
+                        .map_err(|e| io::Error::new(io::ErrorKind::Other, e))?;
+                    listeners.push(listener);
+                }
                 "--authorization" => {
-                    let authorization = fetch_arg(arg_name, &args, &mut arg_index)?;
+                    let authorization = fetch_arg(arg_name, args, &mut arg_index)?;
                     let authorization = authorization
                         .parse()
                         .map_err(|e| io::Error::new(io::ErrorKind::Other, e))?;
@@ -143,37 +427,134 @@ impl Options {
                 }
                 "--authorizations-file" => {
                     let filename =
-                        check_fetch_arg(arg_name, &authorizations_file, &args, &mut arg_index)?;
+                        check_fetch_arg(arg_name, &authorizations_file, args, &mut arg_index)?;
                     authorizations_file = Some(filename.into());
                 }
+                "--scram-credentials" => {
+                    let user = check_fetch_arg(arg_name, &scram_credentials, args, &mut arg_index)?;
+                    scram_credentials = Some(user);
+                }
                 "--tls" => {
-                    let (certfile, keyfile) =
-                        check_fetch_two_args(arg_name, &tls, &args, &mut arg_index)?;
-                    tls = Some(TLSOption {
+                    let certfile = fetch_arg(arg_name, args, &mut arg_index)?;
+                    let keyfile = fetch_arg(arg_name, args, &mut arg_index)?;
+                    tls.push(TLSOption {
                         certfile: certfile.into(),
                         keyfile: keyfile.into(),
                     });
                 }
+                "--tls-client-auth" => {
+                    let (mode, cafile) =
+                        check_fetch_two_args(arg_name, &tls_client_auth, args, &mut arg_index)?;
+                    let is_required = match mode.as_str() {
+                        "required" => true,
+                        "optional" => false,
+                        _ => Err(io::Error::new(
+                            io::ErrorKind::Other,
+                            "invalid tls client auth option",
+                        ))?,
+                    };
+                    tls_client_auth = Some(TLSClientAuthOption {
+                        cafile: cafile.into(),
+                        is_required,
+                    });
+                }
                 "--authentication" => {
-                    let method = check_fetch_arg(arg_name, &authentication, &args, &mut arg_index)?;
-                    authentication = Some(match method.as_str() {
+                    // The backend may be registered under another method name,
+                    // as in "ldap@basic".
+                    let backend = fetch_arg(arg_name, args, &mut arg_index)?;
+                    let (backend, method) = match backend.split_once('@') {
+                        Some((backend, method)) => (backend.to_string(), Some(method.to_string())),
+                        None => (backend, None),
+                    };
+                    let option = match backend.as_str() {
                         "none" => AuthenticationOption::None,
                         "basic" => {
-                            let filename =
-                                check_fetch_arg(arg_name, &authentication, &args, &mut arg_index)?;
+                            let filename = fetch_arg(arg_name, args, &mut arg_index)?;
                             AuthenticationOption::Basic(filename.into())
                         }
                         "ldap" => {
-                            let url =
-                                check_fetch_arg(arg_name, &authentication, &args, &mut arg_index)?;
+                            let url = fetch_arg(arg_name, args, &mut arg_index)?;
                             AuthenticationOption::Ldap(url)
                         }
+                        "certificate" => {
+                            let name_source = fetch_arg(arg_name, args, &mut arg_index)?;
+                            let name_source = name_source
+                                .parse()
+                                .map_err(|e| io::Error::new(io::ErrorKind::Other, e))?;
+                            AuthenticationOption::Certificate(name_source)
+                        }
+                        "bearer" => {
+                            let filename = fetch_arg(arg_name, args, &mut arg_index)?;
+                            AuthenticationOption::Bearer(filename.into())
+                        }
+                        "scram-sha-256" => {
+                            let filename = fetch_arg(arg_name, args, &mut arg_index)?;
+                            AuthenticationOption::Scram(filename.into())
+                        }
+                        "peer" => AuthenticationOption::Peer,
                         _ => Err(io::Error::new(
                             io::ErrorKind::Other,
                             "invalid authentication option",
                         ))?,
+                    };
+                    let method = method.unwrap_or_else(|| option.method().to_string());
+                    authentication.push(AuthenticationMethodOption { method, option });
+                }
+                "--audit" => {
+                    let (sink, path) =
+                        check_fetch_two_args(arg_name, &audit, args, &mut arg_index)?;
+                    audit = Some(match sink.as_str() {
+                        "file" => AuditOption::File {
+                            path: path.into(),
+                            max_size: DEFAULT_MAX_FILE_SIZE,
+                            max_files: DEFAULT_MAX_FILES,
+                        },
+                        "syslog" => AuditOption::Syslog(path.into()),
+                        _ => Err(io::Error::new(io::ErrorKind::Other, "invalid audit option"))?,
                     });
                 }
+                "--audit-rotate" => {
+                    let (max_size, max_files) =
+                        check_fetch_two_args(arg_name, &audit_rotate, args, &mut arg_index)?;
+                    let max_size = max_size
+                        .parse()
+                        .map_err(|e| io::Error::new(io::ErrorKind::Other, e))?;
+                    let max_files = max_files
+                        .parse()
+                        .map_err(|e| io::Error::new(io::ErrorKind::Other, e))?;
+                    audit_rotate = Some((max_size, max_files));
+                }
+                "--limit" => {
+                    let limit = fetch_arg(arg_name, args, &mut arg_index)?;
+                    limits.set(&limit)?;
+                }
+                "--shutdown-timeout" => {
+                    let seconds =
+                        check_fetch_arg(arg_name, &shutdown_timeout, args, &mut arg_index)?;
+                    let seconds = seconds
+                        .parse()
+                        .map_err(|e| io::Error::new(io::ErrorKind::Other, e))?;
+                    shutdown_timeout = Some(seconds);
+                }
+                "--handshake-timeout" => {
+                    let seconds =
+                        check_fetch_arg(arg_name, &handshake_timeout, args, &mut arg_index)?;
+                    let seconds = seconds
+                        .parse()
+                        .map_err(|e| io::Error::new(io::ErrorKind::Other, e))?;
+                    handshake_timeout = Some(seconds);
+                }
+                "--lockout" => {
+                    let (max_failures, seconds) =
+                        check_fetch_two_args(arg_name, &lockout, args, &mut arg_index)?;
+                    let max_failures = max_failures
+                        .parse()
+                        .map_err(|e| io::Error::new(io::ErrorKind::Other, e))?;
+                    let seconds = seconds
+                        .parse()
+                        .map_err(|e| io::Error::new(io::ErrorKind::Other, e))?;
+                    lockout = Some((max_failures, seconds));
+                }
                 "--help" => Err(io::Error::new(
                     io::ErrorKind::Other,
                     Self::usage(args.get(0).unwrap()),
@@ -187,24 +568,136 @@ impl Options {
             arg_index += 1
         }
 
-        // Default socket endpoint
+        // Command line options override the settings.
+        let settings = Settings::load(config.as_deref(), environment)?;
+
         let socket_endpoint = socket_endpoint
-            .or(Some(DEFAULT_SOCKET_ENDPOINT.into()))
-            .unwrap();
-        // Default websocket endpoint
+            .or(settings.socket_endpoint)
+            .unwrap_or_else(|| DEFAULT_SOCKET_ENDPOINT.into());
         let websocket_endpoint = websocket_endpoint
-            .or(Some(DEFAULT_WEB_SOCKET_ENDPOINT.into()))
-            .unwrap();
+            .or(settings.web_socket_endpoint)
+            .unwrap_or_else(|| DEFAULT_WEB_SOCKET_ENDPOINT.into());
+        let metrics_endpoint = metrics_endpoint.or(settings.metrics_endpoint);
+        let authorizations_file = authorizations_file.or(settings.authorizations_file);
+        if authorizations.is_empty() {
+            authorizations = settings
+                .authorizations
+                .iter()
+                .map(|authorization| {
+                    authorization
+                        .parse()
+                        .map_err(|e| io::Error::new(io::ErrorKind::Other, e))
+                })
+                .collect::<io::Result<_>>()?;
+        }
+        if tls.is_empty() {
+            tls = settings.tls;
+        }
+        let tls_client_auth = match tls_client_auth {
+            Some(tls_client_auth) => Some(tls_client_auth),
+            None => settings
+                .tls_client_auth
+                .map(TryInto::try_into)
+                .transpose()?,
+        };
+        if authentication.is_empty() {
+            authentication = settings
+                .authentication
+                .into_iter()
+                .map(TryInto::try_into)
+                .collect::<io::Result<_>>()?;
+        }
+        let mut audit = audit.or(settings.audit.map(Into::into));
+        let limits = limits.or(settings.limits);
+        let shutdown_timeout = Duration::from_secs(
+            shutdown_timeout
+                .or(settings.shutdown_timeout)
+                .unwrap_or(DEFAULT_SHUTDOWN_TIMEOUT),
+        );
+
+        let handshake_timeout = Duration::from_secs(
+            handshake_timeout
+                .or(settings.handshake_timeout)
+                .unwrap_or(DEFAULT_HANDSHAKE_TIMEOUT),
+        );
+        let lockout = match lockout {
+            Some((max_failures, seconds)) => LockoutOption {
+                max_failures,
+                seconds,
+                ..settings.lockout.unwrap_or_default()
+            },
+            None => settings.lockout.unwrap_or_default(),
+        };
+
+        if let Some((size, files)) = audit_rotate {
+            let Some(AuditOption::File {
+                max_size,
+                max_files,
+                ..
+            }) = &mut audit
+            else {
+                return Err(io::Error::new(
+                    io::ErrorKind::Other,
+                    "--audit-rotate requires --audit file",
+                ));
+            };
+            *max_size = size;
+            *max_files = files;
+        }
+
         // Default authentication to none
-        let authentication = authentication.or(Some(AuthenticationOption::None)).unwrap();
+        if authentication.is_empty() {
+            authentication.push(AuthenticationMethodOption {
+                method: AuthenticationOption::None.method().into(),
+                option: AuthenticationOption::None,
+            });
+        }
+
+        // Without listeners the server listens for sockets and web sockets,
+        // using TLS when there are certificates.
+        if listeners.is_empty() {
+            listeners = settings.listeners;
+        }
+        if listeners.is_empty() {
+            for (endpoint, protocol) in [
+                (socket_endpoint, Protocol::Socket),
+                (websocket_endpoint, Protocol::WebSocket),
+            ] {
+                listeners.push(ListenerOption {
+                    endpoint,
+                    protocol,
+                    tls: !tls.is_empty(),
+                    certificates: Vec::new(),
+                    methods: Vec::new(),
+                    mode: None,
+                });
+            }
+        }
+        check_listeners(&listeners, &tls, &authentication)?;
+
+        if tls_client_auth.is_some() && !listeners.iter().any(|listener| listener.tls) {
+            return Err(io::Error::new(
+                io::ErrorKind::Other,
+                "--tls-client-auth requires a TLS listener",
+            ));
+        }
 
         return Ok(Self {
-            socket_endpoint,
-            web_socket_endpoint: websocket_endpoint,
+            listeners,
+            metrics_endpoint,
             authorizations,
             authorizations_file,
             tls,
+            tls_client_auth,
             authentication,
+            handshake_timeout,
+            lockout,
+            scram_credentials,
+            audit,
+            tuning: settings.tuning,
+            limits,
+            shutdown_timeout,
+            check_config,
         });
     }
 
@@ -214,14 +707,37 @@ impl Options {
             \t{prog_name} [<options>]
             
             options:
+            \t--config <filename> # a TOML, YAML or JSON file of settings
+            \t--check-config # check the configuration and exit
             \t--socket-endpoint <ip-address>:<port> # defaults to {DEFAULT_SOCKET_ENDPOINT}
             \t--web-socket-endpoint <ip-address>:<port> # defaults to {DEFAULT_WEB_SOCKET_ENDPOINT}
-            \t--tls <certfile> <keyfile>
+            \t--listener (tcp|tls|ws|wss)://<ip-address>:<port>[?methods=<method>,...&certfile=<certfile>&keyfile=<keyfile>]
+            \t--listener unix://<path>[?methods=<method>,...&mode=<octal-permissions>]
+            \t# --listener may be repeated, and replaces the socket and web socket endpoints
+            \t--metrics-endpoint <ip-address>:<port> # serve Prometheus metrics at /metrics
+            \t--tls <certfile> <keyfile> # may be repeated, selecting by the server name
+            \t--tls-client-auth (required|optional) <cafile>
             \t--authentication none # the default
-            \t--authentication basic <passwd-file>
-            \t--authentication ldap <url>
+            \t--authentication basic[@<method>] <passwd-file>
+            \t--authentication ldap[@<method>] (<url>|<config-file>)
+            \t--authentication certificate[@<method>] (cn|dns|email|uri)
+            \t--authentication bearer[@<method>] <config-file>
+            \t--authentication scram-sha-256[@<method>] <credentials-file>
+            \t--authentication peer[@<method>] # the user of a process on a Unix domain socket
+            \t# --authentication may be repeated, trying backends for the same method in order
+            \t--handshake-timeout <seconds> # for TLS and authentication, defaults to {DEFAULT_HANDSHAKE_TIMEOUT}
+            \t--lockout <max-failures> <seconds> # defaults to {DEFAULT_MAX_FAILURES} {DEFAULT_LOCKOUT_SECONDS}, doubling with further failures
             \t--authorizations-file <filename>
-            \t--authorization <user:topic:entitlements:roles>
+            \t--authorization [!]<user|@group:topic:entitlements:roles[:hosts]> # a leading ! denies
+            \t--audit file <filename> # JSON lines
+            \t--audit syslog <socket> # for example /dev/log
+            \t--audit-rotate <max-bytes> <max-files> # defaults to {DEFAULT_MAX_FILE_SIZE} {DEFAULT_MAX_FILES}
+            \t--limit <limit>=<value> # may be repeated, where the limit is one of
+            \t#   max_connections, max_connections_per_user, max_connections_per_host,
+            \t#   max_subscriptions_per_client, max_notifications_per_client,
+            \t#   max_publish_messages_per_second, max_publish_bytes_per_second
+            \t--shutdown-timeout <seconds> # defaults to {DEFAULT_SHUTDOWN_TIMEOUT}
+            \t--scram-credentials <user> # print credentials for the password on stdin and exit
             "
         )
     }
@@ -232,7 +748,7 @@ impl Options {
             Ok(args) => Ok(args),
             Err(error) => {
                 let prog_name = args.get(0).unwrap();
-                let s = Self::usage(&prog_name);
+                let s = Self::usage(prog_name);
                 println!("error: {error}\n{s}");
                 Err(error)
             }
@@ -251,10 +767,182 @@ mod test {
         let spec: AuthorizationSpec =
             AuthorizationSpec::from_str("*:PUB.*:1,2:Subscriber|Publisher").unwrap();
         let user_entitlements_spec = vec![spec];
-        let entitlements_manager = AuthorizationManager::new(user_entitlements_spec);
+        let entitlements_manager = AuthorizationManager::new(user_entitlements_spec.into());
 
-        let actual = entitlements_manager.entitlements("nobody", "PUB.foo", Role::Subscriber);
+        let actual =
+            entitlements_manager.entitlements("nobody", &[], None, "PUB.foo", Role::Subscriber);
         let expected: HashSet<i32> = HashSet::from([1, 2]);
         assert_eq!(actual, expected);
     }
+
+    #[test]
+    fn parse_hosts() {
+        let spec =
+            AuthorizationSpec::from_str("feed:LSE.*:1:Publisher:10.1.0.0/16,fd00::/8").unwrap();
+        assert_eq!(
+            spec.hosts,
+            vec![
+                "10.1.0.0/16".parse::<IpNet>().unwrap(),
+                "fd00::/8".parse().unwrap()
+            ]
+        );
+
+        assert!(AuthorizationSpec::from_str("feed:LSE.*:1:Publisher:10.1.0.0/33").is_err());
+    }
+
+    #[test]
+    fn parse_audit() {
+        let args: Vec<String> = [
+            "squawkbus",
+            "--audit-rotate",
+            "1000",
+            "2",
+            "--audit",
+            "file",
+            "audit.log",
+        ]
+        .iter()
+        .map(|x| x.to_string())
+        .collect();
+        let options = Options::parse(&args).unwrap();
+        assert_eq!(
+            options.audit,
+            Some(AuditOption::File {
+                path: "audit.log".into(),
+                max_size: 1000,
+                max_files: 2
+            })
+        );
+
+        let args: Vec<String> = [
+            "squawkbus",
+            "--audit",
+            "syslog",
+            "/dev/log",
+            "--audit-rotate",
+            "1000",
+            "2",
+        ]
+        .iter()
+        .map(|x| x.to_string())
+        .collect();
+        assert!(Options::parse(&args).is_err());
+    }
+
+    #[test]
+    fn command_line_overrides_environment() {
+        let args: Vec<String> = ["squawkbus", "--socket-endpoint", "127.0.0.1:9000"]
+            .iter()
+            .map(|x| x.to_string())
+            .collect();
+        let environment = HashMap::from([
+            (
+                "SQUAWKBUS_SOCKET_ENDPOINT".to_string(),
+                "127.0.0.1:9001".to_string(),
+            ),
+            (
+                "SQUAWKBUS_WEB_SOCKET_ENDPOINT".to_string(),
+                "127.0.0.1:9002".to_string(),
+            ),
+        ]);
+        let options = Options::parse_with_environment(&args, Some(environment)).unwrap();
+        assert_eq!(options.listeners[0].endpoint, "127.0.0.1:9000");
+        assert_eq!(options.listeners[1].endpoint, "127.0.0.1:9002");
+        assert_eq!(options.authentication[0].method, "none");
+    }
+
+    #[test]
+    fn parse_listeners() {
+        let args: Vec<String> = [
+            "squawkbus",
+            "--authentication",
+            "none",
+            "--authentication",
+            "ldap@basic",
+            "ldap://ns1.example.com",
+            "--listener",
+            "tcp://127.0.0.1:8558?methods=none",
+            "--listener",
+            "wss://0.0.0.0:8559?methods=basic&certfile=server.crt&keyfile=server.key",
+        ]
+        .iter()
+        .map(|x| x.to_string())
+        .collect();
+        let options = Options::parse(&args).unwrap();
+        assert_eq!(
+            options.listeners,
+            vec![
+                ListenerOption {
+                    endpoint: "127.0.0.1:8558".into(),
+                    protocol: Protocol::Socket,
+                    tls: false,
+                    certificates: Vec::new(),
+                    methods: vec!["none".into()],
+                    mode: None,
+                },
+                ListenerOption {
+                    endpoint: "0.0.0.0:8559".into(),
+                    protocol: Protocol::WebSocket,
+                    tls: true,
+                    certificates: vec![TLSOption {
+                        certfile: "server.crt".into(),
+                        keyfile: "server.key".into()
+                    }],
+                    methods: vec!["basic".into()],
+                    mode: None,
+                }
+            ]
+        );
+
+        // The methods must be known, and TLS needs a certificate.
+        for listener in ["tcp://127.0.0.1:8558?methods=basic", "tls://127.0.0.1:8558"] {
+            let args: Vec<String> = ["squawkbus", "--listener", listener]
+                .iter()
+                .map(|x| x.to_string())
+                .collect();
+            assert!(Options::parse(&args).is_err());
+        }
+    }
+
+    #[test]
+    fn parse_unix_listener() {
+        let args: Vec<String> = [
+            "squawkbus",
+            "--authentication",
+            "peer",
+            "--listener",
+            "unix:///run/squawkbus/squawkbus.sock?methods=peer&mode=660",
+        ]
+        .iter()
+        .map(|x| x.to_string())
+        .collect();
+        let options = Options::parse(&args).unwrap();
+        let listener = &options.listeners[0];
+        assert_eq!(listener.endpoint, "/run/squawkbus/squawkbus.sock");
+        assert_eq!(listener.protocol, Protocol::Unix);
+        assert_eq!(listener.methods, vec!["peer"]);
+        assert_eq!(listener.permissions().unwrap(), Some(0o660));
+
+        // The mode must be octal, and only applies to Unix domain sockets.
+        for listener in [
+            "unix:///tmp/squawkbus.sock?mode=999",
+            "tcp://127.0.0.1:8558?mode=660",
+        ] {
+            let args: Vec<String> = ["squawkbus", "--listener", listener]
+                .iter()
+                .map(|x| x.to_string())
+                .collect();
+            assert!(Options::parse(&args).is_err());
+        }
+    }
+
+    #[test]
+    fn parse_deny() {
+        let spec = AuthorizationSpec::from_str("!@contractors:PUB.*::Subscriber").unwrap();
+        assert!(spec.is_deny);
+        assert!(spec.entitlements.is_empty());
+
+        let spec = AuthorizationSpec::from_str("tom:PUB.*:1:Subscriber").unwrap();
+        assert!(!spec.is_deny);
+    }
 }
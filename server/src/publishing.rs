@@ -1,27 +1,86 @@
 use std::{
     collections::{HashMap, HashSet},
     io,
+    time::Instant,
 };
 
 use common::messages::{DataPacket, Message};
 
 use crate::{
+    audit::{self, AuditEvent},
     authorization::{AuthorizationManager, Role},
-    clients::ClientManager,
+    clients::{Client, ClientManager},
+    conflation::ConflationManager,
     events::ServerEvent,
-    subscriptions::SubscriptionManager,
+    limits::{Limits, RateLimiter},
+    metrics::METRICS,
+    subscriptions::{SubscriptionManager, SubscriptionOptions},
 };
 
 pub struct PublisherManager {
     topics_by_publisher: HashMap<String, HashSet<String>>,
     publishers_by_topic: HashMap<String, HashSet<String>>,
+    limits: Limits,
+    /// The publishing rate of each user, kept across reconnects until the
+    /// allowance is full again.
+    rates_by_user: HashMap<String, RateLimiter>,
+    conflation_manager: ConflationManager,
 }
 
 impl PublisherManager {
-    pub fn new() -> PublisherManager {
+    pub fn new(limits: &Limits) -> PublisherManager {
         PublisherManager {
             topics_by_publisher: HashMap::new(),
             publishers_by_topic: HashMap::new(),
+            limits: limits.clone(),
+            rates_by_user: HashMap::new(),
+            conflation_manager: ConflationManager::new(),
+        }
+    }
+
+    /// Whether conflated data is waiting to be flushed.
+    pub fn has_pending(&self) -> bool {
+        self.conflation_manager.has_pending()
+    }
+
+    /// Send the conflated data which is due.
+    pub async fn flush(
+        &mut self,
+        client_manager: &ClientManager,
+        subscription_manager: &SubscriptionManager,
+    ) -> io::Result<()> {
+        self.conflation_manager
+            .flush(client_manager, subscription_manager)
+            .await
+    }
+
+    /// Count the data against the publisher's rate, telling the publisher
+    /// when it exceeds the limit.
+    fn is_rate_limited(
+        &mut self,
+        publisher_id: &str,
+        publisher: &Client,
+        topic: &str,
+        data_packets: &[DataPacket],
+    ) -> bool {
+        if self.limits.max_publish_messages_per_second.is_none()
+            && self.limits.max_publish_bytes_per_second.is_none()
+        {
+            return false;
+        }
+
+        let now = Instant::now();
+        let bytes = data_packets.iter().map(|packet| packet.data.len()).sum();
+        let rate = self
+            .rates_by_user
+            .entry(publisher.user.clone())
+            .or_insert_with(|| RateLimiter::new(&self.limits, now));
+        match rate.take(bytes, now) {
+            Ok(()) => false,
+            Err(limit) => {
+                publisher.report(limit.exceeded(publisher_id, topic));
+                true
+            }
         }
     }
 
@@ -54,16 +113,19 @@ impl PublisherManager {
             return Ok(());
         };
 
+        if self.is_rate_limited(sender_id, sender, topic, &data_packets) {
+            return Ok(());
+        }
+
         let Some(receiver) = client_manager.get(&receiver_id) else {
             log::debug!("send_unicast_data: no receiver client {receiver_id} - skipping");
             return Ok(());
         };
 
         // Get the entitlements.
-        let sender_entitlements =
-            entitlements_manager.entitlements(sender.user.as_str(), topic, Role::Publisher);
+        let sender_entitlements = sender.entitlements(entitlements_manager, topic, Role::Publisher);
         let receiver_entitlements =
-            entitlements_manager.entitlements(receiver.user.as_str(), topic, Role::Subscriber);
+            receiver.entitlements(entitlements_manager, topic, Role::Subscriber);
         let entitlements: HashSet<i32> = sender_entitlements
             .intersection(&receiver_entitlements)
             .cloned()
@@ -77,10 +139,31 @@ impl PublisherManager {
                 receiver.user,
                 topic
             );
+            audit::emit(AuditEvent::UnicastDenied {
+                sender_id: sender_id.into(),
+                sender: sender.user.clone(),
+                receiver_id: receiver_id.into(),
+                receiver: receiver.user.clone(),
+                topic: topic.into(),
+                messages: 1,
+            });
             return Ok(());
         }
 
+        let packets = data_packets.len();
         let auth_data_packets = self.get_authorized_data(data_packets, &entitlements);
+        if auth_data_packets.len() < packets {
+            METRICS.filtered(topic, packets - auth_data_packets.len());
+            audit::emit(AuditEvent::PublishFiltered {
+                publisher_id: sender_id.into(),
+                publisher: sender.user.clone(),
+                subscriber_id: receiver_id.into(),
+                subscriber: receiver.user.clone(),
+                topic: topic.into(),
+                messages: 1,
+                packets: packets - auth_data_packets.len(),
+            });
+        }
 
         if auth_data_packets.is_empty() {
             log::debug!(
@@ -127,7 +210,13 @@ impl PublisherManager {
         client_manager: &ClientManager,
         entitlements_manager: &AuthorizationManager,
     ) -> io::Result<()> {
-        let subscribers = subscription_manager.subscribers_for_topic(topic);
+        if let Some(publisher) = client_manager.get(publisher_id) {
+            if self.is_rate_limited(publisher_id, publisher, topic, &data_packets) {
+                return Ok(());
+            }
+        }
+
+        let subscribers = subscription_manager.subscriber_options_for_topic(topic);
         if subscribers.is_empty() {
             log::debug!("send_multicast_data: no topic {topic}");
             return Ok(());
@@ -139,19 +228,16 @@ impl PublisherManager {
         };
 
         let publisher_entitlements =
-            entitlements_manager.entitlements(publisher.user.as_str(), topic, Role::Publisher);
+            publisher.entitlements(entitlements_manager, topic, Role::Publisher);
 
         self.add_as_topic_publisher(publisher_id, topic);
 
-        for subscriber_id in &subscribers {
+        for (subscriber_id, options) in &subscribers {
             if let Some(subscriber) = client_manager.get(subscriber_id) {
                 log::debug!("send_multicast_data: ... {subscriber_id}");
 
-                let subscriber_entitlements = entitlements_manager.entitlements(
-                    subscriber.user.as_str(),
-                    topic,
-                    Role::Subscriber,
-                );
+                let subscriber_entitlements =
+                    subscriber.entitlements(entitlements_manager, topic, Role::Subscriber);
                 let entitlements: HashSet<i32> = publisher_entitlements
                     .intersection(&subscriber_entitlements)
                     .cloned()
@@ -165,11 +251,31 @@ impl PublisherManager {
                         subscriber.user,
                         topic
                     );
+                    audit::emit(AuditEvent::PublishDenied {
+                        publisher_id: publisher_id.into(),
+                        publisher: publisher.user.clone(),
+                        subscriber_id: subscriber_id.clone(),
+                        subscriber: subscriber.user.clone(),
+                        topic: topic.into(),
+                        messages: 1,
+                    });
                     continue;
                 }
 
-                let auth_data_packets =
+                let mut auth_data_packets =
                     self.get_authorized_data(data_packets.clone(), &entitlements);
+                if auth_data_packets.len() < data_packets.len() {
+                    METRICS.filtered(topic, data_packets.len() - auth_data_packets.len());
+                    audit::emit(AuditEvent::PublishFiltered {
+                        publisher_id: publisher_id.into(),
+                        publisher: publisher.user.clone(),
+                        subscriber_id: subscriber_id.clone(),
+                        subscriber: subscriber.user.clone(),
+                        topic: topic.into(),
+                        messages: 1,
+                        packets: data_packets.len() - auth_data_packets.len(),
+                    });
+                }
 
                 if auth_data_packets.is_empty() {
                     log::debug!(
@@ -181,6 +287,19 @@ impl PublisherManager {
                     continue;
                 }
 
+                auth_data_packets
+                    .retain(|data_packet| SubscriptionOptions::any_accepts(options, data_packet));
+
+                if auth_data_packets.is_empty() {
+                    log::debug!(
+                        "send_multicast_data: filtered message from {} to {} for {}",
+                        publisher.user,
+                        subscriber.user,
+                        topic
+                    );
+                    continue;
+                }
+
                 let message = Message::ForwardedMulticastData {
                     host: publisher.host.clone(),
                     user: publisher.user.clone(),
@@ -188,6 +307,25 @@ impl PublisherManager {
                     data_packets: auth_data_packets,
                 };
 
+                let message = match SubscriptionOptions::max_rate(options) {
+                    Some(max_rate) => {
+                        let Some(message) = self.conflation_manager.conflate(
+                            subscriber_id,
+                            topic,
+                            max_rate,
+                            message,
+                            Instant::now(),
+                        ) else {
+                            log::debug!(
+                                "send_multicast_data: conflated message for {subscriber_id} on {topic}"
+                            );
+                            continue;
+                        };
+                        message
+                    }
+                    None => message,
+                };
+
                 log::debug!(
                     "send_multicast_data: sending message {message:?} to client {subscriber_id}"
                 );
@@ -231,6 +369,12 @@ impl PublisherManager {
         client_manager: &ClientManager,
         subscription_manager: &SubscriptionManager,
     ) -> io::Result<()> {
+        self.conflation_manager.handle_close(closed_client_id);
+
+        // A full rate is no different to a new one, so need not be kept.
+        let now = Instant::now();
+        self.rates_by_user.retain(|_, rate| !rate.is_full(now));
+
         let topics_without_publishers = remove_publisher(
             closed_client_id,
             &mut self.topics_by_publisher,
@@ -0,0 +1,463 @@
+//! The server configuration file.
+//!
+//! The file may be TOML, YAML or JSON, chosen by its extension. Settings may
+//! be overridden by environment variables named `SQUAWKBUS_<SETTING>`, with
+//! `__` separating the names of nested settings, as in
+//! `SQUAWKBUS_TUNING__HUB_QUEUE_SIZE`. Command line options override both.
+//!
+//! Unknown settings in the file are rejected. Environment variables with the
+//! prefix which don't name a setting are ignored, as they may belong to
+//! something else.
+
+use std::collections::HashMap;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use config::{Config, Environment, File};
+use serde::de;
+
+use crate::audit::{AuditOption, DEFAULT_MAX_FILES, DEFAULT_MAX_FILE_SIZE};
+use crate::authentication::LockoutOption;
+use crate::limits::Limits;
+use crate::options::{
+    AuthenticationMethodOption, AuthenticationOption, ListenerOption, TLSClientAuthOption,
+    TLSOption,
+};
+
+const ENVIRONMENT_PREFIX: &str = "SQUAWKBUS";
+
+#[derive(Debug, Default, serde::Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Settings {
+    pub socket_endpoint: Option<String>,
+    pub web_socket_endpoint: Option<String>,
+    pub listeners: Vec<ListenerOption>,
+    /// Where Prometheus metrics are served, when given.
+    pub metrics_endpoint: Option<String>,
+    pub tls: Vec<TLSOption>,
+    pub tls_client_auth: Option<TlsClientAuthSettings>,
+    pub authentication: Vec<AuthenticationSettings>,
+    /// The seconds a client has to complete the TLS handshake and
+    /// authenticate.
+    pub handshake_timeout: Option<u64>,
+    pub lockout: Option<LockoutOption>,
+    pub authorizations_file: Option<PathBuf>,
+    /// Authorizations in the command line format.
+    pub authorizations: Vec<String>,
+    pub audit: Option<AuditSettings>,
+    /// The seconds clients are given to receive their queued messages when
+    /// the server shuts down.
+    pub shutdown_timeout: Option<u64>,
+    pub limits: Limits,
+    pub tuning: Tuning,
+}
+
+#[derive(Debug, serde::Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct TlsClientAuthSettings {
+    /// Either "required" or "optional".
+    pub mode: String,
+    pub cafile: PathBuf,
+}
+
+impl TryFrom<TlsClientAuthSettings> for TLSClientAuthOption {
+    type Error = io::Error;
+
+    fn try_from(value: TlsClientAuthSettings) -> io::Result<Self> {
+        let is_required = match value.mode.as_str() {
+            "required" => true,
+            "optional" => false,
+            _ => Err(io::Error::new(
+                io::ErrorKind::Other,
+                "invalid tls client auth option",
+            ))?,
+        };
+        Ok(TLSClientAuthOption {
+            cafile: value.cafile,
+            is_required,
+        })
+    }
+}
+
+/// An authentication backend, with the method name clients use to select it
+/// when this is not the name of the backend.
+#[derive(Debug, serde::Deserialize)]
+#[serde(tag = "backend", rename_all = "kebab-case")]
+pub enum AuthenticationSettings {
+    None {
+        method: Option<String>,
+    },
+    Basic {
+        method: Option<String>,
+        file: PathBuf,
+    },
+    Ldap {
+        method: Option<String>,
+        /// A URL, or a configuration file.
+        source: String,
+    },
+    Certificate {
+        method: Option<String>,
+        /// One of "cn", "dns", "email" or "uri".
+        name: String,
+    },
+    Bearer {
+        method: Option<String>,
+        file: PathBuf,
+    },
+    #[serde(rename = "scram-sha-256")]
+    Scram {
+        method: Option<String>,
+        file: PathBuf,
+    },
+    Peer {
+        method: Option<String>,
+    },
+}
+
+impl TryFrom<AuthenticationSettings> for AuthenticationMethodOption {
+    type Error = io::Error;
+
+    fn try_from(value: AuthenticationSettings) -> io::Result<Self> {
+        let (method, option) = match value {
+            AuthenticationSettings::None { method } => (method, AuthenticationOption::None),
+            AuthenticationSettings::Basic { method, file } => {
+                (method, AuthenticationOption::Basic(file))
+            }
+            AuthenticationSettings::Ldap { method, source } => {
+                (method, AuthenticationOption::Ldap(source))
+            }
+            AuthenticationSettings::Certificate { method, name } => {
+                let name_source = name
+                    .parse()
+                    .map_err(|e| io::Error::new(io::ErrorKind::Other, e))?;
+                (method, AuthenticationOption::Certificate(name_source))
+            }
+            AuthenticationSettings::Bearer { method, file } => {
+                (method, AuthenticationOption::Bearer(file))
+            }
+            AuthenticationSettings::Scram { method, file } => {
+                (method, AuthenticationOption::Scram(file))
+            }
+            AuthenticationSettings::Peer { method } => (method, AuthenticationOption::Peer),
+        };
+        let method = method.unwrap_or_else(|| option.method().to_string());
+        Ok(AuthenticationMethodOption { method, option })
+    }
+}
+
+#[derive(Debug, serde::Deserialize)]
+#[serde(tag = "sink", rename_all = "lowercase")]
+pub enum AuditSettings {
+    File {
+        path: PathBuf,
+        max_size: Option<u64>,
+        max_files: Option<usize>,
+    },
+    Syslog {
+        path: PathBuf,
+    },
+}
+
+impl From<AuditSettings> for AuditOption {
+    fn from(value: AuditSettings) -> Self {
+        match value {
+            AuditSettings::File {
+                path,
+                max_size,
+                max_files,
+            } => AuditOption::File {
+                path,
+                max_size: max_size.unwrap_or(DEFAULT_MAX_FILE_SIZE),
+                max_files: max_files.unwrap_or(DEFAULT_MAX_FILES),
+            },
+            AuditSettings::Syslog { path } => AuditOption::Syslog(path),
+        }
+    }
+}
+
+/// Settings which affect performance rather than behaviour.
+#[derive(Debug, Clone, PartialEq, serde::Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Tuning {
+    /// The number of events from clients waiting for the hub.
+    pub hub_queue_size: usize,
+    /// The number of messages waiting to be sent to each client.
+    pub client_queue_size: usize,
+}
+
+impl Default for Tuning {
+    fn default() -> Self {
+        Tuning {
+            hub_queue_size: 32,
+            client_queue_size: 32,
+        }
+    }
+}
+
+impl Settings {
+    /// Load the settings from the file, if given, and the environment. The
+    /// environment may be given for testing, otherwise the process
+    /// environment is used.
+    pub fn load(
+        path: Option<&Path>,
+        environment: Option<HashMap<String, String>>,
+    ) -> io::Result<Self> {
+        let mut builder = Config::builder();
+        if let Some(path) = path {
+            builder = builder.add_source(File::from(path));
+        }
+        let environment = environment.unwrap_or_else(|| std::env::vars().collect());
+        builder = builder.add_source(
+            Environment::with_prefix(ENVIRONMENT_PREFIX)
+                .prefix_separator("_")
+                .separator("__")
+                // Authorizations contain commas, but not spaces.
+                .list_separator(" ")
+                .with_list_parse_key("authorizations")
+                .try_parsing(true)
+                .source(Some(known_settings(environment))),
+        );
+
+        builder
+            .build()
+            .and_then(|config| config.try_deserialize())
+            .map_err(|e| {
+                io::Error::new(io::ErrorKind::Other, format!("invalid configuration: {e}"))
+            })
+    }
+}
+
+/// The names of the top level settings, which environment variables must
+/// start with, as the derived `Deserialize` of `Settings` gives them.
+fn setting_names() -> &'static [&'static str] {
+    /// Keeps the fields of the struct it is asked for, deserializing nothing.
+    struct Fields(&'static [&'static str]);
+
+    impl<'de> de::Deserializer<'de> for &mut Fields {
+        type Error = de::value::Error;
+
+        fn deserialize_any<V: de::Visitor<'de>>(
+            self,
+            _visitor: V,
+        ) -> Result<V::Value, Self::Error> {
+            Err(de::Error::custom("expected a struct"))
+        }
+
+        fn deserialize_struct<V: de::Visitor<'de>>(
+            self,
+            _name: &'static str,
+            fields: &'static [&'static str],
+            _visitor: V,
+        ) -> Result<V::Value, Self::Error> {
+            self.0 = fields;
+            Err(de::Error::custom("only the fields are wanted"))
+        }
+
+        serde::forward_to_deserialize_any! {
+            bool i8 i16 i32 i64 i128 u8 u16 u32 u64 u128 f32 f64 char str string bytes
+            byte_buf option unit unit_struct newtype_struct seq tuple tuple_struct map
+            enum identifier ignored_any
+        }
+    }
+
+    let mut fields = Fields(&[]);
+    let _ = <Settings as de::Deserialize>::deserialize(&mut fields);
+    fields.0
+}
+
+/// The environment variables which name a setting.
+fn known_settings(environment: HashMap<String, String>) -> HashMap<String, String> {
+    let prefix = format!("{ENVIRONMENT_PREFIX}_");
+    environment
+        .into_iter()
+        .filter(|(key, _)| {
+            let key_upper = key.to_uppercase();
+            let Some(setting) = key_upper.strip_prefix(&prefix) else {
+                return false;
+            };
+            let name = setting.split("__").next().unwrap_or_default();
+            let is_known = setting_names().contains(&name.to_lowercase().as_str());
+            if !is_known {
+                log::warn!("Ignoring environment variable {key}, which is not a setting");
+            }
+            is_known
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use std::fs;
+
+    use super::*;
+
+    const TOML: &str = r#"
+socket_endpoint = "127.0.0.1:9000"
+metrics_endpoint = "127.0.0.1:9090"
+authorizations = ["tom:LSE.*:1:Subscriber"]
+shutdown_timeout = 10
+handshake_timeout = 3
+
+[[tls]]
+certfile = "server.crt"
+keyfile = "server.key"
+
+[tls_client_auth]
+mode = "optional"
+cafile = "ca.crt"
+
+[[authentication]]
+backend = "ldap"
+method = "basic"
+source = "ldap://ns1.example.com"
+
+[[authentication]]
+backend = "scram-sha-256"
+file = "scram.passwd"
+
+[lockout]
+max_failures = 3
+
+[audit]
+sink = "file"
+path = "audit.log"
+max_files = 2
+
+[limits]
+max_connections = 1000
+max_publish_bytes_per_second = 1048576
+
+[tuning]
+hub_queue_size = 64
+"#;
+
+    #[test]
+    fn should_load_file_and_environment() {
+        let path = std::env::temp_dir().join(format!("squawkbus-{}.toml", std::process::id()));
+        fs::write(&path, TOML).unwrap();
+
+        let environment = HashMap::from([
+            (
+                "SQUAWKBUS_SOCKET_ENDPOINT".to_string(),
+                "127.0.0.1:9001".to_string(),
+            ),
+            (
+                "SQUAWKBUS_TUNING__CLIENT_QUEUE_SIZE".to_string(),
+                "8".to_string(),
+            ),
+            (
+                "SQUAWKBUS_LIMITS__MAX_CONNECTIONS_PER_USER".to_string(),
+                "4".to_string(),
+            ),
+        ]);
+        let settings = Settings::load(Some(&path), Some(environment)).unwrap();
+        fs::remove_file(&path).unwrap();
+
+        assert_eq!(settings.socket_endpoint.as_deref(), Some("127.0.0.1:9001"));
+        assert_eq!(settings.web_socket_endpoint, None);
+        assert_eq!(settings.metrics_endpoint.as_deref(), Some("127.0.0.1:9090"));
+        assert_eq!(settings.authorizations, vec!["tom:LSE.*:1:Subscriber"]);
+        assert_eq!(settings.shutdown_timeout, Some(10));
+        assert_eq!(settings.handshake_timeout, Some(3));
+        assert_eq!(
+            settings.lockout,
+            Some(LockoutOption {
+                max_failures: 3,
+                ..Default::default()
+            })
+        );
+        assert_eq!(settings.tls[0].certfile, PathBuf::from("server.crt"));
+        assert!(
+            !TLSClientAuthOption::try_from(settings.tls_client_auth.unwrap())
+                .unwrap()
+                .is_required
+        );
+        let authentication: Vec<AuthenticationMethodOption> = settings
+            .authentication
+            .into_iter()
+            .map(|x| x.try_into().unwrap())
+            .collect();
+        assert_eq!(authentication[0].method, "basic");
+        assert!(matches!(
+            authentication[0].option,
+            AuthenticationOption::Ldap(_)
+        ));
+        assert_eq!(authentication[1].method, "scram-sha-256");
+        assert_eq!(
+            AuditOption::from(settings.audit.unwrap()),
+            AuditOption::File {
+                path: "audit.log".into(),
+                max_size: DEFAULT_MAX_FILE_SIZE,
+                max_files: 2
+            }
+        );
+        assert_eq!(
+            settings.limits,
+            Limits {
+                max_connections: Some(1000),
+                max_connections_per_user: Some(4),
+                max_publish_bytes_per_second: Some(1048576),
+                ..Default::default()
+            }
+        );
+        assert_eq!(
+            settings.tuning,
+            Tuning {
+                hub_queue_size: 64,
+                client_queue_size: 8
+            }
+        );
+    }
+
+    #[test]
+    fn should_reject_unknown_settings() {
+        let path =
+            std::env::temp_dir().join(format!("squawkbus-{}-unknown.toml", std::process::id()));
+        fs::write(&path, "socket = \"127.0.0.1:9000\"").unwrap();
+        let result = Settings::load(Some(&path), Some(HashMap::new()));
+        fs::remove_file(&path).unwrap();
+        assert!(result.is_err());
+
+        // Unknown settings under a known one are still rejected.
+        let environment =
+            HashMap::from([("SQUAWKBUS_TUNING__QUEUE_SIZE".to_string(), "8".to_string())]);
+        assert!(Settings::load(None, Some(environment)).is_err());
+    }
+
+    #[test]
+    fn should_name_every_setting() {
+        let names = setting_names();
+        for name in ["socket_endpoint", "authorizations_file", "limits", "tuning"] {
+            assert!(names.contains(&name), "{name} is missing");
+        }
+    }
+
+    #[test]
+    fn should_ignore_unknown_environment_variables() {
+        let environment = HashMap::from([
+            ("SQUAWKBUS_SOCKET".to_string(), "x".to_string()),
+            ("SQUAWKBUS_HOME".to_string(), "/opt/squawkbus".to_string()),
+            ("PATH".to_string(), "/usr/bin".to_string()),
+            (
+                "SQUAWKBUS_SOCKET_ENDPOINT".to_string(),
+                "127.0.0.1:9001".to_string(),
+            ),
+        ]);
+        let settings = Settings::load(None, Some(environment)).unwrap();
+        assert_eq!(settings.socket_endpoint.as_deref(), Some("127.0.0.1:9001"));
+    }
+
+    #[test]
+    fn should_split_authorizations_by_spaces() {
+        let environment = HashMap::from([(
+            "SQUAWKBUS_AUTHORIZATIONS".to_string(),
+            "tom:LSE.*:1,2:Subscriber !dick:LSE.*::Subscriber".to_string(),
+        )]);
+        let settings = Settings::load(None, Some(environment)).unwrap();
+        assert_eq!(
+            settings.authorizations,
+            vec!["tom:LSE.*:1,2:Subscriber", "!dick:LSE.*::Subscriber"]
+        );
+    }
+}
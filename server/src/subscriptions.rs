@@ -5,11 +5,64 @@ use std::{
 
 use wildmatch::WildMatch;
 
-use crate::{clients::ClientManager, notifications::NotificationManager};
+use common::messages::{DataPacket, Message};
+
+use crate::{
+    audit::{self, AuditAction, AuditEvent},
+    authorization::{AuthorizationManager, Role},
+    clients::ClientManager,
+    events::ServerEvent,
+    filters::Filter,
+    limits::{Limit, Limits},
+    metrics::METRICS,
+    notifications::NotificationManager,
+};
+
+/// The options a subscriber requested for a subscription.
+#[derive(Debug, Default)]
+pub struct SubscriptionOptions {
+    /// Only packets matching the filter are sent.
+    pub filter: Option<Filter>,
+    /// The maximum number of updates per second for each topic.
+    pub max_rate: Option<u32>,
+}
+
+impl SubscriptionOptions {
+    /// A data packet is sent if any of the subscriptions accept it.
+    pub fn any_accepts(options: &[&SubscriptionOptions], data_packet: &DataPacket) -> bool {
+        options.iter().any(|options| {
+            options
+                .filter
+                .as_ref()
+                .is_none_or(|filter| filter.matches(data_packet))
+        })
+    }
+
+    /// The rate is limited only if every subscription is limited, in which
+    /// case the highest rate applies.
+    pub fn max_rate(options: &[&SubscriptionOptions]) -> Option<u32> {
+        options
+            .iter()
+            .map(|options| options.max_rate)
+            .collect::<Option<Vec<u32>>>()
+            .and_then(|rates| rates.into_iter().max())
+    }
+}
+
+/// A request from a client to add or remove a subscription.
+#[derive(Debug)]
+pub struct SubscriptionRequest {
+    pub topic: String,
+    pub is_add: bool,
+    /// The filter as the client gave it.
+    pub filter: Option<String>,
+    pub max_rate: Option<u32>,
+}
 
 struct Subscription {
     pattern: WildMatch,
     subscribers: HashMap<String, u32>,
+    options: HashMap<String, SubscriptionOptions>,
 }
 
 impl Subscription {
@@ -17,21 +70,42 @@ impl Subscription {
         Subscription {
             pattern: WildMatch::new(topic),
             subscribers: HashMap::new(),
+            options: HashMap::new(),
         }
     }
 }
 
 pub struct SubscriptionManager {
     subscriptions: HashMap<String, Subscription>,
+    max_subscriptions_per_client: Option<usize>,
 }
 
 impl SubscriptionManager {
-    pub fn new() -> SubscriptionManager {
+    pub fn new(limits: &Limits) -> SubscriptionManager {
         SubscriptionManager {
             subscriptions: HashMap::new(),
+            max_subscriptions_per_client: limits.max_subscriptions_per_client,
         }
     }
 
+    /// Whether another subscription to the topic would exceed the limit. A
+    /// repeated subscription to a topic is not counted.
+    fn is_over_limit(&self, subscriber_id: &str, topic: &str) -> bool {
+        let Some(max_subscriptions) = self.max_subscriptions_per_client else {
+            return false;
+        };
+        let is_subscribed = self
+            .subscriptions
+            .get(topic)
+            .is_some_and(|subscription| subscription.subscribers.contains_key(subscriber_id));
+        let count = self
+            .subscriptions
+            .values()
+            .filter(|subscription| subscription.subscribers.contains_key(subscriber_id))
+            .count();
+        !is_subscribed && count >= max_subscriptions
+    }
+
     pub fn subscribers_for_topic(&self, topic: &str) -> HashSet<String> {
         let mut subscribers: HashSet<String> = HashSet::new();
 
@@ -46,18 +120,100 @@ impl SubscriptionManager {
         subscribers
     }
 
+    /// Find the subscribers for a topic, with the options of each matching
+    /// subscription.
+    pub fn subscriber_options_for_topic(
+        &self,
+        topic: &str,
+    ) -> HashMap<String, Vec<&SubscriptionOptions>> {
+        let mut subscribers: HashMap<String, Vec<&SubscriptionOptions>> = HashMap::new();
+
+        for subscription in self.subscriptions.values() {
+            if subscription.pattern.matches(topic) {
+                for key in subscription.subscribers.keys() {
+                    if let Some(options) = subscription.options.get(key) {
+                        subscribers.entry(key.clone()).or_default().push(options);
+                    }
+                }
+            }
+        }
+
+        subscribers
+    }
+
+    pub fn is_subscribed(&self, subscriber_id: &str, topic: &str) -> bool {
+        self.subscriptions.values().any(|subscription| {
+            subscription.subscribers.contains_key(subscriber_id)
+                && subscription.pattern.matches(topic)
+        })
+    }
+
     pub async fn handle_subscription_request(
         &mut self,
         id: &str,
-        topic: String,
-        is_add: bool,
+        request: SubscriptionRequest,
         client_manager: &ClientManager,
         notification_manager: &NotificationManager,
     ) -> io::Result<()> {
+        let SubscriptionRequest {
+            topic,
+            is_add,
+            filter,
+            max_rate,
+        } = request;
         if is_add {
-            self.add_subscription(id, topic.as_str(), client_manager, notification_manager)
-                .await
+            let Some(client) = client_manager.get(id) else {
+                return Ok(());
+            };
+            let filter = match filter.map(|filter| filter.parse::<Filter>()).transpose() {
+                Ok(filter) => filter,
+                Err(error) => {
+                    log::warn!(
+                        "rejecting subscription from {id} to {topic} with invalid filter: {error}"
+                    );
+                    client.report(Message::SubscriptionRejected {
+                        topic,
+                        reason: format!("invalid filter: {error}"),
+                    });
+                    return Ok(());
+                }
+            };
+            if max_rate == Some(0) {
+                log::warn!("rejecting subscription from {id} to {topic} with a rate of zero");
+                client.report(Message::SubscriptionRejected {
+                    topic,
+                    reason: "invalid rate: zero".into(),
+                });
+                return Ok(());
+            }
+            if self.is_over_limit(id, &topic) {
+                client.report(Limit::Subscriptions.exceeded(id, &topic));
+                return Ok(());
+            }
+            let options = SubscriptionOptions { filter, max_rate };
+            audit::emit(AuditEvent::subscription(
+                id,
+                client,
+                &topic,
+                AuditAction::Added,
+            ));
+            self.add_subscription(
+                id,
+                topic.as_str(),
+                options,
+                client_manager,
+                notification_manager,
+            )
+            .await
         } else {
+            if let Some(client) = client_manager.get(id) {
+                audit::emit(AuditEvent::subscription(
+                    id,
+                    client,
+                    &topic,
+                    AuditAction::Removed,
+                ));
+            }
             self.remove_subscription(
                 id,
                 topic.as_str(),
@@ -73,6 +229,7 @@ impl SubscriptionManager {
         &mut self,
         subscriber_id: &str,
         topic: &str,
+        options: SubscriptionOptions,
         client_manager: &ClientManager,
         notification_manager: &NotificationManager,
     ) -> io::Result<()> {
@@ -92,9 +249,14 @@ impl SubscriptionManager {
             log::debug!("add_subscription: creating new {topic}");
             let count = 1;
             subscription.subscribers.insert(subscriber_id.into(), count);
+            METRICS.subscriptions.inc();
             count
         };
 
+        // The options of the latest request apply.
+        log::debug!("add_subscription: options for {topic} are {options:?}");
+        subscription.options.insert(subscriber_id.into(), options);
+
         notification_manager
             .notify_listeners(subscriber_id, topic, true, count, client_manager)
             .await
@@ -126,6 +288,8 @@ impl SubscriptionManager {
 
         if count == 0 {
             subscription.subscribers.remove(subscriber_id);
+            subscription.options.remove(subscriber_id);
+            METRICS.subscriptions.dec();
             log::debug!("removed all subscriptions for {subscriber_id} on {topic}");
         } else {
             log::debug!("removed one subscription for {subscriber_id} on {topic}");
@@ -161,6 +325,84 @@ impl SubscriptionManager {
         Ok(())
     }
 
+    /// The subscriber and topic of the subscriptions clients are authorized
+    /// for.
+    pub fn authorized(
+        &self,
+        client_manager: &ClientManager,
+        authorization_manager: &AuthorizationManager,
+    ) -> HashSet<(String, String)> {
+        let mut authorized: HashSet<(String, String)> = HashSet::new();
+        for (topic, subscription) in &self.subscriptions {
+            for subscriber_id in subscription.subscribers.keys() {
+                if let Some(subscriber) = client_manager.get(subscriber_id) {
+                    if subscriber.is_authorized(authorization_manager, topic, Role::Subscriber) {
+                        authorized.insert((subscriber_id.clone(), topic.clone()));
+                    }
+                }
+            }
+        }
+        authorized
+    }
+
+    /// Remove the subscriptions clients were authorized for, but no longer
+    /// are, and inform them. Subscriptions which were never authorized are
+    /// left, as they receive no data without entitlements.
+    pub async fn revoke_unauthorized(
+        &mut self,
+        previously_authorized: &HashSet<(String, String)>,
+        client_manager: &ClientManager,
+        notification_manager: &NotificationManager,
+        authorization_manager: &AuthorizationManager,
+    ) -> io::Result<()> {
+        let authorized = self.authorized(client_manager, authorization_manager);
+        let mut revoked: Vec<(String, String)> = previously_authorized
+            .difference(&authorized)
+            .cloned()
+            .collect();
+        revoked.sort();
+
+        for (subscriber_id, topic) in revoked {
+            log::info!("revoking subscription from {subscriber_id} to {topic}");
+            self.remove_subscription(
+                &subscriber_id,
+                &topic,
+                client_manager,
+                notification_manager,
+                true,
+            )
+            .await?;
+
+            if let Some(subscriber) = client_manager.get(&subscriber_id) {
+                audit::emit(AuditEvent::subscription(
+                    &subscriber_id,
+                    subscriber,
+                    &topic,
+                    AuditAction::Revoked,
+                ));
+                let event = ServerEvent::OnMessage(Message::SubscriptionRevoked { topic });
+                subscriber
+                    .tx
+                    .send(event)
+                    .await
+                    .map_err(|e| io::Error::new(io::ErrorKind::Other, e))?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// The subscriber and topic of every subscription.
+    pub fn subscriptions(&self) -> Vec<(String, String)> {
+        let mut subscriptions: Vec<(String, String)> = Vec::new();
+        for (topic, subscription) in &self.subscriptions {
+            for subscriber_id in subscription.subscribers.keys() {
+                subscriptions.push((subscriber_id.clone(), topic.clone()));
+            }
+        }
+        subscriptions
+    }
+
     fn find_client_topics(&self, client_id: &str) -> Vec<String> {
         let mut topics: Vec<String> = Vec::new();
         for (topic, subscription) in &self.subscriptions {
@@ -181,3 +423,177 @@ impl SubscriptionManager {
         subscriptions
     }
 }
+
+#[cfg(test)]
+mod test {
+    use tokio::sync::mpsc;
+
+    use crate::authorization::AuthorizationSpec;
+
+    use super::*;
+
+    fn authorization_manager(specs: &[&str]) -> AuthorizationManager {
+        let specs: Vec<AuthorizationSpec> = specs.iter().map(|x| x.parse().unwrap()).collect();
+        AuthorizationManager::new(specs.into())
+    }
+
+    fn topics(subscription_manager: &SubscriptionManager) -> Vec<String> {
+        let mut topics: Vec<String> = subscription_manager
+            .subscriptions()
+            .into_iter()
+            .map(|(_, topic)| topic)
+            .collect();
+        topics.sort();
+        topics
+    }
+
+    #[tokio::test]
+    async fn should_revoke_unauthorized_subscriptions() {
+        let mut client_manager = ClientManager::new();
+        let (tx, mut rx) = mpsc::channel(8);
+        client_manager.handle_connect("client1", "127.0.0.1".into(), "tom".into(), Vec::new(), tx);
+        let notification_manager = NotificationManager::new(&Limits::default());
+        let mut authorization_manager =
+            authorization_manager(&["tom:LSE.*:1:Subscriber", "tom:NYSE.*:2:Subscriber"]);
+        let mut subscription_manager = SubscriptionManager::new(&Limits::default());
+
+        for topic in ["LSE.TSCO", "NYSE.IBM", "NSE.INFY"] {
+            subscription_manager
+                .handle_subscription_request(
+                    "client1",
+                    SubscriptionRequest {
+                        topic: topic.into(),
+                        is_add: true,
+                        filter: None,
+                        max_rate: None,
+                    },
+                    &client_manager,
+                    &notification_manager,
+                )
+                .await
+                .unwrap();
+        }
+        assert_eq!(
+            topics(&subscription_manager),
+            vec!["LSE.TSCO", "NSE.INFY", "NYSE.IBM"]
+        );
+
+        // Reloading unchanged authorizations revokes nothing.
+        let previously_authorized =
+            subscription_manager.authorized(&client_manager, &authorization_manager);
+        subscription_manager
+            .revoke_unauthorized(
+                &previously_authorized,
+                &client_manager,
+                &notification_manager,
+                &authorization_manager,
+            )
+            .await
+            .unwrap();
+        assert_eq!(
+            topics(&subscription_manager),
+            vec!["LSE.TSCO", "NSE.INFY", "NYSE.IBM"]
+        );
+        assert!(rx.try_recv().is_err());
+
+        // The subscription which was never authorized is left.
+        authorization_manager.reset(vec!["tom:LSE.*:1:Subscriber".parse().unwrap()].into());
+        subscription_manager
+            .revoke_unauthorized(
+                &previously_authorized,
+                &client_manager,
+                &notification_manager,
+                &authorization_manager,
+            )
+            .await
+            .unwrap();
+        assert_eq!(topics(&subscription_manager), vec!["LSE.TSCO", "NSE.INFY"]);
+
+        let Ok(ServerEvent::OnMessage(message)) = rx.try_recv() else {
+            panic!("expected a message");
+        };
+        assert_eq!(
+            message,
+            Message::SubscriptionRevoked {
+                topic: "NYSE.IBM".into()
+            }
+        );
+    }
+
+    #[tokio::test]
+    async fn should_reject_invalid_filters() {
+        let limits = Limits::default();
+        let mut client_manager = ClientManager::new();
+        let (tx, mut rx) = mpsc::channel(8);
+        client_manager.handle_connect("client1", "127.0.0.1".into(), "tom".into(), Vec::new(), tx);
+        let notification_manager = NotificationManager::new(&limits);
+        let mut subscription_manager = SubscriptionManager::new(&limits);
+
+        subscription_manager
+            .handle_subscription_request(
+                "client1",
+                SubscriptionRequest {
+                    topic: "LSE.TSCO".into(),
+                    is_add: true,
+                    filter: Some("NOT ".repeat(10_000)),
+                    max_rate: None,
+                },
+                &client_manager,
+                &notification_manager,
+            )
+            .await
+            .unwrap();
+        assert!(topics(&subscription_manager).is_empty());
+
+        let Ok(ServerEvent::OnMessage(Message::SubscriptionRejected { topic, reason })) =
+            rx.try_recv()
+        else {
+            panic!("expected a rejection");
+        };
+        assert_eq!(topic, "LSE.TSCO");
+        assert!(reason.starts_with("invalid filter"));
+    }
+
+    #[tokio::test]
+    async fn should_limit_subscriptions_per_client() {
+        let limits = Limits {
+            max_subscriptions_per_client: Some(2),
+            ..Default::default()
+        };
+        let mut client_manager = ClientManager::new();
+        let (tx, mut rx) = mpsc::channel(8);
+        client_manager.handle_connect("client1", "127.0.0.1".into(), "tom".into(), Vec::new(), tx);
+        let notification_manager = NotificationManager::new(&limits);
+        let mut subscription_manager = SubscriptionManager::new(&limits);
+
+        // Repeating a subscription does not count against the limit.
+        for topic in ["LSE.TSCO", "LSE.TSCO", "NYSE.IBM", "NSE.INFY"] {
+            subscription_manager
+                .handle_subscription_request(
+                    "client1",
+                    SubscriptionRequest {
+                        topic: topic.into(),
+                        is_add: true,
+                        filter: None,
+                        max_rate: None,
+                    },
+                    &client_manager,
+                    &notification_manager,
+                )
+                .await
+                .unwrap();
+        }
+        assert_eq!(topics(&subscription_manager), vec!["LSE.TSCO", "NYSE.IBM"]);
+
+        let Ok(ServerEvent::OnMessage(message)) = rx.try_recv() else {
+            panic!("expected a message");
+        };
+        assert_eq!(
+            message,
+            Message::LimitExceeded {
+                limit: "max_subscriptions_per_client".into(),
+                detail: "NSE.INFY".into()
+            }
+        );
+    }
+}
@@ -0,0 +1,164 @@
+//! Watches configuration files, so changes are applied without a SIGHUP.
+//!
+//! The directory holding each file is watched rather than the file itself, as
+//! editors and tools such as `htpasswd` often replace a file by renaming a new
+//! one over it.
+
+use std::collections::{HashMap, HashSet};
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use notify::{EventKind, RecommendedWatcher, RecursiveMode, Watcher};
+use tokio::sync::mpsc::{self, UnboundedReceiver};
+use tokio::time::timeout;
+
+/// Changes which arrive within this time are applied together, so that a
+/// certificate and key replaced one after the other are loaded as a pair.
+const SETTLE_TIME: Duration = Duration::from_millis(500);
+
+/// The configuration which needs reloading.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum ConfigChange {
+    Authentication,
+    Authorization,
+    Tls,
+}
+
+pub struct ConfigWatcher {
+    // The watcher stops when dropped.
+    _watcher: RecommendedWatcher,
+    rx: UnboundedReceiver<ConfigChange>,
+}
+
+impl ConfigWatcher {
+    pub fn new(files: &[(PathBuf, ConfigChange)]) -> io::Result<Self> {
+        // A file may hold more than one kind of configuration, which are all
+        // reloaded when it changes.
+        let mut changes: HashMap<PathBuf, HashSet<ConfigChange>> = HashMap::new();
+        for (path, change) in files {
+            changes
+                .entry(std::path::absolute(path)?)
+                .or_default()
+                .insert(*change);
+        }
+
+        let (tx, rx) = mpsc::unbounded_channel();
+        let paths = changes.clone();
+        let mut watcher =
+            notify::recommended_watcher(move |result: notify::Result<notify::Event>| {
+                let event = match result {
+                    Ok(event) => event,
+                    Err(error) => {
+                        log::warn!("failed to watch configuration: {error}");
+                        return;
+                    }
+                };
+                if matches!(event.kind, EventKind::Access(_) | EventKind::Remove(_)) {
+                    return;
+                }
+                for path in &event.paths {
+                    let Some(changes) = paths.get(path) else {
+                        continue;
+                    };
+                    log::debug!("{} changed", path.display());
+                    for change in changes {
+                        // The receiver has gone when the server is stopping.
+                        let _ = tx.send(*change);
+                    }
+                }
+            })
+            .map_err(io::Error::other)?;
+
+        let directories: HashSet<&Path> = changes.keys().filter_map(|path| path.parent()).collect();
+        for directory in directories {
+            log::info!("Watching {} for configuration changes", directory.display());
+            watcher
+                .watch(directory, RecursiveMode::NonRecursive)
+                .map_err(io::Error::other)?;
+        }
+
+        Ok(ConfigWatcher {
+            _watcher: watcher,
+            rx,
+        })
+    }
+
+    /// Wait for files to change, returning the configuration to reload once
+    /// they have settled.
+    pub async fn changed(&mut self) -> HashSet<ConfigChange> {
+        let mut changes = HashSet::new();
+        match self.rx.recv().await {
+            Some(change) => changes.insert(change),
+            // Without a sender no more changes will come.
+            None => std::future::pending().await,
+        };
+        while let Ok(Some(change)) = timeout(SETTLE_TIME, self.rx.recv()).await {
+            changes.insert(change);
+        }
+        changes
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::fs;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn should_report_changed_files() {
+        let dir = std::env::temp_dir().join(format!("watcher-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let authorizations = dir.join("authorizations.yaml");
+        let passwd = dir.join("ht.passwd");
+        fs::write(&authorizations, "").unwrap();
+        fs::write(&passwd, "").unwrap();
+
+        let mut watcher = ConfigWatcher::new(&[
+            (authorizations.clone(), ConfigChange::Authorization),
+            (passwd.clone(), ConfigChange::Authentication),
+        ])
+        .unwrap();
+
+        // Other files in the directory are ignored.
+        fs::write(dir.join("other.txt"), "").unwrap();
+        // Replace the file, as an editor might.
+        let new_passwd = dir.join("ht.passwd.new");
+        fs::write(&new_passwd, "tom:secret").unwrap();
+        fs::rename(&new_passwd, &passwd).unwrap();
+
+        let changes = tokio::time::timeout(Duration::from_secs(5), watcher.changed())
+            .await
+            .unwrap();
+        assert_eq!(changes, HashSet::from([ConfigChange::Authentication]));
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn should_report_every_change_to_a_shared_file() {
+        let dir = std::env::temp_dir().join(format!("watcher-shared-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let cafile = dir.join("ca.pem");
+        fs::write(&cafile, "").unwrap();
+
+        let mut watcher = ConfigWatcher::new(&[
+            (cafile.clone(), ConfigChange::Tls),
+            (cafile.clone(), ConfigChange::Authentication),
+        ])
+        .unwrap();
+
+        fs::write(&cafile, "-----BEGIN CERTIFICATE-----").unwrap();
+
+        let changes = tokio::time::timeout(Duration::from_secs(5), watcher.changed())
+            .await
+            .unwrap();
+        assert_eq!(
+            changes,
+            HashSet::from([ConfigChange::Tls, ConfigChange::Authentication])
+        );
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}